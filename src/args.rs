@@ -0,0 +1,885 @@
+/*
+    Copyright 2024 Bricky
+    https://github.com/bricky149
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+*/
+
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::channels::{self, Tone};
+use crate::codeplug;
+use crate::config::Config;
+use crate::spi::SPI_FLASH_SIZES;
+use crate::uart::{BAUD_RATE, CHUNK_LENGTH};
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Mode {
+    List,
+    Dump,
+    Flash,
+    Restore,
+    Run,
+    Replay,
+    FirmwareInfo,
+    Raw,
+    Shell,
+    Bench,
+    Report,
+    CalTransplant,
+    Split,
+    Assemble,
+    Inspect,
+    Checksum,
+    BulkSet,
+    ChannelImport,
+    Clone,
+    FirmwareDiff,
+    Watch,
+    Serve,
+    SelfUpdate,
+    FirmwareTrust,
+    FirmwareReleases,
+    Validate,
+    Scrub,
+    ChannelTidy,
+    ChannelRename,
+    ChannelFind,
+    ChannelExport,
+    GoldenSet,
+    GoldenRestore,
+    Backup,
+    Doctor,
+    Lint
+}
+
+pub struct Args {
+    pub mode: Mode,
+    pub port: String,
+    pub files: Vec<String>,
+    pub calib_only: bool,
+    pub channels_only: bool,
+    pub baud_rate: u32,
+    pub resume_from: Option<usize>,
+    pub record: Option<String>,
+    pub radios_only: bool,
+    pub known_cables_only: bool,
+    pub yes: bool,
+    pub installed_version: Option<String>,
+    pub allow_downgrade: bool,
+    pub paranoid: bool,
+    pub send: Option<String>,
+    pub session: Option<String>,
+    pub no_color: bool,
+    pub timeout: Option<Duration>,
+    pub flash_size: Option<usize>,
+    pub preserve_calibration: bool,
+    pub force_foreign: bool,
+    pub from: Option<String>,
+    pub into: Option<String>,
+    pub out: Option<String>,
+    pub offset: Option<usize>,
+    pub length: Option<usize>,
+    pub ignore_manifest: bool,
+    pub condition: Option<String>,
+    pub pattern: Option<String>,
+    pub frequencies: Option<(u32, u32)>,
+    pub template: Option<String>,
+    pub rx_tone: Option<Tone>,
+    pub tx_tone: Option<Tone>,
+    pub to: Option<String>,
+    pub incremental: Option<String>,
+    pub encrypt: bool,
+    pub listen: Option<String>,
+    pub inter_chunk_delay: Option<Duration>,
+    pub progress_json: bool,
+    pub accessible: bool,
+    pub json: bool,
+    pub blank_channels: bool,
+    pub blank_calibration: bool,
+    pub dedupe: bool,
+    pub sort: Option<String>,
+    pub compact: bool,
+    pub force: bool,
+    pub pipeline: bool,
+    pub verify_dump: bool,
+    pub rotate: Option<usize>,
+    pub log_file: Option<String>,
+    pub format: Option<String>,
+    pub url: Option<String>,
+    pub sha256: Option<String>,
+    pub pre_hook: Option<String>,
+    pub post_hook: Option<String>
+}
+
+fn set_mode(mode: &mut Option<Mode>, new_mode: Mode) -> Result<(), String> {
+    match mode {
+        Some(_) => Err(String::from("Only one operation may be given")),
+        None => {
+            *mode = Some(new_mode);
+            Ok(())
+        }
+    }
+}
+
+// The value following an option, e.g. the port after -p
+fn value<'a>(iter: &mut impl Iterator<Item = &'a String>, option: &str) -> Result<&'a String, String> {
+    iter.next().ok_or(format!("{} needs a value", option))
+}
+
+fn parsed<T: FromStr>(text: &str, option: &str) -> Result<T, String> {
+    text.parse().map_err(|_| format!("{} takes a whole number, not {}", option, text))
+}
+
+fn number(text: &str, option: &str) -> Result<usize, String> {
+    parse_number(text).ok_or(format!("{} takes a decimal or 0x-prefixed number, not {}", option, text))
+}
+
+// Accepts both decimal and 0x-prefixed hexadecimal, as printed by progress output
+pub fn parse_number(arg: &str) -> Option<usize> {
+    match arg.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16).ok(),
+        None => arg.parse().ok()
+    }
+}
+
+// Either a single frequency or a range in megahertz, e.g. 446.0-446.2
+fn frequency_range(text: &str) -> Result<(u32, u32), String> {
+    let (low, high) = text.split_once('-').unwrap_or((text, text));
+    match (channels::parse_frequency(low), channels::parse_frequency(high)) {
+        (Some(low), Some(high)) if low <= high => Ok((low, high)),
+        _ => Err(format!("--freq takes megahertz or a range of them, e.g. 446.0-446.2, not {}", text))
+    }
+}
+
+fn file_count(mode: Mode) -> usize {
+    match mode {
+        Mode::List | Mode::Raw | Mode::Shell | Mode::Bench | Mode::Report | Mode::CalTransplant
+            | Mode::Clone | Mode::Watch | Mode::Serve | Mode::SelfUpdate | Mode::GoldenRestore
+            | Mode::Backup | Mode::FirmwareReleases | Mode::Doctor => 0,
+        Mode::Dump | Mode::Flash | Mode::Restore | Mode::Run | Mode::Replay | Mode::FirmwareInfo
+            | Mode::Assemble | Mode::Inspect | Mode::Checksum | Mode::FirmwareTrust
+            | Mode::Validate | Mode::Scrub | Mode::GoldenSet | Mode::ChannelFind | Mode::Lint => 1,
+        Mode::ChannelExport | Mode::Split | Mode::BulkSet | Mode::FirmwareDiff | Mode::ChannelTidy
+            | Mode::ChannelRename => 2,
+        Mode::ChannelImport => 3
+    }
+}
+
+// Operations that work on either a dump or the radio itself take fewer
+// files when given a port
+fn live_file_count(mode: Mode) -> Option<usize> {
+    match mode {
+        Mode::Inspect | Mode::BulkSet | Mode::ChannelTidy | Mode::ChannelRename | Mode::ChannelFind
+            | Mode::Lint => Some(0),
+        Mode::ChannelImport | Mode::ChannelExport => Some(1),
+        _ => None
+    }
+}
+
+fn needs_port(mode: Mode) -> bool {
+    matches!(mode, Mode::Dump | Mode::Flash | Mode::Restore | Mode::Raw | Mode::Shell | Mode::Bench
+        | Mode::GoldenRestore | Mode::Backup)
+}
+
+// Bare file names for dumps and restores live in the backup directory, if one is set
+fn backup_path(config: &Config, file: &String) -> String {
+    match &config.backup_dir {
+        Some(dir) if file != "-" && Path::new(file).components().count() == 1 =>
+            dir.join(file).to_string_lossy().into_owned(),
+        _ => file.clone()
+    }
+}
+
+/// Parses the options of a job step or serve request, which may not name
+/// hooks, as only the command line and config file may run shell commands
+pub fn parse_step(args: &[String], config: &Config) -> Result<Args, String> {
+    if args.iter().any(|a| a == "--pre-hook" || a == "--post-hook") {
+        return Err(String::from("Hooks can only be set on the command line or in the config file"))
+    }
+    parse(args, config)
+}
+
+/// Command line options take precedence over the config file
+pub fn parse(args: &[String], config: &Config) -> Result<Args, String> {
+    let mut mode = None;
+    let mut port = None;
+    let mut files = Vec::new();
+    let mut calib_only = false;
+    let mut channels_only = false;
+    let mut baud_rate = None;
+    let mut resume_from = None;
+    let mut record = None;
+    let mut radios_only = false;
+    let mut known_cables_only = false;
+    let mut yes = false;
+    let mut installed_version = None;
+    let mut allow_downgrade = false;
+    let mut paranoid = false;
+    let mut send = None;
+    let mut session = None;
+    let mut no_color = false;
+    let mut timeout = None;
+    let mut no_paranoid = false;
+    let mut flash_size = None;
+    let mut preserve_calibration = false;
+    let mut force_foreign = false;
+    let mut from = None;
+    let mut into = None;
+    let mut out = None;
+    let mut offset = None;
+    let mut length = None;
+    let mut ignore_manifest = false;
+    let mut condition = None;
+    let mut pattern = None;
+    let mut pre_hook = None;
+    let mut post_hook = None;
+    let mut frequencies = None;
+    let mut template = None;
+    let mut rx_tone = None;
+    let mut tx_tone = None;
+    let mut to = None;
+    let mut incremental = None;
+    let mut encrypt = false;
+    let mut on_connect = None;
+    let mut listen = None;
+    let mut inter_chunk_delay = None;
+    let mut progress_json = false;
+    let mut accessible = false;
+    let mut json = false;
+    let mut blank_channels = false;
+    let mut blank_calibration = false;
+    let mut dedupe = false;
+    let mut sort = None;
+    let mut compact = false;
+    let mut force = false;
+    let mut pipeline = false;
+    let mut verify_dump = false;
+    let mut rotate = None;
+    let mut log_file = None;
+    let mut format = None;
+    let mut url = None;
+    let mut sha256 = None;
+
+    let mut iter = args.iter().skip(1);
+
+    // Operations beyond the original four are named rather than flagged
+    match args.get(1).map(String::as_str) {
+        Some("list") => {
+            iter.next();
+            mode = Some(Mode::List)
+        }
+        Some("run") => {
+            iter.next();
+            mode = Some(Mode::Run)
+        }
+        Some("firmware") => {
+            iter.next();
+            match iter.next().map_or("", String::as_str) {
+                "info" => mode = Some(Mode::FirmwareInfo),
+                "diff" => mode = Some(Mode::FirmwareDiff),
+                "trust" => mode = Some(Mode::FirmwareTrust),
+                "releases" => mode = Some(Mode::FirmwareReleases),
+                _ => return Err(String::from("firmware needs one of info, diff, trust or releases"))
+            }
+        }
+        Some("raw") => {
+            iter.next();
+            mode = Some(Mode::Raw)
+        }
+        Some("shell") => {
+            iter.next();
+            mode = Some(Mode::Shell)
+        }
+        Some("bench") => {
+            iter.next();
+            mode = Some(Mode::Bench)
+        }
+        Some("report") => {
+            iter.next();
+            mode = Some(Mode::Report)
+        }
+        Some("cal") => {
+            iter.next();
+            match iter.next().map_or("", String::as_str) {
+                "transplant" => mode = Some(Mode::CalTransplant),
+                _ => return Err(String::from("cal needs transplant"))
+            }
+        }
+        Some("split") => {
+            iter.next();
+            mode = Some(Mode::Split)
+        }
+        Some("assemble") => {
+            iter.next();
+            mode = Some(Mode::Assemble)
+        }
+        Some("inspect") => {
+            iter.next();
+            mode = Some(Mode::Inspect)
+        }
+        Some("scrub") => {
+            iter.next();
+            mode = Some(Mode::Scrub)
+        }
+        Some("validate") => {
+            iter.next();
+            mode = Some(Mode::Validate)
+        }
+        Some("lint") => {
+            iter.next();
+            mode = Some(Mode::Lint)
+        }
+        Some("checksum") => {
+            iter.next();
+            mode = Some(Mode::Checksum)
+        }
+        Some("channels") => {
+            iter.next();
+            match iter.next().map_or("", String::as_str) {
+                "bulk-set" => mode = Some(Mode::BulkSet),
+                "import" => mode = Some(Mode::ChannelImport),
+                "tidy" => mode = Some(Mode::ChannelTidy),
+                "rename" => mode = Some(Mode::ChannelRename),
+                "find" => mode = Some(Mode::ChannelFind),
+                "export" => mode = Some(Mode::ChannelExport),
+                // Short for tidy --compact, which is most often all that is wanted
+                "compact" => {
+                    mode = Some(Mode::ChannelTidy);
+                    compact = true
+                }
+                _ => return Err(String::from("channels needs one of bulk-set, import, export, find, tidy, compact or rename"))
+            }
+        }
+        Some("clone") => {
+            iter.next();
+            mode = Some(Mode::Clone)
+        }
+        Some("serve") => {
+            iter.next();
+            mode = Some(Mode::Serve)
+        }
+        Some("watch") => {
+            iter.next();
+            mode = Some(Mode::Watch)
+        }
+        Some("golden") => {
+            iter.next();
+            match iter.next().map_or("", String::as_str) {
+                "set" => mode = Some(Mode::GoldenSet),
+                "restore" => mode = Some(Mode::GoldenRestore),
+                _ => return Err(String::from("golden needs one of set or restore"))
+            }
+        }
+        Some("backup") => {
+            iter.next();
+            mode = Some(Mode::Backup)
+        }
+        Some("doctor") => {
+            iter.next();
+            mode = Some(Mode::Doctor)
+        }
+        Some("self-update") => {
+            iter.next();
+            mode = Some(Mode::SelfUpdate)
+        }
+        Some("replay") => {
+            iter.next();
+            mode = Some(Mode::Replay)
+        }
+        _ => ()
+    }
+
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "-l" => set_mode(&mut mode, Mode::List)?,
+            "-d" => set_mode(&mut mode, Mode::Dump)?,
+            "-f" => set_mode(&mut mode, Mode::Flash)?,
+            "-r" => set_mode(&mut mode, Mode::Restore)?,
+            "-c" => calib_only = true,
+            "--channels" => channels_only = true,
+            "-p" => port = Some(value(&mut iter, arg)?.clone()),
+            "--baud" => baud_rate = Some(parsed(value(&mut iter, arg)?, arg)?),
+            "--resume-from" => resume_from = Some(number(value(&mut iter, arg)?, arg)?),
+            "--record" => record = Some(value(&mut iter, arg)?.clone()),
+            "--radios-only" => radios_only = true,
+            "--known-cables" => known_cables_only = true,
+            "-y" | "--yes" => yes = true,
+            "--installed" => installed_version = Some(value(&mut iter, arg)?.clone()),
+            "--allow-downgrade" => allow_downgrade = true,
+            "--paranoid" => paranoid = true,
+            "--send" => send = Some(value(&mut iter, arg)?.clone()),
+            "--session" => session = Some(value(&mut iter, arg)?.clone()),
+            "--no-color" => no_color = true,
+            "--progress-json" => progress_json = true,
+            "--accessible" => accessible = true,
+            "--log-file" => log_file = Some(value(&mut iter, arg)?.clone()),
+            // Profiles are picked before parsing, as they supply the defaults used here
+            "--radio" => {
+                value(&mut iter, arg)?;
+            }
+            "--json" => json = true,
+            "--blank-channels" => blank_channels = true,
+            "--blank-calibration" => blank_calibration = true,
+            "--dedupe" => dedupe = true,
+            "--sort" => sort = Some(value(&mut iter, arg)?.clone()),
+            "--compact" => compact = true,
+            "--force" => force = true,
+            "--pipeline" => pipeline = true,
+            "--verify-dump" => verify_dump = true,
+            "--url" => url = Some(value(&mut iter, arg)?.clone()),
+            "--sha256" => sha256 = Some(value(&mut iter, arg)?.clone()),
+            "--format" => format = Some(value(&mut iter, arg)?.clone()),
+            "--rotate" => rotate = Some(parsed(value(&mut iter, arg)?, arg)?),
+            "--timeout" => timeout = Some(parsed(value(&mut iter, arg)?, arg)?),
+            "--inter-chunk-delay" => inter_chunk_delay = Some(parsed(value(&mut iter, arg)?, arg)?),
+            "--no-paranoid" => no_paranoid = true,
+            "--preserve-calibration" => preserve_calibration = true,
+            "--force-foreign" => force_foreign = true,
+            "--from" => from = Some(value(&mut iter, arg)?.clone()),
+            // Backing up is the only action so far, but naming it leaves room for more
+            "--on-connect" => on_connect = Some(value(&mut iter, arg)?.clone()),
+            "--listen" => listen = Some(value(&mut iter, arg)?.clone()),
+            "--incremental" => incremental = Some(value(&mut iter, arg)?.clone()),
+            "--encrypt" => encrypt = true,
+            "--to" => to = Some(value(&mut iter, arg)?.clone()),
+            "--into" => into = Some(value(&mut iter, arg)?.clone()),
+            "--out" => out = Some(value(&mut iter, arg)?.clone()),
+            "--offset" => offset = Some(number(value(&mut iter, arg)?, arg)?),
+            "--ignore-manifest" => ignore_manifest = true,
+            "--where" => condition = Some(value(&mut iter, arg)?.clone()),
+            "--match" | "--name" => pattern = Some(value(&mut iter, arg)?.clone()),
+            "--freq" => frequencies = Some(frequency_range(value(&mut iter, arg)?)?),
+            "--template" => template = Some(value(&mut iter, arg)?.clone()),
+            "--pre-hook" => pre_hook = Some(value(&mut iter, arg)?.clone()),
+            "--post-hook" => post_hook = Some(value(&mut iter, arg)?.clone()),
+            "--tone" => {
+                rx_tone = Some(Tone::parse(value(&mut iter, arg)?)?);
+                tx_tone = rx_tone
+            }
+            "--rx-tone" => rx_tone = Some(Tone::parse(value(&mut iter, arg)?)?),
+            "--tx-tone" => tx_tone = Some(Tone::parse(value(&mut iter, arg)?)?),
+            "--length" => length = Some(number(value(&mut iter, arg)?, arg)?),
+            "--flash-size" => flash_size = Some(parsed::<usize>(value(&mut iter, arg)?, arg)? * 1024 * 1024),
+            // A lone - stands for standard input or output
+            _ if arg == "-" || !arg.starts_with('-') => files.push(arg.clone()),
+            _ => return Err(format!("Unknown option {}", arg))
+        }
+    }
+
+    let mode = mode.ok_or("No operation given")?;
+    // A dump may be named with --out instead, which reads better for templates
+    if mode == Mode::Dump && files.is_empty() {
+        files.extend(out.take())
+    }
+    if port.is_none() && needs_port(mode) {
+        port = config.port.clone()
+    }
+    let expected = match (port.is_some(), live_file_count(mode)) {
+        (true, Some(count)) => count..=count,
+        // checksum compares two dumps when given a second
+        _ if mode == Mode::Checksum => 1..=2,
+        // the radio's own image is restored unless another is named
+        _ if mode == Mode::GoldenRestore => 0..=1,
+        // releases are listed unless one is named to download
+        _ if mode == Mode::FirmwareReleases => 0..=1,
+        // firmware may be downloaded instead of read from a file
+        _ if mode == Mode::Flash && url.is_some() => 0..=0,
+        _ => file_count(mode)..=file_count(mode)
+    };
+    if !expected.contains(&files.len()) {
+        let wanted = match (expected.start(), expected.end()) {
+            (low, high) if low == high => low.to_string(),
+            (low, high) => format!("{} or {}", low, high)
+        };
+        return Err(format!("Wrong number of file names, expected {} but got {}", wanted, files.len()))
+    }
+    if port.is_some() != needs_port(mode) && live_file_count(mode).is_none() {
+        return Err(String::from(match port {
+            Some(_) => "-p is not accepted here",
+            None => "No port given. Specify one with -p or set port in the config file"
+        }))
+    }
+
+    if (offset.is_some() || length.is_some()) && mode != Mode::Inspect {
+        return Err(String::from("--offset and --length are only accepted by inspect"))
+    }
+
+    // clone opens two ports, so a single recording cannot hold both
+    let live = needs_port(mode) || (live_file_count(mode).is_some() && port.is_some());
+    if record.is_some() && !live {
+        return Err(String::from("--record is only accepted when talking to a radio with -p"))
+    }
+    if timeout.is_some() && !live && mode != Mode::Clone {
+        return Err(String::from("--timeout is only accepted when talking to a radio"))
+    }
+
+    // Reads wait for each reply anyway, so only writes are paced
+    let writes = matches!(mode, Mode::Flash | Mode::Restore | Mode::Clone)
+        || (matches!(mode, Mode::BulkSet | Mode::ChannelImport | Mode::ChannelTidy | Mode::ChannelRename) && port.is_some());
+    if inter_chunk_delay.is_some() && !writes {
+        return Err(String::from("--inter-chunk-delay is only accepted when writing to a radio"))
+    }
+
+    // Only operations that talk to a radio a chunk at a time show progress
+    let live = matches!(mode, Mode::Dump | Mode::Flash | Mode::Restore | Mode::Clone)
+        || (matches!(mode, Mode::Inspect | Mode::BulkSet | Mode::ChannelImport | Mode::ChannelTidy | Mode::ChannelRename)
+            && port.is_some());
+    if progress_json && !live {
+        return Err(String::from("--progress-json is only accepted by operations that show progress"))
+    }
+    if progress_json && accessible {
+        return Err(String::from("--progress-json and --accessible cannot be given together"))
+    }
+
+    if (radios_only || known_cables_only || json) && mode != Mode::List {
+        return Err(String::from("--radios-only, --known-cables and --json are only accepted by -l"))
+    }
+
+    // Confirmation is read from stdin, so it cannot also carry the firmware or dump
+    let confirms = matches!(mode, Mode::Flash | Mode::ChannelTidy | Mode::ChannelRename);
+    if yes && !confirms && mode != Mode::SelfUpdate {
+        return Err(String::from("--yes is only accepted by -f, channels tidy, channels rename and self-update"))
+    }
+    if confirms && !yes && files.first().is_some_and(|f| f == "-") {
+        return Err(String::from("Reading from standard input needs --yes, as confirmation is read from it too"))
+    }
+
+    if (installed_version.is_some() || allow_downgrade) && mode != Mode::Flash {
+        return Err(String::from("--installed and --allow-downgrade are only accepted by -f"))
+    }
+
+    // A checksum is only needed for what is downloaded
+    if url.is_some() && mode != Mode::Flash {
+        return Err(String::from("--url is only accepted by -f"))
+    }
+    if sha256.is_some() && url.is_none() {
+        return Err(String::from("--sha256 is only accepted with --url"))
+    }
+    if sha256.as_ref().is_some_and(|h| h.len() != 64 || !h.bytes().all(|b| b.is_ascii_hexdigit())) {
+        return Err(String::from("--sha256 takes 64 hexadecimal digits"))
+    }
+
+    // There is nothing to set without at least one field. Finding takes
+    // --tone, which matches either side.
+    let fields = rx_tone.is_some() || tx_tone.is_some();
+    if mode == Mode::BulkSet && !fields {
+        return Err(String::from("channels bulk-set needs --tone, --rx-tone or --tx-tone"))
+    }
+    if mode == Mode::ChannelFind && rx_tone != tx_tone {
+        return Err(String::from("channels find matches either side, so takes --tone rather than --rx-tone or --tx-tone"))
+    }
+    if !matches!(mode, Mode::BulkSet | Mode::ChannelFind) && fields {
+        return Err(String::from("--tone, --rx-tone and --tx-tone are only accepted by channels bulk-set and find"))
+    }
+    if condition.is_some() && mode != Mode::BulkSet {
+        return Err(String::from("--where is only accepted by channels bulk-set"))
+    }
+    if frequencies.is_some() && mode != Mode::ChannelFind {
+        return Err(String::from("--freq is only accepted by channels find"))
+    }
+
+    // Tidying needs something to do, and can only sort by what is decoded
+    let tidies = dedupe || sort.is_some() || compact;
+    if tidies != (mode == Mode::ChannelTidy) {
+        return Err(String::from(match tidies {
+            true => "--dedupe, --sort and --compact are only accepted by channels tidy",
+            false => "channels tidy needs --dedupe, --sort or --compact"
+        }))
+    }
+    if sort.as_deref().is_some_and(|s| s != "frequency" && s != "name") {
+        return Err(String::from("--sort takes frequency or name"))
+    }
+
+    // Renaming needs a template, and only renaming and finding match names against a pattern
+    if template.is_some() != (mode == Mode::ChannelRename) {
+        return Err(String::from(match template {
+            Some(_) => "--template is only accepted by channels rename",
+            None => "channels rename needs --template"
+        }))
+    }
+    if pattern.is_some() && !matches!(mode, Mode::ChannelRename | Mode::ChannelFind) {
+        return Err(String::from("--match is only accepted by channels rename and find"))
+    }
+
+    if (blank_channels || blank_calibration) && mode != Mode::Scrub {
+        return Err(String::from("--blank-channels and --blank-calibration are only accepted by scrub"))
+    }
+
+    if listen.is_some() && mode != Mode::Serve {
+        return Err(String::from("--listen is only accepted by serve"))
+    }
+
+    if on_connect.is_some() && mode != Mode::Watch {
+        return Err(String::from("--on-connect is only accepted by watch"))
+    }
+    if on_connect.as_deref().is_some_and(|action| action != "backup") {
+        return Err(String::from("--on-connect takes backup, the only action so far"))
+    }
+
+    // Incremental backups are kept as a directory of whole regions
+    if incremental.is_some() && (mode != Mode::Dump || channels_only || files[0] == "-") {
+        return Err(String::from("--incremental is only accepted by -d into a directory, without --channels"))
+    }
+    if encrypt && (mode != Mode::Dump || incremental.is_some()) {
+        return Err(String::from("--encrypt is only accepted by -d, without --incremental"))
+    }
+
+    // Incremental backups only ever add files next to their manifest, and
+    // read through the same path as every other read
+    if (pipeline || verify_dump) && (mode != Mode::Dump || incremental.is_some()) {
+        return Err(String::from("--pipeline and --verify-dump are only accepted by -d, without --incremental"))
+    }
+    if force && !matches!(mode, Mode::Dump | Mode::GoldenSet) {
+        return Err(String::from("--force is only accepted by -d and golden set"))
+    }
+
+    // Incremental backups are region files that restore and assemble read as they are
+    let channel_file = matches!(mode, Mode::ChannelImport | Mode::ChannelExport);
+    if let Some(format) = &format {
+        match mode {
+            Mode::Dump if incremental.is_some() => return Err(String::from("--format is not accepted with --incremental")),
+            Mode::Dump if format != "bin" && format != "ihex" => return Err(String::from("-d --format takes bin or ihex")),
+            _ if channel_file && codeplug::find(format).is_none() =>
+                return Err(format!("--format takes {}", codeplug::FORMATS.map(|f| f.name()).join(" or "))),
+            Mode::Dump => (),
+            _ if !channel_file => return Err(String::from("--format is only accepted by -d, channels import and export")),
+            _ => ()
+        }
+    }
+
+    // Re-reads would land amid replies to requests already in flight
+    if pipeline && verify_dump {
+        return Err(String::from("--pipeline and --verify-dump cannot be given together"))
+    }
+
+    // Keeping no backups at all would delete the one just taken
+    if rotate.is_some() && mode != Mode::Backup {
+        return Err(String::from("--rotate is only accepted by backup"))
+    }
+    if rotate == Some(0) {
+        return Err(String::from("--rotate must keep at least 1 backup"))
+    }
+
+    // Hooks run around operations that change or save what is on a radio
+    let hooked = matches!(mode, Mode::Dump | Mode::Flash | Mode::Restore | Mode::Clone);
+    if (pre_hook.is_some() || post_hook.is_some()) && !hooked {
+        return Err(String::from("--pre-hook and --post-hook are only accepted by -d, -f, -r and clone"))
+    }
+
+    if ignore_manifest && mode != Mode::Restore {
+        return Err(String::from("--ignore-manifest is only accepted by -r"))
+    }
+
+    // The bootloader cannot read MCU flash back
+    if (paranoid || no_paranoid) && mode != Mode::Restore {
+        return Err(String::from("--paranoid and --no-paranoid are only accepted by -r"))
+    }
+    if paranoid && no_paranoid {
+        return Err(String::from("--paranoid and --no-paranoid cannot be given together"))
+    }
+
+    if send.is_some() != (mode == Mode::Raw) {
+        return Err(String::from(match send {
+            Some(_) => "--send is only accepted by raw",
+            None => "raw needs --send"
+        }))
+    }
+
+    if session.is_some() && mode != Mode::Report {
+        return Err(String::from("--session is only accepted by report"))
+    }
+
+    // Calibration data only lives in SPI flash
+    if calib_only && mode != Mode::Restore {
+        return Err(String::from("-c is only accepted by -r"))
+    }
+
+    // Calibration and channel memories are separate regions
+    if channels_only && !matches!(mode, Mode::Dump | Mode::Restore | Mode::Clone) {
+        return Err(String::from("--channels is only accepted by -d, -r and clone"))
+    }
+    if channels_only && calib_only {
+        return Err(String::from("-c and --channels cannot be given together"))
+    }
+
+    // The bootloader has no harmless command to probe a faster rate with
+    let probed = matches!(mode, Mode::Dump | Mode::Restore | Mode::Clone | Mode::Bench)
+        || (live && live_file_count(mode).is_some());
+    if baud_rate.is_some() && !probed {
+        return Err(String::from("--baud is only accepted by -d, -r, clone, bench, inspect and channel commands given -p"))
+    }
+
+    // clone reads from one port and writes to another rather than using -p
+    let transplant = mode == Mode::CalTransplant;
+    if from.is_some() != matches!(mode, Mode::CalTransplant | Mode::Clone) || to.is_some() != (mode == Mode::Clone) {
+        return Err(String::from(match mode {
+            Mode::Clone => "clone needs --from and --to",
+            Mode::CalTransplant => "cal transplant needs --from",
+            _ => "--from and --to are only accepted by clone and cal transplant"
+        }))
+    }
+    if into.is_some() != transplant {
+        return Err(String::from(match transplant {
+            true => "cal transplant needs --into",
+            false => "--into is only accepted by cal transplant"
+        }))
+    }
+    if out.is_some() != matches!(mode, Mode::CalTransplant | Mode::Assemble | Mode::Scrub) {
+        return Err(String::from(match out {
+            Some(_) => "--out is only accepted by -d, cal transplant, assemble and scrub",
+            None => "No output file given. Specify one with --out"
+        }))
+    }
+    // A radio cannot be cloned onto itself
+    if mode == Mode::Clone && from == to {
+        return Err(String::from("clone needs two different ports"))
+    }
+
+    // Only full restores write calibration from someone else's dump
+    if (preserve_calibration || force_foreign) && (mode != Mode::Restore || calib_only || channels_only) {
+        return Err(String::from("--preserve-calibration and --force-foreign are only accepted by full restores with -r"))
+    }
+    if preserve_calibration && force_foreign {
+        return Err(String::from("--preserve-calibration and --force-foreign cannot be given together"))
+    }
+
+    // Channel dumps are the same size whatever the chip
+    if let Some(size) = flash_size {
+        if !matches!(mode, Mode::Dump | Mode::Restore) || channels_only {
+            return Err(String::from("--flash-size is only accepted by -d and -r, without --channels"))
+        }
+        if !SPI_FLASH_SIZES.contains(&size) {
+            return Err(String::from("--flash-size takes 4 or 8"))
+        }
+    }
+
+    // Resume points must line up with a chunk written by an earlier restore or flash
+    if let Some(offset) = resume_from {
+        if !matches!(mode, Mode::Restore | Mode::Flash) {
+            return Err(String::from("--resume-from is only accepted by -f and -r"))
+        }
+        if offset % CHUNK_LENGTH != 0 {
+            return Err(format!("--resume-from must be a multiple of {:#x}, as printed when a transfer stops", CHUNK_LENGTH))
+        }
+    }
+
+    // Defaults only apply where the matching option would have been accepted
+    if probed {
+        baud_rate = baud_rate.or(config.baud_rate)
+    }
+    if mode == Mode::Restore && !no_paranoid {
+        paranoid = paranoid || config.verify
+    }
+    if live || mode == Mode::Clone {
+        timeout = timeout.or(config.timeout)
+    }
+    if hooked {
+        pre_hook = pre_hook.or(config.pre_hook.clone());
+        post_hook = post_hook.or(config.post_hook.clone())
+    }
+    if matches!(mode, Mode::Dump | Mode::Restore) {
+        files[0] = backup_path(config, &files[0]);
+        if !channels_only && incremental.is_none() {
+            flash_size = flash_size.or(config.flash_size)
+        }
+    }
+
+    Ok(Args {
+        mode,
+        port: port.unwrap_or_default(),
+        files,
+        calib_only,
+        channels_only,
+        baud_rate: baud_rate.unwrap_or(BAUD_RATE),
+        resume_from,
+        record,
+        radios_only,
+        known_cables_only,
+        yes,
+        installed_version,
+        allow_downgrade,
+        paranoid,
+        send,
+        session,
+        no_color,
+        timeout: timeout.map(Duration::from_millis),
+        flash_size,
+        preserve_calibration,
+        force_foreign,
+        from,
+        into,
+        out,
+        offset,
+        length,
+        ignore_manifest,
+        condition,
+        pattern,
+        frequencies,
+        template,
+        rx_tone,
+        tx_tone,
+        to,
+        incremental,
+        encrypt,
+        listen,
+        inter_chunk_delay: inter_chunk_delay.map(Duration::from_millis),
+        progress_json,
+        // Front-ends asking for JSON read it, not a person
+        accessible: (accessible || config.accessible) && !progress_json,
+        json,
+        blank_channels,
+        blank_calibration,
+        dedupe,
+        sort,
+        compact,
+        force,
+        pipeline,
+        verify_dump,
+        rotate,
+        log_file,
+        format,
+        url,
+        sha256,
+        pre_hook,
+        post_hook
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_line(line: &str) -> Result<Args, String> {
+        let argv: Vec<String> = line.split_whitespace().map(String::from).collect();
+        parse(&argv, &Config::default())
+    }
+
+    #[test]
+    fn errors_name_the_problem() {
+        assert!(parse_line("rt890-flash -p /dev/ttyUSB0 -d radio.bin").is_ok());
+        for (line, error) in [
+            ("rt890-flash", "No operation given"),
+            ("rt890-flash -d radio.bin", "No port given. Specify one with -p or set port in the config file"),
+            ("rt890-flash -p /dev/ttyUSB0 -d", "Wrong number of file names, expected 1 but got 0"),
+            ("rt890-flash -p /dev/ttyUSB0 -d radio.bin --bogus", "Unknown option --bogus"),
+            ("rt890-flash -p /dev/ttyUSB0 -d radio.bin --baud", "--baud needs a value"),
+            ("rt890-flash -p /dev/ttyUSB0 -f fw.bin -c", "-c is only accepted by -r"),
+            ("rt890-flash golden", "golden needs one of set or restore")
+        ] {
+            assert_eq!(parse_line(line).err().as_deref(), Some(error), "{}", line)
+        }
+    }
+}
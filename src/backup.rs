@@ -0,0 +1,187 @@
+/*
+    Copyright 2024 Bricky
+    https://github.com/bricky149
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+*/
+
+// Unattended backups, either one at a time from cron or a timer, or of each
+// radio as it is connected.
+
+use std::fs;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::args::{self, Args};
+use crate::config::Config;
+use crate::connect::probe_radio;
+use crate::dump::manifest_path;
+use crate::interrupt;
+use crate::output;
+use crate::remote;
+use crate::uart;
+
+// Exit codes of backup, so scheduled runs can tell a radio that was switched
+// off from one that failed part way. 2 is left for bad arguments.
+const EXIT_NO_RADIO: i32 = 3;
+const EXIT_BACKUP_FAILED: i32 = 4;
+const EXIT_UPLOAD_FAILED: i32 = 5;
+// Seconds between watch asking silent ports again, as each probe opens them
+const WATCH_REPROBE_SECS: u64 = 10;
+
+// UTC, as there is no time zone database to hand
+pub fn timestamp() -> String {
+    let secs = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+
+    // Days since 1970 to a civil date, from http://howardhinnant.github.io/date_algorithms.html
+    let z = (secs / 86400) as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!("{:04}{:02}{:02}-{:02}{:02}{:02}", year, month, day, secs / 3600 % 24, secs / 60 % 60, secs % 60)
+}
+
+fn backup_name() -> String {
+    format!("rt890-{}.bin", timestamp())
+}
+
+// Only names backup_name() gives are rotated, and they sort oldest first
+fn is_backup_name(name: &str) -> bool {
+    name.strip_prefix("rt890-")
+        .and_then(|n| n.strip_suffix(".bin"))
+        .is_some_and(|t| t.len() == 15 && t.char_indices().all(|(i, c)| if i == 8 { c == '-' } else { c.is_ascii_digit() }))
+}
+
+fn rotate_backups(dir: &Path, keep: usize) {
+    let mut backups: Vec<String> = match fs::read_dir(dir) {
+        Ok(entries) => entries.filter_map(|e| e.ok())
+            .map(|e| e.file_name().to_string_lossy().into_owned())
+            .filter(|n| is_backup_name(n))
+            .collect(),
+        Err(e) => panic!("Failed to list {}: {}", dir.display(), e)
+    };
+    backups.sort();
+
+    let old = backups.len().saturating_sub(keep);
+    for name in &backups[..old] {
+        let path = dir.join(name);
+        fs::remove_file(&path).expect("Failed to remove old backup");
+        let _ = fs::remove_file(manifest_path(&path.to_string_lossy()));
+        output::info(&format!("Removed old backup {}", path.display()))
+    }
+}
+
+// Returns the exit code, as this is meant to run unattended from cron
+pub fn backup(args: &Args, config: &Config, operation: fn(&Args) -> bool) -> i32 {
+    if !probe_radio(&args.port) {
+        output::error(&format!("No radio answered on {}. Is it switched on in normal mode?", args.port));
+        return EXIT_NO_RADIO
+    }
+
+    let argv: Vec<String> = ["rt890-flash", "-p", &args.port, "-d", &backup_name()]
+        .iter().map(|a| a.to_string()).collect();
+    let mut dump = args::parse(&argv, config).expect("Invalid backup options");
+    dump.timeout = args.timeout;
+    dump.record = args.record.clone();
+
+    // The panic hook has already reported why
+    if !panic::catch_unwind(AssertUnwindSafe(|| operation(&dump))).unwrap_or(false) {
+        // A partial dump would otherwise count as the newest backup
+        if fs::remove_file(&dump.files[0]).is_ok() {
+            output::info(&format!("Removed incomplete backup {}", dump.files[0]))
+        }
+        return EXIT_BACKUP_FAILED
+    }
+
+    // Old backups are only rotated away once the new one is safely off the machine
+    if !upload_backup(&dump.files[0], config) {
+        return EXIT_UPLOAD_FAILED
+    }
+    if let Some(keep) = args.rotate {
+        let dir = config.backup_dir.clone().unwrap_or_else(|| PathBuf::from("."));
+        rotate_backups(&dir, keep)
+    }
+    0
+}
+
+// Copies a backup and its manifest to upload_url, if one is set
+fn upload_backup(filename: &str, config: &Config) -> bool {
+    let url = match &config.upload_url {
+        Some(u) => u,
+        None => return true
+    };
+    let target = remote::Target {
+        url,
+        user: config.upload_user.as_deref(),
+        s3_region: config.upload_s3_region.as_deref()
+    };
+    for file in [filename.to_string(), manifest_path(filename)] {
+        if let Err(e) = remote::upload(&target, Path::new(&file)) {
+            output::error(&e);
+            return false
+        }
+    }
+    output::info(&format!("Backup uploaded to {}", url));
+    true
+}
+
+// Every port is probed at startup and as soon as it appears. Ports with no
+// radio answering are asked again now and then, so one switched on at the end
+// of a cable already plugged in is noticed too.
+pub fn watch(config: &Config, operation: fn(&Args) -> bool) {
+    let port_names = || -> Vec<String> {
+        uart::get_available_ports().into_iter().map(|p| p.port_name).collect()
+    };
+    let mut done: Vec<String> = Vec::new();
+    let mut seen: Vec<String> = Vec::new();
+    output::info("Waiting for a radio to be connected. Press Ctrl-C to stop.");
+
+    for tick in 0.. {
+        if interrupt::interrupted() {
+            break
+        }
+        if tick > 0 {
+            thread::sleep(Duration::from_secs(1))
+        }
+        let present = port_names();
+        // Forget unplugged cables so plugging them back in is noticed
+        done.retain(|p| present.contains(p));
+        let reprobe = tick % WATCH_REPROBE_SECS == 0;
+
+        for port in present.iter().filter(|p| !done.contains(p)).cloned().collect::<Vec<_>>() {
+            let due = reprobe || !seen.contains(&port);
+            if !due || !probe_radio(&port) {
+                continue
+            }
+            done.push(port.clone());
+
+            output::info(&format!("Radio found on {}, backing it up", port));
+            let argv: Vec<String> = ["rt890-flash", "-p", &port, "-d", &backup_name()]
+                .iter().map(|a| a.to_string()).collect();
+            let args = args::parse(&argv, config).expect("Invalid backup options");
+            // A failed backup is reported by the panic hook and watching carries on
+            if panic::catch_unwind(AssertUnwindSafe(|| operation(&args))).unwrap_or(false) {
+                upload_backup(&args.files[0], config);
+            }
+        }
+        seen = present
+    }
+}
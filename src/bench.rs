@@ -0,0 +1,60 @@
+/*
+    Copyright 2024 Bricky
+    https://github.com/bricky149
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+*/
+
+// Reads at increasing run lengths, to compare cables and adapters.
+
+use std::time::{Duration, Instant};
+
+use crate::args::Args;
+use crate::connect::open_radio;
+use crate::interrupt;
+use crate::uart::{self, CHUNK_LENGTH};
+
+pub fn run_benchmark(args: &Args) {
+    let mut port = open_radio(args, Duration::from_secs(2));
+
+    println!("{:>8} {:>10} {:>8} {:>8} {:>8} {:>8} {:>8}", "Chunks", "Bytes/s", "Retries", "p50 ms", "p90 ms", "p99 ms", "Max ms");
+    for run_length in [1, 16, 128, 512] {
+        let retries = uart::retries();
+        let mut latencies = Vec::with_capacity(run_length);
+        let started = Instant::now();
+
+        for block in 0..run_length {
+            if interrupt::interrupted() {
+                interrupt::stop(interrupt::EXIT_CODE)
+            }
+            let sent = Instant::now();
+            match uart::command_readspiflash(port.as_mut(), block as u16) {
+                Ok(Some(_)) => latencies.push(sent.elapsed()),
+                Ok(None) => panic!("Failed to read SPI flash at address {:#08x}", block * CHUNK_LENGTH),
+                Err(e) => panic!("{}. Is the radio in normal mode?", e)
+            }
+        }
+
+        let elapsed = started.elapsed().as_secs_f64();
+        latencies.sort();
+        let percentile = |p: usize| latencies[(latencies.len() - 1) * p / 100].as_secs_f64() * 1000.0;
+        println!("{:>8} {:>10.0} {:>8} {:>8.1} {:>8.1} {:>8.1} {:>8.1}",
+            run_length,
+            (run_length * CHUNK_LENGTH) as f64 / elapsed,
+            uart::retries() - retries,
+            percentile(50),
+            percentile(90),
+            percentile(99),
+            percentile(100))
+    }
+}
@@ -0,0 +1,487 @@
+/*
+    Copyright 2024 Bricky
+    https://github.com/bricky149
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+*/
+
+use std::fmt;
+use std::ops::Range;
+
+use crate::spi::CHANNELS;
+
+// Channel memories are 32-byte records following the VFOs at the start of
+// the channel region. Only the fields below are understood, every other
+// byte of a record is kept exactly as it was.
+// Stock firmware and every Open Edition build seen so far share this layout,
+// so there is nothing to migrate between versions yet. Should one change it,
+// its offsets belong beside these with a mapping between the two, as only
+// whole records are ever moved.
+pub const CHANNEL_BASE: usize = CHANNELS.offset + 0x1000;
+pub const CHANNEL_COUNT: usize = 1024;
+pub const RECORD_LENGTH: usize = 32;
+
+const RX_FREQUENCY: usize = 0;
+const TX_FREQUENCY: usize = 4;
+const RX_TONE: usize = 8;
+const TX_TONE: usize = 10;
+const NAME: usize = 20;
+pub const NAME_LENGTH: usize = 10;
+// Receive-only channels have no transmit frequency, left erased or zeroed
+pub const NO_TRANSMIT: u32 = 0xFFFF_FFFF;
+
+// The BK4819 tunes 18 to 660 MHz and 840 to 1300 MHz, in tens of hertz
+pub const RX_RANGES: [Range<u32>; 2] = [1_800_000..66_000_001, 84_000_000..130_000_001];
+// The power amplifier and its filters only cover the VHF and UHF bands
+pub const TX_RANGES: [Range<u32>; 2] = [13_600_000..17_400_001, 40_000_000..52_000_001];
+
+// Tones above this are DCS codes, with the next bit marking inverted ones
+const DCS_FLAG: u16 = 0x8000;
+const DCS_INVERTED: u16 = 0x4000;
+
+// The standard tones, which are the only ones the radio generates
+const CTCSS_TONES: [u16; 50] = [
+    670, 693, 719, 744, 770, 797, 825, 854, 885, 915, 948, 974, 1000, 1035, 1072, 1109, 1148, 1188, 1230, 1273,
+    1318, 1365, 1413, 1462, 1514, 1567, 1598, 1622, 1655, 1679, 1713, 1738, 1773, 1799, 1835, 1862, 1899, 1928,
+    1966, 1995, 2035, 2065, 2107, 2181, 2257, 2291, 2336, 2418, 2503, 2541
+];
+const DCS_CODES: [u16; 104] = [
+    0o023, 0o025, 0o026, 0o031, 0o032, 0o036, 0o043, 0o047, 0o051, 0o053, 0o054, 0o065, 0o071, 0o072, 0o073,
+    0o074, 0o114, 0o115, 0o116, 0o122, 0o125, 0o131, 0o132, 0o134, 0o143, 0o145, 0o152, 0o155, 0o156, 0o162,
+    0o165, 0o172, 0o174, 0o205, 0o212, 0o223, 0o225, 0o226, 0o243, 0o244, 0o245, 0o246, 0o251, 0o252, 0o255,
+    0o261, 0o263, 0o265, 0o266, 0o271, 0o274, 0o306, 0o311, 0o315, 0o325, 0o331, 0o332, 0o343, 0o346, 0o351,
+    0o356, 0o364, 0o365, 0o371, 0o411, 0o412, 0o413, 0o423, 0o431, 0o432, 0o445, 0o446, 0o452, 0o454, 0o455,
+    0o462, 0o464, 0o465, 0o466, 0o503, 0o506, 0o516, 0o523, 0o526, 0o532, 0o546, 0o565, 0o606, 0o612, 0o624,
+    0o627, 0o631, 0o632, 0o654, 0o662, 0o664, 0o703, 0o712, 0o723, 0o731, 0o732, 0o734, 0o743, 0o754
+];
+
+fn nearest(valid: &[u16], value: u16) -> u16 {
+    *valid.iter().min_by_key(|&&v| v.abs_diff(value)).unwrap()
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Tone {
+    None,
+    /// Tenths of a hertz, e.g. 885 for 88.5 Hz
+    Ctcss(u16),
+    /// Octal code as printed, e.g. 0o023 for D023N
+    Dcs(u16, bool)
+}
+
+impl Tone {
+    fn decode(raw: u16) -> Tone {
+        match raw {
+            0 | 0xFFFF => Tone::None,
+            _ if raw & DCS_FLAG != 0 => Tone::Dcs(raw & 0x1FF, raw & DCS_INVERTED != 0),
+            _ => Tone::Ctcss(raw)
+        }
+    }
+
+    fn encode(self) -> u16 {
+        match self {
+            Tone::None => 0,
+            Tone::Ctcss(tenths) => tenths,
+            Tone::Dcs(code, false) => DCS_FLAG | code,
+            Tone::Dcs(code, true) => DCS_FLAG | DCS_INVERTED | code
+        }
+    }
+
+    /// Reads "off", a CTCSS frequency such as 88.5 or 885, or a DCS code
+    /// such as D023N or D023I. Tones the radio cannot generate are refused,
+    /// naming the nearest one it can.
+    pub fn parse(text: &str) -> Result<Tone, String> {
+        let text = text.trim();
+        let invalid = || format!("{} is not a tone", text);
+        if text.eq_ignore_ascii_case("off") || text.eq_ignore_ascii_case("none") {
+            return Ok(Tone::None)
+        }
+        if let Some(code) = text.strip_prefix('D').or_else(|| text.strip_prefix('d')) {
+            let (digits, inverted) = match code.as_bytes().last().ok_or_else(invalid)? {
+                b'N' | b'n' => (&code[..code.len() - 1], false),
+                b'I' | b'i' | b'R' | b'r' => (&code[..code.len() - 1], true),
+                _ => (code, false)
+            };
+            let code = u16::from_str_radix(digits, 8).map_err(|_| invalid())?;
+            let tone = Tone::Dcs(code, inverted);
+            if !DCS_CODES.contains(&code) {
+                return Err(format!("{} is not a DCS code the radio can send, the nearest is {}",
+                    tone, Tone::Dcs(nearest(&DCS_CODES, code), inverted)))
+            }
+            return Ok(tone)
+        }
+
+        // No CTCSS tone reaches 300 Hz, so larger whole numbers are tenths
+        let hz: f64 = text.parse().map_err(|_| invalid())?;
+        let tenths = if !text.contains('.') && hz >= 300.0 { hz } else { (hz * 10.0).round() };
+        if !(0.0..=f64::from(u16::MAX)).contains(&tenths) {
+            return Err(invalid())
+        }
+        let tone = Tone::Ctcss(tenths as u16);
+        if !CTCSS_TONES.contains(&(tenths as u16)) {
+            return Err(format!("{} Hz is not a standard CTCSS tone, the nearest is {}",
+                tone, Tone::Ctcss(nearest(&CTCSS_TONES, tenths as u16))))
+        }
+        Ok(tone)
+    }
+
+    /// Whether the radio can generate this tone
+    pub fn is_standard(self) -> bool {
+        match self {
+            Tone::None => true,
+            Tone::Ctcss(tenths) => CTCSS_TONES.contains(&tenths),
+            Tone::Dcs(code, _) => DCS_CODES.contains(&code)
+        }
+    }
+}
+
+impl fmt::Display for Tone {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Tone::None => write!(f, "off"),
+            Tone::Ctcss(tenths) => write!(f, "{}.{}", tenths / 10, tenths % 10),
+            Tone::Dcs(code, inverted) => write!(f, "D{:03o}{}", code, if *inverted { 'I' } else { 'N' })
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Channel {
+    /// Numbered from 1 as on the radio
+    pub number: usize,
+    /// Tens of hertz, e.g. 14550000 for 145.5 MHz
+    pub rx_frequency: u32,
+    pub tx_frequency: u32,
+    pub rx_tone: Tone,
+    pub tx_tone: Tone,
+    pub name: String
+}
+
+impl Channel {
+    pub fn band(&self) -> &'static str {
+        match self.rx_frequency {
+            0..=2_999_999 => "HF",
+            3_000_000..=29_999_999 => "VHF",
+            _ => "UHF"
+        }
+    }
+
+    pub fn transmits(&self) -> bool {
+        self.tx_frequency != 0 && self.tx_frequency != NO_TRANSMIT
+    }
+}
+
+// Channels sit on a 2.5 or 6.25 kHz raster, or 8.33 kHz in the air band,
+// which does not divide into tens of hertz and so is rounded
+fn on_step(frequency: u32) -> bool {
+    let thirds = (frequency as u64 * 3) % 2500;
+    frequency.is_multiple_of(250) || frequency.is_multiple_of(625) || thirds <= 15 || thirds >= 2485
+}
+
+/// Reasons the radio could not use a channel as it is stored, if any
+pub fn problems(channel: &Channel) -> Vec<String> {
+    let mut found = Vec::new();
+    if !RX_RANGES.iter().any(|r| r.contains(&channel.rx_frequency)) {
+        found.push(format!("receive frequency {} MHz is outside the radio's range", format_frequency(channel.rx_frequency)))
+    } else if !on_step(channel.rx_frequency) {
+        found.push(format!("receive frequency {} MHz is not on a 2.5, 6.25 or 8.33 kHz step", format_frequency(channel.rx_frequency)))
+    }
+    if channel.transmits() && !TX_RANGES.iter().any(|r| r.contains(&channel.tx_frequency)) {
+        found.push(format!("transmit frequency {} MHz is outside the radio's transmit bands", format_frequency(channel.tx_frequency)))
+    }
+    for tone in [channel.rx_tone, channel.tx_tone] {
+        if !tone.is_standard() {
+            found.push(format!("tone {} is not one the radio can generate", tone))
+        }
+    }
+    if !channel.name.chars().all(|c| c.is_ascii_graphic() || c == ' ') {
+        found.push(String::from("name has characters the radio cannot show"))
+    }
+    found
+}
+
+/// Whether the channel memories read sensibly with the layout above, or None
+/// if none are in use. No firmware marks its layout in SPI flash, so this is
+/// judged from the records: read with the wrong offsets, few of them would
+/// hold a frequency the radio can tune and tones it can send.
+pub fn known_layout(spi: &[u8]) -> Option<bool> {
+    let in_use = all(spi);
+    if in_use.is_empty() {
+        return None
+    }
+    // A few odd channels are the user's doing, most of them are the layout's
+    let sensible = in_use.iter().filter(|c| problems(c).is_empty()).count();
+    Some(sensible * 2 >= in_use.len())
+}
+
+/// Formats tens of hertz as megahertz, e.g. 145.50000
+pub fn format_frequency(frequency: u32) -> String {
+    format!("{}.{:05}", frequency / 100_000, frequency % 100_000)
+}
+
+/// Reads megahertz, e.g. 145.5, as tens of hertz
+pub fn parse_frequency(text: &str) -> Option<u32> {
+    let (whole, fraction) = text.trim().split_once('.').unwrap_or((text.trim(), ""));
+    // CHIRP writes hertz, which is fine as long as they are whole tens
+    let fraction = match fraction.get(5..) {
+        Some(hz) if hz.bytes().all(|b| b == b'0') => &fraction[..5],
+        Some(_) => return None,
+        None => fraction
+    };
+    if !fraction.chars().all(|c| c.is_ascii_digit()) {
+        return None
+    }
+    let whole: u32 = whole.parse().ok()?;
+    let fraction: u32 = format!("{:0<5}", fraction).parse().ok()?;
+    whole.checked_mul(100_000)?.checked_add(fraction)
+}
+
+fn record_offset(number: usize) -> usize {
+    CHANNEL_BASE + (number - 1) * RECORD_LENGTH
+}
+
+fn read_u32(record: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([record[at], record[at + 1], record[at + 2], record[at + 3]])
+}
+
+fn read_u16(record: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([record[at], record[at + 1]])
+}
+
+/// Reads a channel from a full dump, or None if its memory is empty
+pub fn get(spi: &[u8], number: usize) -> Option<Channel> {
+    let record = &spi[record_offset(number)..record_offset(number) + RECORD_LENGTH];
+    let rx_frequency = read_u32(record, RX_FREQUENCY);
+    if rx_frequency == 0 || rx_frequency == 0xFFFF_FFFF {
+        return None
+    }
+
+    let name = &record[NAME..NAME + NAME_LENGTH];
+    let name: String = name.iter()
+        .take_while(|&&b| b != 0 && b != 0xFF)
+        .map(|&b| b as char)
+        .collect();
+
+    Some(Channel {
+        number,
+        rx_frequency,
+        tx_frequency: read_u32(record, TX_FREQUENCY),
+        rx_tone: Tone::decode(read_u16(record, RX_TONE)),
+        tx_tone: Tone::decode(read_u16(record, TX_TONE)),
+        name: name.trim_end().to_string()
+    })
+}
+
+/// Every channel memory in use, in order
+pub fn all(spi: &[u8]) -> Vec<Channel> {
+    (1..=CHANNEL_COUNT).filter_map(|n| get(spi, n)).collect()
+}
+
+/// Numbers of the channel memories not in use, in order
+pub fn free(spi: &[u8]) -> Vec<usize> {
+    (1..=CHANNEL_COUNT).filter(|&n| get(spi, n).is_none()).collect()
+}
+
+/// Writes the known fields of a channel into a full dump
+pub fn set(spi: &mut [u8], channel: &Channel) {
+    let start = record_offset(channel.number);
+
+    // An erased record may not be valid to the firmware, so new channels
+    // take their unknown fields from the first channel in use
+    if get(spi, channel.number).is_none() {
+        if let Some(template) = all(spi).first() {
+            let from = record_offset(template.number);
+            spi.copy_within(from..from + RECORD_LENGTH, start)
+        }
+    }
+
+    let record = &mut spi[start..start + RECORD_LENGTH];
+    record[RX_FREQUENCY..RX_FREQUENCY + 4].copy_from_slice(&channel.rx_frequency.to_le_bytes());
+    record[TX_FREQUENCY..TX_FREQUENCY + 4].copy_from_slice(&channel.tx_frequency.to_le_bytes());
+    record[RX_TONE..RX_TONE + 2].copy_from_slice(&channel.rx_tone.encode().to_le_bytes());
+    record[TX_TONE..TX_TONE + 2].copy_from_slice(&channel.tx_tone.encode().to_le_bytes());
+
+    // Names are padded with spaces and limited to printable ASCII
+    let mut name = [b' '; NAME_LENGTH];
+    for (dst, c) in name.iter_mut().zip(channel.name.chars()) {
+        *dst = if c.is_ascii_graphic() || c == ' ' { c as u8 } else { b'?' }
+    }
+    record[NAME..NAME + NAME_LENGTH].copy_from_slice(&name)
+}
+
+/// Moves whole records, unknown fields and all, so that for each (to, from)
+/// pair memory to holds what memory from did. Memories in use that are not
+/// moved into are erased.
+pub fn rearrange(spi: &mut [u8], moves: &[(usize, usize)]) {
+    let before = spi[CHANNEL_BASE..CHANNEL_BASE + CHANNEL_COUNT * RECORD_LENGTH].to_vec();
+    let record = |number: usize| &before[(number - 1) * RECORD_LENGTH..number * RECORD_LENGTH];
+
+    for channel in all(spi) {
+        if !moves.iter().any(|&(to, _)| to == channel.number) {
+            let start = record_offset(channel.number);
+            spi[start..start + RECORD_LENGTH].fill(0xFF)
+        }
+    }
+    for &(to, from) in moves {
+        let start = record_offset(to);
+        spi[start..start + RECORD_LENGTH].copy_from_slice(record(from))
+    }
+}
+
+/// Checks a channel against a FIELD=VALUE condition, e.g. band=UHF
+pub fn matches(channel: &Channel, condition: &str) -> Option<bool> {
+    let (field, value) = condition.split_once('=')?;
+    Some(match field.trim() {
+        "band" => channel.band().eq_ignore_ascii_case(value.trim()),
+        "name" => channel.name.eq_ignore_ascii_case(value.trim()),
+        "frequency" => channel.rx_frequency == parse_frequency(value)?,
+        "channel" => {
+            let (first, last) = value.split_once('-').unwrap_or((value, value));
+            let range = first.trim().parse::<usize>().ok()?..=last.trim().parse::<usize>().ok()?;
+            range.contains(&channel.number)
+        }
+        _ => return None
+    })
+}
+
+/// Matches a name against a pattern where * stands for any run of characters
+/// and ? for any one, ignoring case as names are shown in capitals
+pub fn glob_matches(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.to_uppercase().chars().collect();
+    let name: Vec<char> = name.to_uppercase().chars().collect();
+    // Backtracks to the last * only, which is enough for a single pass
+    let (mut p, mut n) = (0, 0);
+    let mut star = None;
+    while n < name.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == name[n]) {
+            p += 1;
+            n += 1
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, n));
+            p += 1
+        } else if let Some((star_p, star_n)) = star {
+            p = star_p + 1;
+            n = star_n + 1;
+            star = Some((star_p, star_n + 1))
+        } else {
+            return false
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Fills in a name template, e.g. "{index:02} {name}", where index counts the
+/// channels being renamed from 1. A width starting with 0 pads with zeros.
+pub fn render_name(template: &str, channel: &Channel, index: usize) -> Result<String, String> {
+    let mut name = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        name.push_str(&rest[..start]);
+        let end = rest[start..].find('}').ok_or(format!("Unclosed {{ in template {}", template))? + start;
+        let (field, spec) = rest[start + 1..end].split_once(':').unwrap_or((&rest[start + 1..end], ""));
+        let width: usize = if spec.is_empty() { 0 } else { spec.parse().map_err(|_| format!("Invalid width {} in template", spec))? };
+        let value = match field {
+            "name" => channel.name.clone(),
+            "index" => index.to_string(),
+            "number" => channel.number.to_string(),
+            "frequency" => format_frequency(channel.rx_frequency),
+            "band" => channel.band().to_string(),
+            _ => return Err(format!("Unknown field {{{}}} in template, use name, index, number, frequency or band", field))
+        };
+        match spec.starts_with('0') {
+            true => name.push_str(&format!("{:0>width$}", value, width = width)),
+            false => name.push_str(&format!("{:<width$}", value, width = width))
+        }
+        rest = &rest[end + 1..]
+    }
+    name.push_str(rest);
+    Ok(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::spi::SPI_FLASH_SIZE;
+
+    #[test]
+    fn frequencies_in_megahertz() {
+        assert_eq!(parse_frequency("145.5"), Some(14_550_000));
+        assert_eq!(parse_frequency("446.006250"), Some(44_600_625));
+        assert_eq!(parse_frequency(" 430 "), Some(43_000_000));
+        assert_eq!(parse_frequency("145.000005"), None);
+        assert_eq!(parse_frequency("145.5x"), None);
+        assert_eq!(parse_frequency("50000.0"), None);
+        assert_eq!(format_frequency(44_600_625), "446.00625")
+    }
+
+    #[test]
+    fn records_round_trip() {
+        let mut spi = vec![0xFF; SPI_FLASH_SIZE];
+        let channel = Channel {
+            number: 7,
+            rx_frequency: 14_570_000,
+            tx_frequency: 14_510_000,
+            rx_tone: Tone::Dcs(0o23, true),
+            tx_tone: Tone::Ctcss(885),
+            name: String::from("GB3XX")
+        };
+        set(&mut spi, &channel);
+        let read = get(&spi, 7).unwrap();
+        assert_eq!((read.rx_frequency, read.tx_frequency, read.name.as_str()), (14_570_000, 14_510_000, "GB3XX"));
+        assert!(read.rx_tone == channel.rx_tone && read.tx_tone == channel.tx_tone);
+        assert!(get(&spi, 6).is_none());
+        assert_eq!(all(&spi).len(), 1);
+        assert_eq!(free(&spi).len(), CHANNEL_COUNT - 1);
+        assert!(problems(&read).is_empty())
+    }
+
+    #[test]
+    fn frequency_steps() {
+        // 2.5, 6.25 and 8.33 kHz steps, the last within a hertz or so
+        for frequency in [14_550_000, 14_550_250, 44_600_625, 11_810_833, 11_810_834, 11_810_000] {
+            assert!(on_step(frequency), "{}", frequency)
+        }
+        for frequency in [14_550_100, 44_600_600, 11_810_800] {
+            assert!(!on_step(frequency), "{}", frequency)
+        }
+    }
+
+    #[test]
+    fn transmit_bands() {
+        let mut channel = Channel {
+            number: 1,
+            rx_frequency: 11_810_000,
+            tx_frequency: NO_TRANSMIT,
+            rx_tone: Tone::None,
+            tx_tone: Tone::None,
+            name: String::from("AIR")
+        };
+        // Receiving in the air band is fine, transmitting there is not
+        assert!(problems(&channel).is_empty());
+        channel.tx_frequency = 11_810_000;
+        assert_eq!(problems(&channel), ["transmit frequency 118.10000 MHz is outside the radio's transmit bands"]);
+        channel.tx_frequency = 14_550_000;
+        assert!(problems(&channel).is_empty())
+    }
+
+    #[test]
+    fn tones() {
+        assert!(Tone::parse("88.5") == Ok(Tone::Ctcss(885)));
+        assert!(Tone::parse("885") == Ok(Tone::Ctcss(885)));
+        assert!(Tone::parse("D023N") == Ok(Tone::Dcs(0o23, false)));
+        assert!(Tone::parse("d754i") == Ok(Tone::Dcs(0o754, true)));
+        assert!(Tone::parse("off") == Ok(Tone::None));
+        assert_eq!(Tone::parse("89.0").err().unwrap_or_default(), "89.0 Hz is not a standard CTCSS tone, the nearest is 88.5");
+        assert!(Tone::parse("D024N").is_err());
+        assert!(Tone::parse("D09N").is_err())
+    }
+}
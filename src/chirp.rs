@@ -0,0 +1,308 @@
+/*
+    Copyright 2024 Bricky
+    https://github.com/bricky149
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+*/
+
+use crate::channels::{self, parse_frequency, Channel, Tone};
+
+// CHIRP's generic CSV, which RepeaterBook can also export, has one channel
+// per row with named columns in any order
+fn split_csv_line(line: &str) -> Vec<String> {
+    let mut fields = vec![String::new()];
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                fields.last_mut().unwrap().push('"');
+                chars.next();
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => fields.push(String::new()),
+            _ => fields.last_mut().unwrap().push(c)
+        }
+    }
+    fields
+}
+
+struct Row<'a> {
+    header: &'a [String],
+    values: Vec<String>
+}
+
+impl Row<'_> {
+    // Missing columns read as empty, as CHIRP leaves out ones it has no use for
+    fn get(&self, name: &str) -> &str {
+        self.header.iter().position(|h| h == name)
+            .and_then(|column| self.values.get(column))
+            .map_or("", |value| value.trim())
+    }
+}
+
+fn dcs(code: &str, polarity: Option<char>) -> Result<Tone, String> {
+    Tone::parse(&format!("D{}{}", code.trim(), if polarity == Some('R') { 'I' } else { 'N' }))
+}
+
+// Works out both tones from CHIRP's tone mode and the columns it refers to
+fn tones(row: &Row) -> Result<(Tone, Tone), String> {
+    let field = |column| row.get(column);
+    let ctcss = |column| Tone::parse(row.get(column));
+    let polarity = field("DtcsPolarity");
+    let (tx_polarity, rx_polarity) = (polarity.chars().next(), polarity.chars().nth(1));
+
+    let mode = || format!("{} is not a tone mode", field("Tone"));
+    Ok(match field("Tone") {
+        "" => (Tone::None, Tone::None),
+        "Tone" => (ctcss("rToneFreq")?, Tone::None),
+        "TSQL" => (ctcss("cToneFreq")?, ctcss("cToneFreq")?),
+        "DTCS" => (dcs(field("DtcsCode"), tx_polarity)?, dcs(field("DtcsCode"), rx_polarity)?),
+        "Cross" => {
+            let (tx, rx) = field("CrossMode").split_once("->").ok_or_else(mode)?;
+            let tx = match tx {
+                "" => Tone::None,
+                "Tone" => ctcss("rToneFreq")?,
+                "DTCS" => dcs(field("DtcsCode"), tx_polarity)?,
+                _ => return Err(mode())
+            };
+            let rx = match rx {
+                "" => Tone::None,
+                "Tone" => ctcss("cToneFreq")?,
+                "DTCS" => dcs(field("RxDtcsCode"), rx_polarity)?,
+                _ => return Err(mode())
+            };
+            (tx, rx)
+        }
+        _ => return Err(mode())
+    })
+}
+
+// Reads one row, or says what is wrong with it
+fn channel(row: &Row, line: usize) -> Result<Channel, String> {
+    let field = |column| row.get(column);
+    let invalid = |what: &str| format!("Line {} has an invalid {}", line, what);
+
+    let rx_frequency = parse_frequency(field("Frequency")).ok_or_else(|| invalid("frequency"))?;
+    let offset = || parse_frequency(field("Offset")).ok_or_else(|| invalid("offset"));
+    let tx_frequency = match field("Duplex") {
+        "" => rx_frequency,
+        // Transmit inhibit, as for air band or weather channels
+        "off" => channels::NO_TRANSMIT,
+        "+" => rx_frequency.checked_add(offset()?).ok_or_else(|| invalid("offset"))?,
+        "-" => rx_frequency.checked_sub(offset()?).ok_or_else(|| invalid("offset"))?,
+        "split" => offset()?,
+        _ => return Err(invalid("duplex"))
+    };
+    let (tx_tone, rx_tone) = tones(row).map_err(|e| format!("Line {}: {}", line, e))?;
+
+    Ok(Channel {
+        number: 0,
+        rx_frequency,
+        tx_frequency,
+        rx_tone,
+        tx_tone,
+        name: field("Name").to_string()
+    })
+}
+
+/// Reads the channels from a CHIRP CSV file, numbered 0 so they can be put
+/// wherever there is room. Every row the radio could not use is reported,
+/// one line each, rather than only the first.
+pub fn import_csv(text: &str) -> Result<Vec<Channel>, String> {
+    let mut lines = text.lines().enumerate().filter(|(_, l)| !l.trim().is_empty());
+    let header = match lines.next() {
+        Some((_, h)) => split_csv_line(h),
+        None => return Ok(Vec::new())
+    };
+    if !header.iter().any(|h| h == "Frequency") {
+        return Err(String::from("Not a CHIRP CSV file, there is no Frequency column"))
+    }
+
+    let mut channels = Vec::new();
+    let mut errors = Vec::new();
+    for (i, line) in lines {
+        let row = Row { header: &header, values: split_csv_line(line) };
+        match channel(&row, i + 1) {
+            Ok(c) => {
+                let problems = channels::problems(&c);
+                errors.extend(problems.iter().map(|p| format!("Line {}: {}", i + 1, p)));
+                if problems.is_empty() {
+                    channels.push(c)
+                }
+            }
+            Err(e) => errors.push(e)
+        }
+    }
+    if !errors.is_empty() {
+        return Err(errors.join("\n"))
+    }
+    Ok(channels)
+}
+
+// Columns in the order CHIRP writes them, so its CSV import reads them as its own
+const CSV_HEADER: &str = "Location,Name,Frequency,Duplex,Offset,Tone,rToneFreq,cToneFreq,DtcsCode,DtcsPolarity,RxDtcsCode,CrossMode,Mode,TStep,Skip,Power,Comment,URCALL,RPT1CALL,RPT2CALL,DVCODE";
+// Larger gaps between receive and transmit are written as split, which is
+// how CHIRP shows cross-band channels
+const MAX_OFFSET: u32 = 1_000_000;
+
+// CHIRP writes megahertz to six places, the nearest hertz
+fn csv_frequency(frequency: u32) -> String {
+    format!("{}0", channels::format_frequency(frequency))
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+// Tone mode, rToneFreq, cToneFreq, DtcsCode, DtcsPolarity, RxDtcsCode and
+// CrossMode, with CHIRP's defaults in columns the mode does not use
+fn csv_tones(tx: Tone, rx: Tone) -> [String; 7] {
+    let hz = |tone: Tone, default: &str| match tone {
+        Tone::Ctcss(_) => tone.to_string(),
+        _ => default.to_string()
+    };
+    let code = |tone: Tone| match tone {
+        Tone::Dcs(code, _) => format!("{:03o}", code),
+        _ => String::from("023")
+    };
+    let polarity = |tone: Tone| match tone {
+        Tone::Dcs(_, true) => 'R',
+        _ => 'N'
+    };
+    let kind = |tone: Tone| match tone {
+        Tone::None => "",
+        Tone::Ctcss(_) => "Tone",
+        Tone::Dcs(..) => "DTCS"
+    };
+
+    let mode = match (tx, rx) {
+        (Tone::None, Tone::None) => "",
+        (Tone::Ctcss(_), Tone::None) => "Tone",
+        (Tone::Ctcss(a), Tone::Ctcss(b)) if a == b => "TSQL",
+        (Tone::Dcs(a, _), Tone::Dcs(b, _)) if a == b => "DTCS",
+        _ => "Cross"
+    };
+    let cross = if mode == "Cross" { format!("{}->{}", kind(tx), kind(rx)) } else { String::from("Tone->Tone") };
+    [mode.to_string(), hz(tx, "88.5"), hz(rx, "88.5"), code(tx), format!("{}{}", polarity(tx), polarity(rx)), code(rx), cross]
+}
+
+/// Writes channels as CHIRP's generic CSV, which it opens for any radio and
+/// import_csv reads back
+pub fn export_csv(channels: &[Channel]) -> String {
+    let mut text = format!("{}\n", CSV_HEADER);
+    for channel in channels {
+        let (rx, tx) = (channel.rx_frequency, channel.tx_frequency);
+        let (duplex, offset) = match tx.abs_diff(rx) {
+            _ if !channel.transmits() => ("off", 0),
+            0 => ("", 0),
+            gap if gap > MAX_OFFSET => ("split", tx),
+            gap if tx > rx => ("+", gap),
+            gap => ("-", gap)
+        };
+        let mut fields = vec![channel.number.to_string(), csv_field(&channel.name), csv_frequency(rx),
+            duplex.to_string(), csv_frequency(offset)];
+        fields.extend(csv_tones(channel.tx_tone, channel.rx_tone));
+        // Mode, step and power are not decoded yet, so CHIRP's defaults stand in
+        fields.extend(["FM", "5.00", "", "", "", "", "", "", ""].map(String::from));
+        text.push_str(&fields.join(","));
+        text.push('\n')
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_quoted_fields() {
+        assert_eq!(split_csv_line(r#"1,"Club, North","Say ""hi""",,x"#), ["1", "Club, North", "Say \"hi\"", "", "x"]);
+        assert_eq!(csv_field("Club, North"), "\"Club, North\"");
+        assert_eq!(csv_field("Say \"hi\""), "\"Say \"\"hi\"\"\"");
+        assert_eq!(csv_field("Plain"), "Plain")
+    }
+
+    #[test]
+    fn imports_chirp_rows() {
+        let text = "Location,Name,Frequency,Duplex,Offset,Tone,rToneFreq,cToneFreq,DtcsCode,DtcsPolarity,RxDtcsCode,CrossMode\n\
+            1,GB3XX,145.700000,-,0.600000,Tone,88.5,88.5,023,NN,023,Tone->Tone\n\
+            2,\"Club, UHF\",430.900000,+,7.600000,TSQL,88.5,110.9,023,NN,023,Tone->Tone\n\
+            3,PMR1,446.006250,,0.000000,DTCS,88.5,88.5,023,RN,023,Tone->Tone\n\
+            4,Air,118.100000,off,0.000000,,88.5,88.5,023,NN,023,Tone->Tone\n\
+            5,Split,145.500000,split,435.000000,Cross,88.5,88.5,023,NN,754,->DTCS\n";
+        let channels = import_csv(text).unwrap();
+        assert_eq!(channels.len(), 5);
+        let frequencies: Vec<(u32, u32)> = channels.iter().map(|c| (c.rx_frequency, c.tx_frequency)).collect();
+        assert_eq!(frequencies, [(14_570_000, 14_510_000), (43_090_000, 43_850_000), (44_600_625, 44_600_625),
+            (11_810_000, channels::NO_TRANSMIT), (14_550_000, 43_500_000)]);
+        assert!(!channels[3].transmits(), "duplex off must import as receive-only");
+        assert_eq!(channels[1].name, "Club, UHF");
+        assert!(channels[0].tx_tone == Tone::Ctcss(885) && channels[0].rx_tone == Tone::None);
+        assert!(channels[1].tx_tone == Tone::Ctcss(1109) && channels[1].rx_tone == Tone::Ctcss(1109));
+        assert!(channels[2].tx_tone == Tone::Dcs(0o23, true) && channels[2].rx_tone == Tone::Dcs(0o23, false));
+        assert!(channels[4].tx_tone == Tone::None && channels[4].rx_tone == Tone::Dcs(0o754, false))
+    }
+
+    #[test]
+    fn reports_every_bad_row() {
+        let text = "Frequency,Duplex,Offset,Tone,rToneFreq\n\
+            145.500000,+,42949.600000,,\n\
+            145.501000,,,,\n\
+            145.500000,sideways,,,\n\
+            145.500000,,,Tone,89.0\n";
+        let errors = import_csv(text).err().unwrap_or_default();
+        assert_eq!(errors.lines().count(), 4, "{}", errors);
+        assert!(errors.lines().zip(2..).all(|(e, line)| e.starts_with(&format!("Line {}", line))), "{}", errors);
+        assert!(errors.starts_with("Line 2 has an invalid offset"), "{}", errors);
+        assert!(import_csv("Name,Offset\nA,0\n").is_err());
+        assert!(import_csv("").unwrap().is_empty())
+    }
+
+    fn channel(number: usize, rx: u32, tx: u32, tx_tone: Tone, rx_tone: Tone, name: &str) -> Channel {
+        Channel { number, rx_frequency: rx, tx_frequency: tx, rx_tone, tx_tone, name: name.to_string() }
+    }
+
+    #[test]
+    fn exports_what_it_imports() {
+        let channels = [
+            channel(1, 14_570_000, 14_510_000, Tone::Ctcss(885), Tone::None, "GB3XX"),
+            channel(2, 43_090_000, 43_850_000, Tone::Dcs(0o23, false), Tone::Dcs(0o23, false), "Club, UHF"),
+            channel(3, 14_550_000, 43_500_000, Tone::None, Tone::Ctcss(1109), "Split"),
+            channel(4, 44_600_625, 44_600_625, Tone::None, Tone::None, "PMR1")
+        ];
+        let text = export_csv(&channels);
+        assert!(text.starts_with(CSV_HEADER));
+        let imported = import_csv(&text).unwrap();
+        assert_eq!(imported.len(), channels.len());
+        for (a, b) in channels.iter().zip(&imported) {
+            assert_eq!((a.rx_frequency, a.tx_frequency, &a.name), (b.rx_frequency, b.tx_frequency, &b.name));
+            assert!(a.rx_tone == b.rx_tone && a.tx_tone == b.tx_tone, "tones of {}", a.name)
+        }
+    }
+
+    #[test]
+    fn receive_only_round_trips() {
+        for tx in [0, channels::NO_TRANSMIT] {
+            let text = export_csv(&[channel(1, 11_810_000, tx, Tone::None, Tone::None, "Air")]);
+            let row = split_csv_line(text.lines().nth(1).unwrap());
+            assert_eq!((row[3].as_str(), row[4].as_str()), ("off", "0.000000"));
+            let imported = import_csv(&text).unwrap();
+            assert!(!imported[0].transmits(), "receive-only channel imported as transmitting")
+        }
+    }
+}
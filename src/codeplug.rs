@@ -0,0 +1,68 @@
+/*
+    Copyright 2024 Bricky
+    https://github.com/bricky149
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+*/
+
+// Channel file formats that channels import and export can use. A new one
+// only needs an implementation and an entry in FORMATS. Settings are left
+// out, as their layout in SPI flash is not known yet. This is a binary, so
+// formats are compiled in rather than loaded from other crates at run time.
+
+use crate::channels::Channel;
+use crate::chirp;
+
+pub trait CodeplugFormat {
+    /// Name given to --format
+    fn name(&self) -> &'static str;
+
+    /// Shown in messages, e.g. "CHIRP CSV"
+    fn description(&self) -> &'static str;
+
+    /// Reads channels, numbered 0 so they can be put wherever there is room
+    fn decode(&self, data: &[u8]) -> Result<Vec<Channel>, String>;
+
+    fn encode(&self, channels: &[Channel]) -> Vec<u8>;
+}
+
+struct ChirpCsv;
+
+impl CodeplugFormat for ChirpCsv {
+    fn name(&self) -> &'static str {
+        "chirp"
+    }
+
+    fn description(&self) -> &'static str {
+        "CHIRP CSV"
+    }
+
+    fn decode(&self, data: &[u8]) -> Result<Vec<Channel>, String> {
+        chirp::import_csv(&String::from_utf8_lossy(data))
+    }
+
+    fn encode(&self, channels: &[Channel]) -> Vec<u8> {
+        chirp::export_csv(channels).into_bytes()
+    }
+}
+
+/// Every format, the first being the default
+pub const FORMATS: [&dyn CodeplugFormat; 1] = [&ChirpCsv];
+
+pub fn find(name: &str) -> Option<&'static dyn CodeplugFormat> {
+    FORMATS.iter().find(|f| f.name() == name).copied()
+}
+
+pub fn default_format() -> &'static dyn CodeplugFormat {
+    FORMATS[0]
+}
@@ -0,0 +1,266 @@
+/*
+    Copyright 2024 Bricky
+    https://github.com/bricky149
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+*/
+
+// Defaults are read from a small subset of TOML, for example:
+//
+//   port = "/dev/ttyUSB0"
+//   baud = 115200
+//   timeout = 2000            # milliseconds
+//   backup_dir = "/home/me/rt890"
+//   verify = true             # same as --paranoid on every restore
+//   flash_size = 4            # MiB, same as --flash-size
+//   accessible = true         # same as --accessible on every run
+//   post_hook = "git -C ~/rt890 add -A && git -C ~/rt890 commit -qm backup"
+//   upload_url = "https://dav.example.org/rt890/"
+//   upload_user = "club:secret"  # or set RT890_UPLOAD_USER
+//   upload_s3_region = "eu-west-1"  # only for S3 and compatible stores
+//
+//   [radio.callsign-1]        # picked with --radio callsign-1
+//   port = "/dev/ttyUSB1"     # settings above apply unless overridden here
+
+use std::env;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+use crate::spi::SPI_FLASH_SIZES;
+
+#[derive(Clone, Default)]
+pub struct Config {
+    pub port: Option<String>,
+    pub baud_rate: Option<u32>,
+    pub timeout: Option<u64>,
+    pub backup_dir: Option<PathBuf>,
+    pub verify: bool,
+    pub flash_size: Option<usize>,
+    pub accessible: bool,
+    /// Shell commands run around dumps, flashes, restores and clones
+    pub pre_hook: Option<String>,
+    pub post_hook: Option<String>,
+    /// Where backups are copied once made, as WebDAV or S3
+    pub upload_url: Option<String>,
+    pub upload_user: Option<String>,
+    pub upload_s3_region: Option<String>,
+    /// Named profiles, each starting from the settings before its table
+    pub radios: Vec<(String, Config)>
+}
+
+pub fn path() -> Option<PathBuf> {
+    match env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) => Some(PathBuf::from(dir)),
+        None => env::var_os("HOME").map(|home| PathBuf::from(home).join(".config"))
+    }.map(|dir| dir.join("rt890-flash").join("config.toml"))
+}
+
+/// Known firmware releases added by the user, kept beside the config file
+pub fn known_firmware_path() -> Option<PathBuf> {
+    path().map(|p| p.with_file_name("known-firmware.sha256"))
+}
+
+/// Known-good images kept for repairs, one per radio, beside the config file
+pub fn golden_dir() -> Option<PathBuf> {
+    path().map(|p| p.with_file_name("golden"))
+}
+
+/// Downloaded firmware releases, kept where other caches are
+pub fn firmware_cache_dir() -> Option<PathBuf> {
+    match env::var_os("XDG_CACHE_HOME") {
+        Some(dir) => Some(PathBuf::from(dir)),
+        None => env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache"))
+    }.map(|dir| dir.join("rt890-flash").join("firmware"))
+}
+
+fn parse_string(value: &str) -> Option<(String, &str)> {
+    let inner = value.strip_prefix('"')?;
+    let end = inner.find('"')?;
+    Some((inner[..end].to_string(), &inner[end + 1..]))
+}
+
+fn parse_value(value: &str) -> Option<String> {
+    // Comments may only follow a value, not sit inside a quoted one
+    match parse_string(value) {
+        Some((s, rest)) => {
+            let rest = rest.trim();
+            (rest.is_empty() || rest.starts_with('#')).then_some(s)
+        }
+        None => {
+            let value = value.split('#').next().unwrap_or_default().trim();
+            (!value.is_empty()).then(|| value.to_string())
+        }
+    }
+}
+
+fn parse_bool(value: &str) -> Option<bool> {
+    match value {
+        "true" => Some(true),
+        "false" => Some(false),
+        _ => None
+    }
+}
+
+pub fn parse(text: &str) -> Result<Config, String> {
+    let mut config = Config::default();
+    let mut radios: Vec<(String, Config)> = Vec::new();
+
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue
+        }
+
+        let invalid = || format!("Invalid config file, line {} is not understood", i + 1);
+        if let Some(table) = line.strip_prefix('[') {
+            let name = table.split('#').next().unwrap_or_default().trim()
+                .strip_suffix(']')
+                .and_then(|t| t.trim().strip_prefix("radio."))
+                .filter(|name| !name.is_empty())
+                .ok_or_else(invalid)?;
+            radios.push((name.to_string(), config.clone()));
+            continue
+        }
+        let (key, value) = line.split_once('=').ok_or_else(invalid)?;
+        let value = parse_value(value.trim()).ok_or_else(invalid)?;
+
+        // TOML has no way back to the top level once a table starts
+        let config = radios.last_mut().map_or(&mut config, |(_, radio)| radio);
+        match key.trim() {
+            "port" => config.port = Some(value),
+            "baud" => config.baud_rate = Some(value.parse().map_err(|_| invalid())?),
+            "timeout" => config.timeout = Some(value.parse().map_err(|_| invalid())?),
+            "backup_dir" => config.backup_dir = Some(PathBuf::from(value)),
+            "verify" => config.verify = parse_bool(&value).ok_or_else(invalid)?,
+            "accessible" => config.accessible = parse_bool(&value).ok_or_else(invalid)?,
+            "pre_hook" => config.pre_hook = Some(value),
+            "post_hook" => config.post_hook = Some(value),
+            "upload_url" => config.upload_url = Some(value),
+            "upload_user" => config.upload_user = Some(value),
+            "upload_s3_region" => config.upload_s3_region = Some(value),
+            "flash_size" => {
+                let size = value.parse::<usize>().map_err(|_| invalid())? * 1024 * 1024;
+                if !SPI_FLASH_SIZES.contains(&size) {
+                    return Err(invalid())
+                }
+                config.flash_size = Some(size)
+            }
+            other => return Err(format!("Invalid config file, unknown setting {} on line {}", other, i + 1))
+        }
+    }
+    config.radios = radios;
+    Ok(config)
+}
+
+fn read_file() -> Result<Config, String> {
+    let path = match path() {
+        Some(p) => p,
+        None => return Ok(Config::default())
+    };
+    match fs::read_to_string(&path) {
+        Ok(text) => parse(&text),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Config::default()),
+        Err(e) => Err(format!("Failed to read {}: {}", path.display(), e))
+    }
+}
+
+// Empty variables are treated as unset so they can be cleared in scripts
+fn var(name: &str) -> Option<String> {
+    env::var(name).ok().filter(|v| !v.is_empty())
+}
+
+fn parse_var<T>(name: &str, parse: impl Fn(&str) -> Option<T>) -> Result<Option<T>, String> {
+    match var(name) {
+        Some(v) => parse(&v).map(Some).ok_or(format!("Invalid value for {}: {}", name, v)),
+        None => Ok(None)
+    }
+}
+
+fn apply_env(config: &mut Config) -> Result<(), String> {
+    if let Some(port) = var("RT890_PORT") {
+        config.port = Some(port)
+    }
+    if let Some(baud_rate) = parse_var("RT890_BAUD", |v| v.parse().ok())? {
+        config.baud_rate = Some(baud_rate)
+    }
+    if let Some(timeout) = parse_var("RT890_TIMEOUT", |v| v.parse().ok())? {
+        config.timeout = Some(timeout)
+    }
+    if let Some(dir) = var("RT890_BACKUP_DIR") {
+        config.backup_dir = Some(PathBuf::from(dir))
+    }
+    if let Some(verify) = parse_var("RT890_VERIFY", parse_bool)? {
+        config.verify = verify
+    }
+    if let Some(user) = var("RT890_UPLOAD_USER") {
+        config.upload_user = Some(user)
+    }
+    Ok(())
+}
+
+/// Reads the user's defaults from the config file, or those of the named
+/// radio's profile, then lets RT890_* environment variables override them
+pub fn load(radio: Option<&str>) -> Result<Config, String> {
+    let mut config = read_file()?;
+    if let Some(name) = radio {
+        config = match config.radios.iter().find(|(n, _)| n == name) {
+            Some((_, profile)) => profile.clone(),
+            None => return Err(format!("There is no [radio.{}] profile in the config file", name))
+        }
+    }
+    apply_env(&mut config)?;
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_the_documented_example() {
+        // The example at the top of this file, without its comment markers
+        let text: String = include_str!("config.rs").lines()
+            .skip_while(|l| !l.starts_with("//   port"))
+            .take_while(|l| l.starts_with("//"))
+            .map(|l| format!("{}\n", l.trim_start_matches('/')))
+            .collect();
+        let config = parse(&text).unwrap();
+        assert_eq!(config.port.as_deref(), Some("/dev/ttyUSB0"));
+        assert_eq!(config.baud_rate, Some(115_200));
+        assert_eq!(config.timeout, Some(2000));
+        assert_eq!(config.backup_dir, Some(PathBuf::from("/home/me/rt890")));
+        assert!(config.verify && config.accessible);
+        assert_eq!(config.flash_size, Some(4 * 1024 * 1024));
+        assert_eq!(config.post_hook.as_deref(), Some("git -C ~/rt890 add -A && git -C ~/rt890 commit -qm backup"));
+        assert_eq!(config.upload_url.as_deref(), Some("https://dav.example.org/rt890/"));
+        assert_eq!(config.upload_user.as_deref(), Some("club:secret"));
+        assert_eq!(config.upload_s3_region.as_deref(), Some("eu-west-1"));
+
+        // Radio tables start from the settings above them
+        assert_eq!(config.radios.len(), 1);
+        let (name, radio) = &config.radios[0];
+        assert_eq!(name, "callsign-1");
+        assert_eq!(radio.port.as_deref(), Some("/dev/ttyUSB1"));
+        assert_eq!(radio.baud_rate, Some(115_200))
+    }
+
+    #[test]
+    fn refuses_invalid_settings() {
+        for text in ["port", "baud = fast", "verify = yes", "flash_size = 2", "colour = true", "[radio.]", "[profile.a]",
+            "port = \"a\" b"] {
+            assert!(parse(text).is_err(), "{} parsed", text)
+        }
+        assert!(parse("# only a comment\n\n").is_ok())
+    }
+}
@@ -0,0 +1,264 @@
+/*
+    Copyright 2024 Bricky
+    https://github.com/bricky149
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+*/
+
+// Opening the port to a radio, whether a serial port or a tcp:// address, and
+// working out why a radio does not answer when it does not.
+
+extern crate serialport5;
+use self::serialport5::*;
+
+extern crate tracing;
+use self::tracing::{debug, info_span, warn};
+
+use std::io;
+use std::thread;
+use std::time::Duration;
+
+use crate::args::Args;
+use crate::output;
+use crate::session::Recorder;
+use crate::transport::{self, Transport};
+use crate::uart::{self, BAUD_RATE};
+
+// Ports given as e.g. tcp://raspberrypi:2000 are reached over the network
+const TCP_PREFIX: &str = "tcp://";
+
+// Shortest read timeout used over Bluetooth serial links
+const BLUETOOTH_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub fn open_port(port: &String, baud_rate: u32, timeout: Duration) -> Box<dyn Transport> {
+    match try_open_port(port, baud_rate, timeout) {
+        Ok(t) => t,
+        Err(e) => match port.strip_prefix(TCP_PREFIX) {
+            Some(address) => panic!("Failed to connect to {}: {}", address, e),
+            None => panic!("Failed to open port: {}", e)
+        }
+    }
+}
+
+fn try_open_port(port: &String, baud_rate: u32, timeout: Duration) -> io::Result<Box<dyn Transport>> {
+    let _span = info_span!("open_port", port = %port, baud_rate, timeout_ms = timeout.as_millis() as u64).entered();
+    let opened = open_transport(port, baud_rate, timeout);
+    match &opened {
+        Ok(_) => debug!("opened"),
+        Err(e) => warn!(error = %e, "failed to open")
+    }
+    opened
+}
+
+fn open_transport(port: &String, baud_rate: u32, timeout: Duration) -> io::Result<Box<dyn Transport>> {
+    if let Some(address) = port.strip_prefix(TCP_PREFIX) {
+        return Ok(Box::new(transport::connect(address, timeout)?))
+    }
+
+    let callout = uart::callout_port(port);
+    if let Some(cu) = &callout {
+        output::info(&format!("Using {} instead of {}, which would wait for a carrier the cable never gives", cu, port))
+    }
+    let port = callout.as_ref().unwrap_or(port);
+
+    // Bluetooth links deliver frames late and in pieces, which would otherwise
+    // look like a radio that stopped answering
+    let timeout = if uart::is_bluetooth(port) { timeout.max(BLUETOOTH_TIMEOUT) } else { timeout };
+
+    let serial = SerialPort::builder()
+        .baud_rate(baud_rate)
+        .read_timeout(Some(timeout))
+        .open(port)
+        .map_err(|e| port_in_use(port, e.into(), io::ErrorKind::ResourceBusy))?;
+
+    // Anything else still attached, e.g. the CPS or a terminal program, would
+    // read replies meant for this tool and corrupt the transfer
+    let holders = transport::holders(port);
+    if let Err(e) = transport::lock(&serial) {
+        return Err(port_in_use(port, e, io::ErrorKind::WouldBlock))
+    }
+    if !holders.is_empty() {
+        output::warn(&format!("{} is also open in {}, which may disturb the transfer. Close it first.", port, holders.join(", ")))
+    }
+    Ok(Box::new(serial))
+}
+
+// A port held open exclusively fails with EBUSY and one locked fails with
+// EWOULDBLOCK. Either way, name whoever has it rather than the bare error,
+// and point users without access to the port at doctor.
+fn port_in_use(port: &str, e: io::Error, busy: io::ErrorKind) -> io::Error {
+    if e.kind() == io::ErrorKind::PermissionDenied {
+        return io::Error::other(format!("{} cannot be opened by this user. Run \"rt890-flash doctor\" to see how to get access", port))
+    }
+    if e.kind() != busy {
+        return e
+    }
+    let holders = transport::holders(port);
+    match holders.is_empty() {
+        true => io::Error::other(format!("{} is in use by another program. Close it, or run \"rt890-flash doctor\" to look for services that grab cables", port)),
+        false => io::Error::other(format!("{} is in use by {}. Close it and try again", port, holders.join(", ")))
+    }
+}
+
+fn answers_read(port: &mut dyn Transport) -> bool {
+    matches!(uart::command_readspiflash(port, 0), Ok(Some(_)))
+}
+
+// Stock firmware has no command to change its UART rate, so a faster one only
+// works if the radio already listens at it. Probe with a harmless read and
+// fall back to the stock rate if nothing sensible comes back.
+pub fn open_normal_mode_port(port: &String, baud_rate: u32, timeout: Duration) -> Box<dyn Transport> {
+    let mut serial = open_port(port, baud_rate, timeout);
+    if answers_read(serial.as_mut()) {
+        return serial
+    }
+    drop(serial);
+
+    // The rate of a remote port is set at the other end
+    if baud_rate != BAUD_RATE && !port.starts_with(TCP_PREFIX) {
+        output::warn(&format!("Radio did not respond at {} baud, using {} baud instead", baud_rate, BAUD_RATE));
+        let mut serial = open_port(port, BAUD_RATE, timeout);
+        if answers_read(serial.as_mut()) {
+            return serial
+        }
+    }
+
+    match diagnose(port, timeout) {
+        Diagnosis::Answers(serial, how) => {
+            output::warn(&how);
+            serial
+        }
+        Diagnosis::CannotOpen(e) => panic!("{} cannot be opened again ({}). Check the cable is plugged in \
+            and the port is listed by -l.", port, e),
+        Diagnosis::Silent => panic!("The radio does not answer in normal mode. Check it is switched on normally \
+            rather than in bootloader mode, the plug is pushed fully into the radio and the port belongs to \
+            the programming cable.")
+    }
+}
+
+pub enum Diagnosis {
+    /// The radio answers a normal mode read on this port after all, and how
+    Answers(Box<dyn Transport>, String),
+    CannotOpen(io::Error),
+    Silent
+}
+
+// Works out the likeliest reason the radio did not answer, so there is
+// something to try rather than a bare timeout
+pub fn diagnose(port: &String, timeout: Duration) -> Diagnosis {
+    output::info(&format!("The radio did not answer on {}, checking why", port));
+    let mut serial = match try_open_port(port, BAUD_RATE, timeout) {
+        Ok(serial) => serial,
+        Err(e) => return Diagnosis::CannotOpen(e)
+    };
+
+    // Some radios miss the first command after the port opens
+    if answers_read(serial.as_mut()) {
+        return Diagnosis::Answers(serial, String::from("The radio answered when asked again, so the connection may be unreliable"))
+    }
+
+    // Some cables power their level shifter from DTR or RTS
+    if !port.starts_with(TCP_PREFIX) {
+        for level in [true, false] {
+            if serial.set_control_lines(level).is_err() {
+                break
+            }
+            thread::sleep(Duration::from_millis(100));
+            let _ = serial.clear_input();
+            if answers_read(serial.as_mut()) {
+                let how = format!("The radio only answered with DTR and RTS {}, which this cable seems to need",
+                    if level { "raised" } else { "lowered" });
+                return Diagnosis::Answers(serial, how)
+            }
+        }
+    }
+    Diagnosis::Silent
+}
+
+// Wraps the port so all traffic is saved if --record was given
+pub fn record_session(serial: Box<dyn Transport>, record: &Option<String>) -> Box<dyn Transport> {
+    match record {
+        Some(filename) => match Recorder::new(serial, filename) {
+            Ok(r) => Box::new(r),
+            Err(e) => panic!("{}", e)
+        },
+        None => serial
+    }
+}
+
+// Opens -p in normal mode at --baud, waiting --timeout for replies if given,
+// and records the session if --record was given
+pub fn open_radio(args: &Args, timeout: Duration) -> Box<dyn Transport> {
+    let serial = open_normal_mode_port(&args.port, args.baud_rate, args.timeout.unwrap_or(timeout));
+    record_session(serial, &args.record)
+}
+
+// Opens the port again after the radio stopped answering mid-operation and
+// checks it responds, carrying on any recording rather than starting over
+pub fn reopen_port(args: &Args, timeout: Duration) -> Option<Box<dyn Transport>> {
+    let mut rates = vec![args.baud_rate, BAUD_RATE];
+    rates.dedup();
+    for baud_rate in rates {
+        let mut serial = match try_open_port(&args.port, baud_rate, timeout) {
+            Ok(serial) => serial,
+            Err(_) => continue
+        };
+        if let Ok(Some(_)) = uart::command_readspiflash(serial.as_mut(), 0) {
+            return match &args.record {
+                Some(filename) => match Recorder::append(serial, filename) {
+                    Ok(r) => Some(Box::new(r)),
+                    Err(e) => panic!("{}", e)
+                },
+                None => Some(serial)
+            }
+        }
+    }
+    None
+}
+
+// Gives cheap USB adapters time to drain their buffers between writes
+pub fn throttle(args: &Args) {
+    if let Some(delay) = args.inter_chunk_delay {
+        thread::sleep(delay)
+    }
+}
+
+pub fn describe_port(info: &SerialPortInfo) -> String {
+    let usb = match &info.port_type {
+        SerialPortType::UsbPort(usb) => usb,
+        SerialPortType::PciPort => return format!("{}\tPCI", info.port_name),
+        SerialPortType::BluetoothPort => return format!("{}\tBluetooth", info.port_name),
+        SerialPortType::Unknown => return info.port_name.clone()
+    };
+
+    let mut text = format!("{}\tUSB {:04x}:{:04x}", info.port_name, usb.vid, usb.pid);
+    for field in [&usb.manufacturer, &usb.product].into_iter().flatten() {
+        text.push(' ');
+        text.push_str(field)
+    }
+    if let Some(serial) = &usb.serial_number {
+        text.push_str(&format!(" (serial {})", serial))
+    }
+    if let Some(chip) = uart::cable_chip(usb) {
+        text.push_str(&format!(" [likely programming cable, {}]", chip))
+    }
+    text
+}
+
+// A radio in normal mode answers a read of the first chunk
+pub fn probe_radio(port: &String) -> bool {
+    match try_open_port(port, BAUD_RATE, Duration::from_secs(1)) {
+        Ok(mut serial) => matches!(uart::command_readspiflash(serial.as_mut(), 0), Ok(Some(_))),
+        Err(_) => false
+    }
+}
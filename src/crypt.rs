@@ -0,0 +1,85 @@
+/*
+    Copyright 2024 Bricky
+    https://github.com/bricky149
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+*/
+
+// Backups hold personal channels and DTMF IDs, and often end up in cloud
+// drives. Encryption is left to age, as HTTP is left to curl, so no crypto
+// is written here. age asks for the passphrase on the terminal itself.
+
+use std::fs;
+use std::io::{self, Read, Write};
+use std::process::{Command, Stdio};
+use std::thread;
+
+use crate::output;
+
+const AGE_HEADER: &[u8] = b"age-encryption.org/v1\n";
+
+/// Whether data is an age file, which every encrypted backup is
+pub fn is_encrypted(data: &[u8]) -> bool {
+    data.starts_with(AGE_HEADER)
+}
+
+// Input is fed from another thread so neither side waits on a full pipe
+fn age(args: &[&str], input: &[u8]) -> Result<Vec<u8>, String> {
+    let mut child = Command::new("age")
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("Failed to run age, which encrypted backups need: {}", e))?;
+
+    let mut stdin = child.stdin.take().unwrap();
+    let mut stdout = child.stdout.take().unwrap();
+    let output = thread::scope(|scope| {
+        // Dropping stdin once written is what tells age the input has ended
+        scope.spawn(move || stdin.write_all(input));
+        let mut output = Vec::new();
+        stdout.read_to_end(&mut output).map(|_| output)
+    }).map_err(|e| format!("Failed to talk to age: {}", e))?;
+
+    match child.wait() {
+        Ok(status) if status.success() => Ok(output),
+        _ => Err(String::from("age failed, see above"))
+    }
+}
+
+/// Encrypts data with a passphrase
+pub fn encrypt(data: &[u8]) -> Result<Vec<u8>, String> {
+    age(&["--passphrase"], data)
+}
+
+/// Decrypts an age file, asking for its passphrase
+pub fn decrypt(data: &[u8]) -> Result<Vec<u8>, String> {
+    age(&["--decrypt"], data)
+}
+
+// Encrypted backups are decrypted as they are read, so every command takes them
+pub fn read_input(filename: &String) -> io::Result<Vec<u8>> {
+    let data = if filename != "-" {
+        fs::read(filename)?
+    } else {
+        let mut data = Vec::new();
+        io::stdin().lock().read_to_end(&mut data)?;
+        data
+    };
+
+    if !is_encrypted(&data) {
+        return Ok(data)
+    }
+    output::info(&format!("{} is encrypted", if filename == "-" { "Input" } else { filename }));
+    decrypt(&data).map_err(io::Error::other)
+}
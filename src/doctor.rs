@@ -0,0 +1,164 @@
+/*
+    Copyright 2024 Bricky
+    https://github.com/bricky149
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+*/
+
+// Most first-time problems are the system rather than the radio: a port
+// nobody may open, a service that grabs new serial devices, or a cable
+// whose driver is not loaded. None of these checks touch a port.
+
+extern crate nix;
+use nix::unistd::{self, AccessFlags, Group};
+
+extern crate serialport5;
+use self::serialport5::*;
+
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+use crate::connect::describe_port;
+use crate::output;
+use crate::transport;
+use crate::uart;
+
+pub struct Finding {
+    pub problem: bool,
+    pub text: String,
+    /// What to do about a problem
+    pub fix: Option<String>
+}
+
+fn ok(text: String) -> Finding {
+    Finding { problem: false, text, fix: None }
+}
+
+fn problem(text: String, fix: &str) -> Finding {
+    Finding { problem: true, text, fix: Some(fix.to_string()) }
+}
+
+// Services known to open serial devices as they appear. brltty claims CH340
+// cables as braille displays, ModemManager probes them with AT commands.
+const CONFLICTS: [(&str, &str); 2] = [
+    ("ModemManager", "Stop it with \"sudo systemctl stop ModemManager\", or disable it if no modem is used"),
+    ("brltty", "Unless a braille display is used, remove it, e.g. \"sudo apt remove brltty\", then replug the cable")
+];
+
+fn running(name: &str) -> bool {
+    fs::read_dir("/proc").is_ok_and(|processes| processes.filter_map(|e| e.ok()).any(|e| {
+        fs::read_to_string(e.path().join("comm")).is_ok_and(|comm| comm.trim() == name)
+    }))
+}
+
+// Cables plugged in, read from sysfs, so one without a driver still shows up
+fn usb_cables() -> Vec<(String, &'static str)> {
+    let read = |dir: &Path, name: &str| fs::read_to_string(dir.join(name)).ok()
+        .and_then(|id| u16::from_str_radix(id.trim(), 16).ok());
+    let devices = match fs::read_dir("/sys/bus/usb/devices") {
+        Ok(d) => d,
+        Err(_) => return Vec::new()
+    };
+    devices.filter_map(|e| e.ok()).filter_map(|e| {
+        let dir = e.path();
+        let chip = uart::known_cable(read(&dir, "idVendor")?, read(&dir, "idProduct")?)?;
+        Some((e.file_name().to_string_lossy().into_owned(), chip))
+    }).collect()
+}
+
+fn port_access(port: &str) -> Finding {
+    let path = Path::new(port);
+    if unistd::access(path, AccessFlags::R_OK | AccessFlags::W_OK).is_ok() {
+        return ok(format!("{} can be opened by this user", port))
+    }
+    let group = fs::metadata(path).ok()
+        .and_then(|m| Group::from_gid(unistd::Gid::from_raw(m.gid())).ok().flatten())
+        .map(|g| g.name);
+    match group {
+        Some(group) => {
+            let member = unistd::getgroups().unwrap_or_default().iter()
+                .any(|&gid| Group::from_gid(gid).ok().flatten().is_some_and(|g| g.name == group));
+            let fix = if member {
+                // Group changes only apply to new logins
+                "You are in the group already, so log out and back in for it to apply"
+            } else {
+                "Add yourself to the group with \"sudo usermod -aG GROUP $USER\", then log out and back in"
+            };
+            problem(format!("{} belongs to group {}, which this user cannot use yet", port, group), &fix.replace("GROUP", &group))
+        }
+        None => problem(format!("{} cannot be opened by this user", port), "Run rt890-flash with sudo")
+    }
+}
+
+/// Checks the system for what commonly stops a radio being reached
+pub fn check(ports: &[SerialPortInfo]) -> Vec<Finding> {
+    let mut findings = Vec::new();
+
+    for (name, fix) in CONFLICTS {
+        if running(name) {
+            findings.push(problem(format!("{} is running and may grab the cable as it is plugged in", name), fix))
+        }
+    }
+
+    let usb: Vec<&SerialPortInfo> = ports.iter().filter(|p| matches!(p.port_type, SerialPortType::UsbPort(_))).collect();
+    let cables = usb.iter().filter(|p| match &p.port_type {
+        SerialPortType::UsbPort(info) => uart::cable_chip(info).is_some(),
+        _ => false
+    }).count();
+    // A cable with no port has no driver bound, or had it taken by brltty
+    let plugged = usb_cables();
+    if plugged.len() > cables {
+        for (device, chip) in plugged {
+            findings.push(problem(format!("A {} cable is plugged in at USB {}, but not every cable has a serial port", chip, device),
+                "Check \"sudo dmesg | tail\" after replugging it, and that the kernel has its driver, e.g. \"sudo modprobe ch341\" for a CH340"))
+        }
+    }
+    if ports.is_empty() {
+        findings.push(problem(String::from("No serial ports found"),
+            "Plug the cable in firmly at both ends; if it is still not listed, its driver may be missing"))
+    }
+
+    for port in usb {
+        findings.push(port_access(&port.port_name));
+        let holders = transport::holders(&port.port_name);
+        if !holders.is_empty() {
+            findings.push(problem(format!("{} is open in {}", port.port_name, holders.join(", ")),
+                "Close those programs, as the radio only answers one at a time"))
+        }
+    }
+    findings
+}
+
+// Problems are listed with what to do about them, and fail the command so
+// scripts can tell
+pub fn run() -> bool {
+    let ports = uart::get_available_ports();
+    println!("Ports available:");
+    for p in &ports {
+        println!("\t{}", describe_port(p))
+    }
+    println!();
+
+    let findings = check(&ports);
+    for finding in &findings {
+        match &finding.fix {
+            Some(fix) => {
+                output::warn(&finding.text);
+                output::info(&format!("  Fix: {}", fix))
+            }
+            None => output::success(&finding.text)
+        }
+    }
+    !findings.iter().any(|f| f.problem)
+}
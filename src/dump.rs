@@ -0,0 +1,339 @@
+/*
+    Copyright 2024 Bricky
+    https://github.com/bricky149
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+*/
+
+// Reading SPI flash from a radio into a file, whole or as an incremental
+// backup of the regions that changed, and the manifests kept beside dumps.
+
+extern crate serialport5;
+use self::serialport5::*;
+
+extern crate tracing;
+use self::tracing::info_span;
+
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+use crate::args::Args;
+use crate::backup::timestamp;
+use crate::connect::open_radio;
+use crate::crypt;
+use crate::digest;
+use crate::hex;
+use crate::interrupt;
+use crate::output;
+use crate::spi::{self, CALIBRATION, CHANNELS, SPI_FLASH_SIZE, SPI_FLASH_SIZES, SPI_RANGES};
+use crate::transport::Transport;
+use crate::uart::{self, CHUNK_LENGTH};
+
+// Reads of a block that may disagree before --verify-dump gives up
+const VERIFY_READS: usize = 4;
+// Long enough for a reply already in flight to arrive before it is discarded
+const PIPELINE_SETTLE: Duration = Duration::from_millis(100);
+
+// Names the dump an incremental backup builds on
+pub const BASE_MANIFEST: &str = "base.sha256";
+
+pub fn region_name(offset: usize) -> Option<&'static str> {
+    spi::range_at(offset).map(|r| r.name)
+}
+
+// Chips ignore address bits beyond their size, so a larger one is only
+// assumed when a block past 4 MiB differs from the one it would wrap to
+pub fn detect_flash_size(port: &mut dyn Transport) -> usize {
+    let beyond = (SPI_FLASH_SIZE / CHUNK_LENGTH) as u16;
+    // Erased blocks match anyway, so look at more than one
+    for block in 0..4 {
+        let low = uart::command_readspiflash(port, block);
+        let high = uart::command_readspiflash(port, beyond + block);
+        match (low, high) {
+            (Ok(Some(low)), Ok(Some(high))) if low != high => {
+                output::info("Detected 8 MiB SPI flash");
+                return SPI_FLASH_SIZES[1]
+            }
+            (Ok(Some(_)), Ok(Some(_))) => (),
+            // Firmware that refuses reads past 4 MiB may leave a reply behind
+            _ => {
+                let _ = port.clear_input();
+                break
+            }
+        }
+    }
+    SPI_FLASH_SIZE
+}
+
+// Fills in the placeholders that do not need the radio
+fn fill_template(template: &str, channels_only: bool) -> String {
+    template.replace("{model}", "rt890")
+        .replace("{date}", &timestamp())
+        .replace("{region}", if channels_only { "channels" } else { "full" })
+}
+
+// The radio has no serial number to read, but its calibration is unique to
+// it and never changes
+pub fn radio_id(calibration: &[u8]) -> String {
+    format!("{:08x}", digest::crc32(calibration))
+}
+
+pub fn dump_spi_flash(args: &Args) {
+    let _span = info_span!("dump", port = %args.port, file = %args.files[0]).entered();
+    let mut filename = fill_template(&args.files[0], args.channels_only);
+    // Checked before touching the radio, as the old file may be the only good backup
+    let exists = |e: &io::Error| e.kind() == io::ErrorKind::AlreadyExists;
+    if filename != "-" && !args.force && !filename.contains("{serial}") && Path::new(&filename).exists() {
+        panic!("{} already exists. Specify --force to overwrite it or choose another name.", filename)
+    }
+
+    let mut port = open_radio(args, Duration::from_secs(2));
+
+    if filename.contains("{serial}") {
+        let calibration = read_spi(port.as_mut(), CALIBRATION.offset, CALIBRATION.size);
+        filename = filename.replace("{serial}", &radio_id(&calibration))
+    }
+    if filename != args.files[0] {
+        output::info(&format!("Dumping to {}", filename))
+    }
+    let filename = &filename;
+
+    // Status output goes to stderr so it never ends up in a piped dump
+    let out: Box<dyn Write> = if filename == "-" {
+        Box::new(io::stdout().lock())
+    } else {
+        let file = match args.force {
+            true => File::create(filename),
+            false => File::options().write(true).create_new(true).open(filename)
+        };
+        match file {
+            Ok(f) => Box::new(f),
+            Err(e) if exists(&e) => panic!("{} already exists. Specify --force to overwrite it.", filename),
+            Err(e) => panic!("{}", e)
+        }
+    };
+    // Encrypted dumps are kept in memory until the end so no plaintext is written
+    let mut plain = Vec::new();
+    let (mut fw, out): (Box<dyn Write + '_>, _) = match args.encrypt {
+        true => (Box::new(&mut plain), Some(out)),
+        false => (out, None)
+    };
+
+    let (start, size) = if args.channels_only {
+        (CHANNELS.offset, CHANNELS.size)
+    } else {
+        (0, args.flash_size.unwrap_or_else(|| detect_flash_size(port.as_mut())))
+    };
+
+    output::start("dump", size, region_name);
+
+    // Reads are addressed by chunk rather than by byte
+    let first_block = start / CHUNK_LENGTH;
+    let last_block = (start + size) / CHUNK_LENGTH;
+    let mut in_flight = false;
+    let intel_hex = args.format.as_deref() == Some("ihex");
+    let mut segment = None;
+
+    for block in first_block..last_block {
+        let address = block * CHUNK_LENGTH;
+
+        if interrupt::interrupted() {
+            fw.flush().expect("Failed to dump SPI flash");
+            drop(fw);
+            match out {
+                Some(_) => output::warn(&format!("Dump interrupted, nothing saved to {} as it was to be encrypted", filename)),
+                None => output::warn(&format!("Dump interrupted, {} bytes saved to {}", address - start, filename))
+            }
+            interrupt::stop(interrupt::EXIT_CODE)
+        }
+
+        let next = (block + 1 < last_block).then_some(block as u16 + 1);
+        let result = match args.pipeline {
+            true => read_pipelined(port.as_mut(), block as u16, next, &mut in_flight),
+            false => uart::command_readspiflash(port.as_mut(), block as u16)
+        };
+        match result {
+            Ok(Some(data)) => {
+                let data = if args.verify_dump { read_agreed(port.as_mut(), block as u16, data) } else { data };
+                output::progress("Dumping SPI flash from", address, CHUNK_LENGTH);
+                // Addresses are written out so the file loads at the right place
+                let data = if intel_hex { hex::intel_hex(&data, address, &mut segment).into_bytes() } else { data };
+                fw.write_all(&data).expect("Failed to dump SPI flash")
+            }
+            Ok(None) => panic!("Failed to read SPI flash at address {:#08x}", address),
+            Err(e) => panic!("{}. Is the radio in normal mode?", e)
+        }
+    }
+
+    if intel_hex {
+        fw.write_all(hex::INTEL_HEX_END.as_bytes()).expect("Failed to dump SPI flash")
+    }
+    fw.flush().expect("Failed to dump SPI flash");
+    drop(fw);
+
+    if let Some(mut out) = out {
+        output::info("Encrypting the dump");
+        let data = crypt::encrypt(&plain).unwrap_or_else(|e| panic!("{}, so {} holds no dump", e, filename));
+        out.write_all(&data).expect("Failed to dump SPI flash")
+    }
+
+    // Manifests hash what was read, so they still check an encrypted dump once decrypted
+    if filename != "-" {
+        let data = match args.encrypt {
+            true => plain,
+            false => fs::read(filename).expect("Failed to read back SPI flash dump")
+        };
+        write_manifest(filename, &data)
+    }
+}
+
+// A checksum is a single byte, so a corrupted block passes one time in 256.
+// Reading it again until two reads agree rules that out.
+fn read_agreed(port: &mut dyn Transport, block: u16, first: Vec<u8>) -> Vec<u8> {
+    let address = block as usize * CHUNK_LENGTH;
+    let mut last = first;
+    for _ in 0..VERIFY_READS {
+        match uart::command_readspiflash(port, block) {
+            Ok(Some(data)) if data == last => return data,
+            Ok(Some(data)) => {
+                output::warn(&format!("Reads of address {:#08x} disagree, reading it again", address));
+                last = data
+            }
+            Ok(None) => panic!("Failed to read SPI flash at address {:#08x}", address),
+            Err(e) => panic!("{}. Is the radio in normal mode?", e)
+        }
+    }
+    panic!("Address {:#08x} read differently {} times running. Check the cable.", address, VERIFY_READS + 1)
+}
+
+// Asks for the next block before the current one has arrived, which hides the
+// round trip on adapters with a lot of latency. Should a reply come back out
+// of step, whatever is in flight is thrown away and the block read on its own.
+fn read_pipelined(port: &mut dyn Transport, block: u16, next: Option<u16>, in_flight: &mut bool) -> Result<Option<Vec<u8>>> {
+    if !*in_flight {
+        uart::request_readspiflash(port, block)?
+    }
+    if let Some(next) = next {
+        uart::request_readspiflash(port, next)?
+    }
+    *in_flight = next.is_some();
+
+    match uart::receive_readspiflash(port, block)? {
+        Some(data) => Ok(Some(data)),
+        None => {
+            thread::sleep(PIPELINE_SETTLE);
+            port.clear_input()?;
+            *in_flight = false;
+            uart::command_readspiflash(port, block)
+        }
+    }
+}
+
+// Only ranges that differ from the base are kept, in the same layout as split
+pub fn dump_incremental(args: &Args, base: &String) {
+    let base_data = match fs::read(base) {
+        Ok(f) => f,
+        Err(e) => panic!("{}", e)
+    };
+    if !SPI_FLASH_SIZES.contains(&base_data.len()) {
+        panic!("{} is not a 4 or 8 MiB dump", base)
+    }
+
+    // Checked before touching the radio, and assemble would take any region
+    // file left in the directory as part of this backup
+    let dir = Path::new(&args.files[0]);
+    if !args.force && fs::read_dir(dir).is_ok_and(|mut entries| entries.next().is_some()) {
+        panic!("{} is not empty. Specify --force to reuse it or choose another directory.", dir.display())
+    }
+
+    let mut port = open_radio(args, Duration::from_secs(2));
+    let spi = read_spi(port.as_mut(), 0, base_data.len());
+
+    fs::create_dir_all(dir).expect("Failed to create backup directory");
+    let base_path = fs::canonicalize(base).expect("Failed to find base dump");
+    fs::write(dir.join(BASE_MANIFEST), digest::manifest(&base_data, &base_path.to_string_lossy()))
+        .expect("Failed to write manifest");
+
+    let mut changed = 0;
+    for range in &SPI_RANGES {
+        let region = range.offset..range.offset + range.size;
+        let path = dir.join(format!("{}.bin", range.name));
+        if spi[region.clone()] != base_data[region.clone()] {
+            fs::write(&path, &spi[region]).expect("Failed to write region file");
+            output::info(&format!("{} changed", range.name));
+            changed += 1
+        } else {
+            match fs::remove_file(&path) {
+                Ok(()) => output::info(&format!("Removed {} left from an earlier run, as {} has not changed", path.display(), range.name)),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+                Err(e) => panic!("Failed to remove {}: {}", path.display(), e)
+            }
+        }
+    }
+    if spi != base_data && changed == 0 {
+        output::warn("Only areas outside every region changed, which are not kept")
+    }
+    output::info(&format!("{} of {} regions changed since {}", changed, SPI_RANGES.len(), base))
+}
+
+pub fn manifest_path(filename: &str) -> String {
+    format!("{}.sha256", filename)
+}
+
+// Backups are often kept for years, so record what they should hash to
+pub fn write_manifest(filename: &str, data: &[u8]) {
+    let name = Path::new(filename).file_name().map_or(filename.into(), |n| n.to_string_lossy());
+    fs::write(manifest_path(filename), digest::manifest(data, &name)).expect("Failed to write manifest")
+}
+
+// Dumps from before manifests existed, or piped in, are taken as they are
+pub fn check_manifest(filename: &str, data: &[u8]) {
+    let manifest = match fs::read_to_string(manifest_path(filename)) {
+        Ok(m) => m,
+        Err(_) => return
+    };
+    match digest::matches_manifest(data, &manifest) {
+        Some(true) => output::info(&format!("{} matches its manifest", filename)),
+        Some(false) => panic!("{} does not match {}, it may be corrupt or truncated. \
+            Specify --ignore-manifest to restore it anyway.", filename, manifest_path(filename)),
+        None => panic!("{} is not a SHA-256 manifest", manifest_path(filename))
+    }
+}
+
+// Reads whole chunks, so offset and size must be multiples of CHUNK_LENGTH
+pub fn read_spi(port: &mut dyn Transport, start: usize, size: usize) -> Vec<u8> {
+    let _span = info_span!("read", offset = start, size).entered();
+    let mut data = Vec::with_capacity(size);
+    output::start("read", size, region_name);
+    for offset in (start..start + size).step_by(CHUNK_LENGTH) {
+        // Nothing has been written yet, so there is nothing to resume
+        if interrupt::interrupted() {
+            output::warn("Interrupted while reading SPI flash, nothing has been saved or written");
+            interrupt::stop(interrupt::EXIT_CODE)
+        }
+
+        match uart::command_readspiflash(port, (offset / CHUNK_LENGTH) as u16) {
+            Ok(Some(chunk)) => {
+                output::progress("Reading SPI flash from", offset, CHUNK_LENGTH);
+                data.extend_from_slice(&chunk)
+            }
+            Ok(None) => panic!("Failed to read SPI flash at address {:#08x}", offset),
+            Err(e) => panic!("{}. Is the radio in normal mode?", e)
+        }
+    }
+    output::end_line();
+    data
+}
@@ -0,0 +1,274 @@
+/*
+    Copyright 2024 Bricky
+    https://github.com/bricky149
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+*/
+
+// Operations on dump files that need no radio, and inspect, which can read
+// either a dump or the radio itself.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+
+use crate::args::Args;
+use crate::channels;
+use crate::connect::open_radio;
+use crate::crypt::read_input;
+use crate::digest;
+use crate::dump::{manifest_path, read_spi, BASE_MANIFEST};
+use crate::hex;
+use crate::output;
+use crate::spi::{self, SpiRange, CALIBRATION, CHANNELS, SPI_FLASH_SIZE, SPI_FLASH_SIZES, SPI_RANGES};
+use crate::uart::CHUNK_LENGTH;
+
+// Bytes shown by inspect when no --length is given
+const INSPECT_LENGTH: usize = 256;
+
+pub fn transplant_calibration(from: &String, into: &String, out: &String) -> bool {
+    let donor = match read_input(from) {
+        Ok(f) => f,
+        Err(e) => panic!("{}", e)
+    };
+    let mut spi = match fs::read(into) {
+        Ok(f) => f,
+        Err(e) => panic!("{}", e)
+    };
+    if !SPI_FLASH_SIZES.contains(&donor.len()) || !SPI_FLASH_SIZES.contains(&spi.len()) {
+        return false
+    }
+
+    let calibration = CALIBRATION.offset..CALIBRATION.offset + CALIBRATION.size;
+    if donor[calibration.clone()].iter().all(|&b| b == 0xFF) {
+        output::warn(&format!("Calibration in {} is blank", from))
+    }
+    spi[calibration.clone()].copy_from_slice(&donor[calibration]);
+
+    fs::write(out, spi).expect("Failed to write SPI flash dump");
+    true
+}
+
+// Where the DTMF ID and welcome text are kept is not known yet, so only
+// channel names, or the regions asked for, can be blanked
+pub fn scrub_dump(args: &Args) -> bool {
+    let mut spi = match read_input(&args.files[0]) {
+        Ok(f) => f,
+        Err(e) => panic!("{}", e)
+    };
+    if !SPI_FLASH_SIZES.contains(&spi.len()) {
+        return false
+    }
+
+    let mut blank = |range: &SpiRange| spi[range.offset..range.offset + range.size].fill(0xFF);
+    if args.blank_calibration {
+        blank(&CALIBRATION)
+    }
+    if args.blank_channels {
+        blank(&CHANNELS)
+    } else {
+        for mut channel in channels::all(&spi) {
+            channel.name.clear();
+            channels::set(&mut spi, &channel)
+        }
+    }
+
+    fs::write(args.out.as_ref().unwrap(), spi).expect("Failed to write SPI flash dump");
+    true
+}
+
+pub fn split_dump(dump: &String, dir: &String) -> bool {
+    let spi = match read_input(dump) {
+        Ok(f) => f,
+        Err(e) => panic!("{}", e)
+    };
+    if !SPI_FLASH_SIZES.contains(&spi.len()) {
+        return false
+    }
+
+    fs::create_dir_all(dir).expect("Failed to create output directory");
+    for range in &SPI_RANGES {
+        let path = Path::new(dir).join(format!("{}.bin", range.name));
+        fs::write(&path, &spi[range.offset..range.offset + range.size]).expect("Failed to write region file");
+        output::info(&format!("{:#08x} {:>8} bytes  {}", range.offset, range.size, path.display()))
+    }
+    true
+}
+
+// Anything between regions cannot be restored, so it is left erased unless
+// the regions are an incremental backup, which builds on its base dump
+pub fn assemble_dump(dir: &String, out: &String) -> bool {
+    let (mut spi, base) = match fs::read_to_string(Path::new(dir).join(BASE_MANIFEST)) {
+        Ok(manifest) => {
+            let base = digest::manifest_filename(&manifest).unwrap_or_default().to_string();
+            let data = match fs::read(&base) {
+                Ok(f) => f,
+                Err(e) => panic!("Failed to read base dump {}: {}", base, e)
+            };
+            if digest::matches_manifest(&data, &manifest) != Some(true) {
+                panic!("Base dump {} has changed since the incremental backup was made", base)
+            }
+            (data, true)
+        }
+        Err(_) => (vec![0xFF; SPI_FLASH_SIZE], false)
+    };
+
+    for range in &SPI_RANGES {
+        let path = Path::new(dir).join(format!("{}.bin", range.name));
+        match fs::read(&path) {
+            Ok(data) if data.len() == range.size => spi[range.offset..range.offset + range.size].copy_from_slice(&data),
+            Ok(_) => {
+                output::error(&format!("{} is not exactly {} bytes", path.display(), range.size));
+                return false
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound && base => (),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => output::warn(&format!("{} not found, left erased", path.display())),
+            Err(e) => panic!("{}", e)
+        }
+    }
+
+    fs::write(out, spi).expect("Failed to write SPI flash dump");
+    true
+}
+
+// Labels each run of lines with the region it belongs to
+fn print_inspection(data: &[u8], base: usize) {
+    let mut region = None;
+    for (i, line) in hex::dump(data, base).iter().enumerate() {
+        let name = spi::range_at(base + i * 16).map_or("outside any region", |r| r.name);
+        if region != Some(name) {
+            println!("-- {} --", name);
+            region = Some(name)
+        }
+        println!("{}", line)
+    }
+}
+
+pub fn layout_description(known: bool) -> &'static str {
+    match known {
+        true => "channels are in the layout every known firmware uses",
+        false => "channels do not read sensibly, so the dump may come from firmware that keeps them differently"
+    }
+}
+
+pub fn inspect_file(args: &Args) {
+    let data = match read_input(&args.files[0]) {
+        Ok(f) => f,
+        Err(e) => panic!("{}", e)
+    };
+    if let Some(known) = SPI_FLASH_SIZES.contains(&data.len()).then(|| channels::known_layout(&data)).flatten() {
+        println!("-- {} --", layout_description(known))
+    }
+    let start = args.offset.unwrap_or(0);
+    if start >= data.len() {
+        panic!("Offset {:#08x} is past the end of {}", start, args.files[0])
+    }
+    let end = data.len().min(start + args.length.unwrap_or(INSPECT_LENGTH));
+    print_inspection(&data[start..end], start)
+}
+
+pub fn inspect_radio(args: &Args) {
+    let mut port = open_radio(args, Duration::from_secs(2));
+
+    let start = args.offset.unwrap_or(0);
+    let length = args.length.unwrap_or(INSPECT_LENGTH);
+    if start + length > SPI_FLASH_SIZES[1] {
+        panic!("Cannot read past {:#08x}", SPI_FLASH_SIZES[1])
+    }
+    // Reads cover whole chunks, so fetch around the range and trim
+    let first = start - start % CHUNK_LENGTH;
+    let last = (start + length).div_ceil(CHUNK_LENGTH) * CHUNK_LENGTH;
+    let data = read_spi(port.as_mut(), first, last - first);
+    print_inspection(&data[start - first..start - first + length], start)
+}
+
+pub fn print_checksums(files: &[String]) -> bool {
+    let dumps: Vec<Vec<u8>> = files.iter().map(|f| match read_input(f) {
+        Ok(data) => data,
+        Err(e) => panic!("{}", e)
+    }).collect();
+    if dumps.iter().any(|d| !SPI_FLASH_SIZES.contains(&d.len())) {
+        return false
+    }
+
+    let mut regions: Vec<(&str, usize, usize)> = SPI_RANGES.iter().map(|r| (r.name, r.offset, r.size)).collect();
+    regions.sort_by_key(|&(_, offset, _)| offset);
+    regions.push(("whole image", 0, dumps[0].len()));
+
+    println!("{:<12} {:>8} {:>8}  {:<8}  SHA-256", "Region", "Offset", "Size", "CRC-32");
+    for (name, offset, size) in regions {
+        let digests: Vec<(u32, [u8; 32])> = dumps.iter()
+            .filter_map(|d| d.get(offset..offset + size))
+            .map(|data| (digest::crc32(data), digest::sha256(data)))
+            .collect();
+        println!("{:<12} {:>#8x} {:>8}  {:08x}  {}", name, offset, size, digests[0].0, digest::to_hex(&digests[0].1));
+        if dumps.len() == 2 {
+            match digests.get(1) {
+                Some(other) if other.1 == digests[0].1 => println!("{:<31} same in {}", "", files[1]),
+                Some(other) => println!("{:<31} {:08x}  {}  differs in {}", "", other.0, digest::to_hex(&other.1), files[1]),
+                None => println!("{:<31} differs in size in {}", "", files[1])
+            }
+        }
+    }
+    true
+}
+
+// Checks everything whose layout is understood, printing one line per check
+pub fn validate_dump(filename: &String) -> bool {
+    let spi = match read_input(filename) {
+        Ok(f) => f,
+        Err(e) => panic!("{}", e)
+    };
+    let mut problems = 0;
+    let mut report = |ok: bool, text: String| {
+        println!("{:<8}{}", if ok { "ok" } else { "PROBLEM" }, text);
+        problems += !ok as usize
+    };
+
+    if !SPI_FLASH_SIZES.contains(&spi.len()) {
+        report(false, format!("size is {} bytes, not 4 or 8 MiB", spi.len()));
+        return false
+    }
+    report(true, format!("size is {} MiB", spi.len() / 1024 / 1024));
+
+    if let Ok(manifest) = fs::read_to_string(manifest_path(filename)) {
+        match digest::matches_manifest(&spi, &manifest) {
+            Some(matches) => report(matches, format!("SHA-256 against {}", manifest_path(filename))),
+            None => report(false, format!("{} is not a SHA-256 manifest", manifest_path(filename)))
+        }
+    }
+
+    let blank = |data: &[u8]| data.iter().all(|&b| b == 0xFF) || data.iter().all(|&b| b == 0);
+    for range in &SPI_RANGES {
+        let data = &spi[range.offset..range.offset + range.size];
+        // Calibration is the one region every radio has filled in at the factory
+        let ok = range.cmd != CALIBRATION.cmd || !blank(data);
+        report(ok, format!("{} is {}", range.name, if blank(data) { "blank" } else { "filled" }))
+    }
+
+    let in_use = channels::all(&spi);
+    for channel in &in_use {
+        for problem in channels::problems(channel) {
+            report(false, format!("channel {}: {}", channel.number, problem))
+        }
+    }
+    report(true, format!("{} channels in use", in_use.len()));
+    match channels::known_layout(&spi) {
+        Some(known) => report(known, String::from(layout_description(known))),
+        None => println!("{:<8}channel layout cannot be told, as no channels are in use", "skipped")
+    }
+    println!("{:<8}settings are not checked, as their layout is not known yet", "skipped");
+
+    problems == 0
+}
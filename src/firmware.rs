@@ -0,0 +1,230 @@
+/*
+    Copyright 2024 Bricky
+    https://github.com/bricky149
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+*/
+
+use std::ops::Range;
+
+use crate::digest;
+
+pub const FIRMWARE_SIZE: usize = 60_416;
+
+// The AT32F421 runs code from 64 KiB of flash and has 16 KiB of SRAM
+const FLASH: Range<u32> = 0x0800_0000..0x0801_0000;
+const SRAM: Range<u32> = 0x2000_0000..0x2000_4001;
+
+fn word(fw: &[u8], index: usize) -> u32 {
+    let start = index * 4;
+    u32::from_le_bytes([fw[start], fw[start + 1], fw[start + 2], fw[start + 3]])
+}
+
+// Handlers must be Thumb code, so addresses are odd, and live in flash
+fn is_handler(address: u32) -> bool {
+    address & 1 == 1 && FLASH.contains(&address)
+}
+
+// The same vector table test as check(), for the first 16 bytes alone
+fn vector_table_valid(head: &[u8]) -> bool {
+    let stack_pointer = word(head, 0);
+    SRAM.contains(&stack_pointer) && stack_pointer.is_multiple_of(4) && (1..4).all(|i| is_handler(word(head, i)))
+}
+
+/// Explains why a file cannot be RT-890 firmware, if it obviously isn't
+pub fn check(fw: &[u8]) -> Result<(), &'static str> {
+    if fw.len() != FIRMWARE_SIZE {
+        return Err("File is not the size of RT-890 firmware")
+    }
+
+    // CPS exports, CSVs and the like are plain text throughout
+    if fw[..1024].iter().all(|b| b.is_ascii_graphic() || b.is_ascii_whitespace()) {
+        return Err("File looks like text rather than firmware")
+    }
+
+    // Vector table: initial stack pointer, then reset, NMI and HardFault handlers
+    let stack_pointer = word(fw, 0);
+    if !SRAM.contains(&stack_pointer) || !stack_pointer.is_multiple_of(4) {
+        return Err("Initial stack pointer is not in SRAM")
+    }
+    if !(1..4).all(|i| is_handler(word(fw, i))) {
+        return Err("Reset or fault handlers do not point into MCU flash")
+    }
+
+    Ok(())
+}
+
+// Longest header looked for in front of an image in an updater file
+const MAX_HEADER: usize = 4096;
+
+/// Finds the plain image in a vendor updater file, which may put a header
+/// before it or anything after it, and may XOR every byte with one key.
+/// Returns the image and a description of what was taken off.
+pub fn unwrap(data: &[u8]) -> Option<(Vec<u8>, String)> {
+    let last_offset = data.len().checked_sub(FIRMWARE_SIZE)?.min(MAX_HEADER);
+    for offset in 0..=last_offset {
+        let window = &data[offset..offset + FIRMWARE_SIZE];
+        for key in 0..=255u8 {
+            // Only the vector table is decoded until a key fits it
+            let mut head = [0u8; 16];
+            for (h, b) in head.iter_mut().zip(window) {
+                *h = b ^ key
+            }
+            if (offset == 0 && key == 0) || !vector_table_valid(&head) {
+                continue
+            }
+            let fw: Vec<u8> = window.iter().map(|b| b ^ key).collect();
+            if check(&fw).is_err() {
+                continue
+            }
+
+            let mut parts = Vec::new();
+            if offset > 0 {
+                parts.push(format!("a {}-byte header", offset))
+            }
+            let trailer = data.len() - offset - FIRMWARE_SIZE;
+            if trailer > 0 {
+                parts.push(format!("{} bytes after the image", trailer))
+            }
+            if key != 0 {
+                parts.push(format!("XOR obfuscation with key {:#04x}", key))
+            }
+            return Some((fw, parts.join(", ")))
+        }
+    }
+    None
+}
+
+const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+/// Runs of printable ASCII at least min_length long, with their offsets
+pub fn strings(fw: &[u8], min_length: usize) -> Vec<(usize, String)> {
+    let mut found = Vec::new();
+    let mut start = 0;
+    for (i, byte) in fw.iter().chain([0u8].iter()).enumerate() {
+        if byte.is_ascii_graphic() || *byte == b' ' {
+            continue
+        }
+        if i - start >= min_length {
+            found.push((start, String::from_utf8_lossy(&fw[start..i]).into_owned()))
+        }
+        start = i + 1
+    }
+    found
+}
+
+// Versions look like 1.34 or V1.3.2
+fn parse_version(token: &str) -> Option<String> {
+    let numbers = token.trim_start_matches(['V', 'v']);
+    let parts: Vec<&str> = numbers.split('.').collect();
+    let valid = parts.len() >= 2 && parts.iter()
+        .all(|p| !p.is_empty() && p.len() <= 3 && p.bytes().all(|b| b.is_ascii_digit()));
+    if valid {
+        Some(token.to_string())
+    } else {
+        None
+    }
+}
+
+/// Splits a version into numbers for comparison, e.g. V1.3.2 into [1, 3, 2]
+pub fn version_numbers(version: &str) -> Vec<u32> {
+    version.trim_start_matches(['V', 'v'])
+        .split('.')
+        .filter_map(|p| p.parse().ok())
+        .collect()
+}
+
+/// Finds the version string embedded in a firmware image, preferring
+/// ones prefixed with V over bare numbers
+pub fn version(fw: &[u8]) -> Option<String> {
+    let versions: Vec<String> = strings(fw, 3).iter()
+        .flat_map(|(_, s)| s.split_whitespace().filter_map(parse_version).collect::<Vec<_>>())
+        .collect();
+
+    versions.iter()
+        .find(|v| v.starts_with(['V', 'v']))
+        .or(versions.first())
+        .cloned()
+}
+
+/// Finds a compiler build date, which is always formatted like "Jan  5 2024"
+pub fn build_date(fw: &[u8]) -> Option<String> {
+    for (_, s) in strings(fw, 11) {
+        let bytes = s.as_bytes();
+        for i in 0..=bytes.len() - 11 {
+            let candidate = &bytes[i..i + 11];
+            let is_date = MONTHS.iter().any(|m| candidate.starts_with(m.as_bytes()))
+                && candidate[3] == b' '
+                && (candidate[4] == b' ' || candidate[4].is_ascii_digit())
+                && candidate[5].is_ascii_digit()
+                && candidate[6] == b' '
+                && candidate[7..].iter().all(|b| b.is_ascii_digit());
+            if is_date {
+                return Some(String::from_utf8_lossy(candidate).into_owned())
+            }
+        }
+    }
+    None
+}
+
+// SHA-256 digests of vendor releases, confirmed against their downloads.
+// None has been verified yet, so only releases users add to the database
+// file with firmware trust are known for now.
+const KNOWN_RELEASES: &[(&str, &str)] = &[];
+
+/// Names the release an image is, looking in the built-in list and then in
+/// a database of sha256sum lines
+pub fn known_release(fw: &[u8], database: &str) -> Option<String> {
+    let digest = digest::to_hex(&digest::sha256(fw));
+    let built_in = KNOWN_RELEASES.iter().copied();
+    let added = database.lines().filter_map(|line| line.split_once("  "));
+    built_in.chain(added)
+        .find(|(hash, _)| hash.eq_ignore_ascii_case(&digest))
+        .map(|(_, name)| name.trim().to_string())
+}
+
+/// Strings that name the radio or firmware project
+pub fn identification(fw: &[u8]) -> Vec<(usize, String)> {
+    const NAMES: [&str; 4] = ["RT-890", "RT890", "OEFW", "RADTEL"];
+    strings(fw, 4).into_iter()
+        .filter(|(_, s)| NAMES.iter().any(|n| s.to_uppercase().contains(n)))
+        .collect()
+}
+
+// Differences closer together than this are reported as one range, as a
+// changed instruction or pointer rarely differs in every byte
+const DIFF_GAP: usize = 8;
+
+/// Byte ranges that differ between two images, including any bytes one has
+/// beyond the end of the other
+pub fn differences(a: &[u8], b: &[u8]) -> Vec<Range<usize>> {
+    let mut ranges: Vec<Range<usize>> = Vec::new();
+    for i in 0..a.len().max(b.len()) {
+        if a.get(i) == b.get(i) {
+            continue
+        }
+        match ranges.last_mut() {
+            Some(last) if i - last.end < DIFF_GAP => last.end = i + 1,
+            _ => ranges.push(i..i + 1)
+        }
+    }
+    ranges
+}
+
+/// Strings in one image that are nowhere in the other, wherever they moved to
+pub fn strings_missing_from(fw: &[u8], other: &[u8]) -> Vec<(usize, String)> {
+    let other = strings(other, 4);
+    strings(fw, 4).into_iter()
+        .filter(|(_, s)| !other.iter().any(|(_, t)| t == s))
+        .collect()
+}
@@ -0,0 +1,385 @@
+/*
+    Copyright 2024 Bricky
+    https://github.com/bricky149
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+*/
+
+// Firmware files, from a disk, an updater or a release download, and flashing
+// them through the bootloader.
+
+extern crate serialport5;
+use self::serialport5::*;
+
+extern crate tracing;
+use self::tracing::info_span;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::args::Args;
+use crate::config;
+use crate::connect::{diagnose, open_port, record_session, throttle, Diagnosis};
+use crate::crypt::read_input;
+use crate::digest;
+use crate::dump::manifest_path;
+use crate::firmware::{self, FIRMWARE_SIZE};
+use crate::interrupt;
+use crate::output::{self, confirm};
+use crate::uart::{self, BAUD_RATE, CHUNK_LENGTH};
+use crate::update;
+use crate::zip;
+
+pub fn flash_firmware(args: &Args) -> Result<bool> {
+    let source = args.url.as_ref().unwrap_or_else(|| &args.files[0]);
+    let _span = info_span!("flash", port = %args.port, file = %source).entered();
+    let timeout = args.timeout.unwrap_or(Duration::from_secs(2));
+    let serial = open_port(&args.port, BAUD_RATE, timeout);
+    let mut port = record_session(serial, &args.record);
+
+    let fw = match &args.url {
+        Some(url) => download_firmware(url, &args.sha256),
+        None => read_firmware(&args.files[0])
+    };
+    if fw.len() != FIRMWARE_SIZE {
+        return Ok(false)
+    }
+
+    if let Err(reason) = firmware::check(&fw) {
+        panic!("{}. Nothing has been erased.", reason)
+    }
+
+    // The bootloader has no command to report what it is replacing, so the
+    // best that can be done is show what is about to be written
+    let version = firmware::version(&fw).unwrap_or(String::from("an unknown version"));
+    let build_date = firmware::build_date(&fw).unwrap_or(String::from("an unknown date"));
+    output::info(&format!("File is firmware {} built {}", version, build_date));
+    match firmware::known_release(&fw, &known_firmware()) {
+        Some(name) => output::info(&format!("File matches known release {}", name)),
+        None => output::warn("File does not match any release added with firmware trust, and none are built in as none \
+            have been verified yet. Only continue if you trust where it came from.")
+    }
+
+    if let Some(installed) = &args.installed_version {
+        let file_numbers = firmware::version_numbers(&version);
+        if file_numbers.is_empty() {
+            output::warn(&format!("Cannot tell whether this is a downgrade from {}", installed))
+        } else if file_numbers < firmware::version_numbers(installed) {
+            if !args.allow_downgrade {
+                output::error(&format!("This would downgrade the radio from {}. Specify --allow-downgrade to continue.", installed));
+                interrupt::stop(1)
+            }
+            output::warn(&format!("Downgrading from {}. Check your settings after flashing.", installed))
+        }
+    }
+
+    // Everything before the resume point was acknowledged by an earlier
+    // flash, so erasing again would only throw that away
+    let mut offset = args.resume_from.unwrap_or(0);
+    if offset >= FIRMWARE_SIZE {
+        panic!("Address {:#06x} is beyond the end of the firmware", offset)
+    }
+    if offset == 0 {
+        if !args.yes && !confirm("Erase MCU flash and write this firmware?") {
+            output::info("Flash cancelled. Nothing has been erased.");
+            interrupt::stop(1)
+        }
+        match uart::command_eraseflash(port.as_mut()) {
+            Ok(true) => output::info("MCU flash erased"),
+            _ => {
+                drop(port);
+                match diagnose(&args.port, timeout) {
+                    Diagnosis::Answers(..) => panic!("Failed to erase MCU flash, as the radio is in normal mode. \
+                        Switch it off and on again in bootloader mode, then flash again."),
+                    Diagnosis::CannotOpen(e) => panic!("Failed to erase MCU flash, and {} cannot be opened again ({}). \
+                        Check the cable is plugged in and the port is listed by -l.", args.port, e),
+                    Diagnosis::Silent => panic!("Failed to erase MCU flash. Check the radio was switched on in \
+                        bootloader mode, the plug is pushed fully into the radio and the port belongs to the \
+                        programming cable.")
+                }
+            }
+        }
+    } else if !args.yes && !confirm(&format!("Write the rest of this firmware from {:#06x} without erasing?", offset)) {
+        output::info("Flash cancelled");
+        interrupt::stop(1)
+    }
+
+    output::start("flash", FIRMWARE_SIZE - offset, |_| Some("firmware"));
+
+    while offset < FIRMWARE_SIZE {
+        if interrupt::interrupted() {
+            output::warn(&format!("Flash interrupted before address {:#06x}. The radio is still in bootloader mode.", offset));
+            output::info(&format!("Keep it in bootloader mode and run the same command with --resume-from {:#06x}", offset));
+            interrupt::stop(interrupt::EXIT_CODE)
+        }
+
+        match uart::command_writeflash(port.as_mut(), offset, &fw) {
+            Ok(true) => {
+                output::progress("Flashing firmware to", offset, CHUNK_LENGTH);
+                throttle(args)
+            }
+            _ => panic!("Failed to write firmware to MCU flash at address {:#06x}. Keep the radio in bootloader mode \
+                and run the same command with --resume-from {:#06x} to carry on", offset, offset)
+        }
+        offset += CHUNK_LENGTH
+    }
+
+    Ok(true)
+}
+
+// Releases often hold a readme, checksums or builds for other radios too, so
+// only take a file that looks like firmware, and only if there is one
+fn firmware_from_zip(filename: &String, data: &[u8]) -> Vec<u8> {
+    let entries = zip::entries(data).unwrap_or_else(|e| panic!("{}: {}", filename, e));
+    let mut found: Vec<(&str, Vec<u8>)> = Vec::new();
+    // Updater files are a little larger than the image they wrap
+    for entry in entries.iter().filter(|e| (FIRMWARE_SIZE..=FIRMWARE_SIZE * 2).contains(&e.size)) {
+        let fw = zip::extract(data, entry).unwrap_or_else(|e| panic!("{}: {}", filename, e));
+        // The same build is sometimes included twice under different names
+        let plausible = firmware::check(&fw).is_ok() || firmware::unwrap(&fw).is_some();
+        if plausible && !found.iter().any(|(_, f)| *f == fw) {
+            found.push((&entry.name, fw))
+        }
+    }
+
+    match found.len() {
+        0 => panic!("{} has no file in it that looks like RT-890 firmware", filename),
+        1 => {
+            let (name, fw) = found.remove(0);
+            output::info(&format!("Using {} from {}", name, filename));
+            fw
+        }
+        _ => {
+            let names: Vec<&str> = found.iter().map(|(name, _)| *name).collect();
+            panic!("{} holds several firmware images: {}. Extract the one to flash and give it instead.",
+                filename, names.join(", "))
+        }
+    }
+}
+
+fn read_firmware(filename: &String) -> Vec<u8> {
+    match read_input(filename) {
+        Ok(data) => open_firmware(filename, data),
+        Err(e) => panic!("{}", e)
+    }
+}
+
+// Checked against the hash given, or the manifest published beside the file
+// if there is one, before anything else is done with it
+fn download_firmware(url: &String, sha256: &Option<String>) -> Vec<u8> {
+    output::info(&format!("Downloading {}", url));
+    let data = update::fetch(url).unwrap_or_else(|e| panic!("{}", e));
+    let digest = digest::to_hex(&digest::sha256(&data));
+    match sha256 {
+        Some(expected) if !expected.eq_ignore_ascii_case(&digest) =>
+            panic!("Download does not match the SHA-256 given. Nothing has been erased."),
+        Some(_) => output::info("Download matches the SHA-256 given"),
+        None => match update::fetch(&manifest_path(url)) {
+            Ok(manifest) => match digest::matches_manifest(&data, &String::from_utf8_lossy(&manifest)) {
+                Some(true) => output::info(&format!("Download matches {}", manifest_path(url))),
+                _ => panic!("Download does not match {}. Nothing has been erased.", manifest_path(url))
+            },
+            Err(_) => output::warn("No SHA-256 was given or published for this download, so it is only checked against known releases")
+        }
+    }
+    open_firmware(url, data)
+}
+
+// Updater files may wrap the image, so take it out and say how
+fn open_firmware(filename: &String, data: Vec<u8>) -> Vec<u8> {
+    let data = if zip::is_zip(&data) { firmware_from_zip(filename, &data) } else { data };
+    if firmware::check(&data).is_ok() {
+        return data
+    }
+    match firmware::unwrap(&data) {
+        Some((fw, wrapping)) => {
+            output::info(&format!("{} is an updater file with {}, using the firmware inside", filename, wrapping));
+            fw
+        }
+        None => data
+    }
+}
+
+pub fn show_firmware_info(filename: &String) {
+    let fw = read_firmware(filename);
+
+    println!("Size: {} bytes", fw.len());
+    match firmware::check(&fw) {
+        Ok(()) => println!("Looks like RT-890 firmware"),
+        Err(reason) => println!("Does not look like RT-890 firmware: {}", reason)
+    }
+    println!("Version: {}", firmware::version(&fw).unwrap_or(String::from("unknown")));
+    println!("Build date: {}", firmware::build_date(&fw).unwrap_or(String::from("unknown")));
+    match firmware::known_release(&fw, &known_firmware()) {
+        Some(name) => println!("Known release: {}", name),
+        None => println!("Known release: none, this image is not in the database")
+    }
+    for (offset, s) in firmware::identification(&fw) {
+        println!("{:#06x}: {}", offset, s)
+    }
+}
+
+// A missing database just means nothing has been added to it yet
+fn known_firmware() -> String {
+    config::known_firmware_path()
+        .and_then(|path| fs::read_to_string(path).ok())
+        .unwrap_or_default()
+}
+
+// Named after its version where one can be found, as file names get reused
+pub fn trust_firmware(filename: &String) {
+    let fw = read_firmware(filename);
+    if let Err(reason) = firmware::check(&fw) {
+        panic!("{}", reason)
+    }
+    if let Some(name) = firmware::known_release(&fw, &known_firmware()) {
+        output::info(&format!("{} is already known as {}", filename, name));
+        return
+    }
+
+    let name = firmware::version(&fw).unwrap_or_else(|| {
+        Path::new(filename).file_name().map_or(filename.clone(), |n| n.to_string_lossy().into_owned())
+    });
+    let path = config::known_firmware_path().expect("Cannot find the config directory, set HOME");
+    let mut database = known_firmware();
+    database.push_str(&digest::manifest(&fw, &name));
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).expect("Failed to create config directory")
+    }
+    fs::write(&path, database).expect("Failed to write known firmware database");
+    output::success(&format!("{} added to {} as {}", filename, path.display(), name))
+}
+
+// Files already downloaded are kept per release, under their own names
+fn cached_asset(tag: &str, asset: &update::Asset) -> Option<PathBuf> {
+    config::firmware_cache_dir().map(|dir| dir.join(tag).join(&asset.name))
+}
+
+// Manifests are published as assets too, but are not worth listing
+fn firmware_assets(release: &update::FirmwareRelease) -> impl Iterator<Item = &update::Asset> {
+    release.assets.iter().filter(|a| !a.name.ends_with(".sha256"))
+}
+
+pub fn list_firmware_releases() {
+    let releases = update::firmware_releases().unwrap_or_else(|e| panic!("{}", e));
+    if releases.is_empty() {
+        output::info("No firmware releases found");
+        return
+    }
+    for release in &releases {
+        let name = if release.name.is_empty() || release.name == release.tag { String::new() } else { format!(" {}", release.name) };
+        println!("{}{} ({})", release.tag, name, release.date);
+        for asset in firmware_assets(release) {
+            let cached = cached_asset(&release.tag, asset).is_some_and(|p| p.exists());
+            println!("    {} {} bytes{}", asset.name, asset.size, if cached { ", downloaded" } else { "" });
+            let manifest = format!("{}.sha256", asset.name);
+            match &asset.sha256 {
+                Some(digest) => println!("        sha256 {}", digest),
+                None if release.assets.iter().any(|a| a.name == manifest) => println!("        sha256 in {}", manifest),
+                None => println!("        sha256 not published")
+            }
+        }
+        // Only the start of the notes, as some run to pages
+        let notes: Vec<&str> = release.notes.lines().map(str::trim).filter(|l| !l.is_empty()).collect();
+        for line in notes.iter().take(4) {
+            println!("    | {}", line)
+        }
+        if notes.len() > 4 {
+            println!("    | ...")
+        }
+        println!()
+    }
+    output::info("Download one with: rt890-flash firmware releases TAG")
+}
+
+// Checked against the digest GitHub keeps or a manifest published beside
+// it, as -f --url does
+pub fn fetch_firmware_release(tag: &str) {
+    let releases = update::firmware_releases().unwrap_or_else(|e| panic!("{}", e));
+    let release = releases.iter().find(|r| r.tag == tag)
+        .unwrap_or_else(|| panic!("No firmware release is tagged {}, see firmware releases", tag));
+    let manifests: Vec<&update::Asset> = release.assets.iter().filter(|a| a.name.ends_with(".sha256")).collect();
+    let mut fetched = Vec::new();
+
+    for asset in firmware_assets(release) {
+        let path = cached_asset(tag, asset).expect("Cannot find the cache directory, set HOME");
+        let expected = match &asset.sha256 {
+            Some(digest) => Some(digest.clone()),
+            None => match manifests.iter().find(|m| m.name == format!("{}.sha256", asset.name)) {
+                Some(manifest) => {
+                    let manifest = update::fetch(&manifest.url).unwrap_or_else(|e| panic!("{}", e));
+                    String::from_utf8_lossy(&manifest).split_whitespace().next().map(str::to_string)
+                }
+                None => None
+            }
+        };
+        let matches = |data: &[u8]| expected.as_ref().is_none_or(|e| e.eq_ignore_ascii_case(&digest::to_hex(&digest::sha256(data))));
+
+        if fs::read(&path).is_ok_and(|data| expected.is_some() && matches(&data)) {
+            output::info(&format!("{} is already downloaded", asset.name));
+        } else {
+            output::info(&format!("Downloading {}", asset.url));
+            let data = update::fetch(&asset.url).unwrap_or_else(|e| panic!("{}", e));
+            if !matches(&data) {
+                panic!("{} does not match its published SHA-256, nothing was saved", asset.name)
+            }
+            if expected.is_none() {
+                output::warn(&format!("No SHA-256 is published for {}, so it is only checked against known releases when flashed", asset.name))
+            }
+            if let Some(dir) = path.parent() {
+                fs::create_dir_all(dir).expect("Failed to create cache directory")
+            }
+            fs::write(&path, &data).unwrap_or_else(|e| panic!("Failed to write {}: {}", path.display(), e));
+        }
+        fetched.push(path)
+    }
+
+    if fetched.is_empty() {
+        panic!("Release {} has no files attached", tag)
+    }
+    for path in fetched {
+        output::success(&format!("Saved {}, flash it with: rt890-flash -p PORT -f {}", path.display(), path.display()))
+    }
+}
+
+pub fn show_firmware_diff(old: &String, new: &String) {
+    let (a, b) = (read_firmware(old), read_firmware(new));
+    let unknown = || String::from("unknown");
+
+    println!("Size: {} -> {} bytes", a.len(), b.len());
+    println!("Version: {} -> {}", firmware::version(&a).unwrap_or_else(unknown), firmware::version(&b).unwrap_or_else(unknown));
+    println!("Build date: {} -> {}", firmware::build_date(&a).unwrap_or_else(unknown), firmware::build_date(&b).unwrap_or_else(unknown));
+
+    let ranges = firmware::differences(&a, &b);
+    if !ranges.is_empty() {
+        println!("\nDiffering ranges:");
+    }
+    for range in &ranges {
+        println!("{:#06x}-{:#06x} ({} bytes)", range.start, range.end - 1, range.len())
+    }
+
+    let (removed, added) = (firmware::strings_missing_from(&a, &b), firmware::strings_missing_from(&b, &a));
+    for (title, strings) in [(old, &removed), (new, &added)] {
+        if !strings.is_empty() {
+            println!("\nStrings only in {}:", title)
+        }
+        for (offset, s) in strings.iter() {
+            println!("{:#06x}: {}", offset, s)
+        }
+    }
+
+    let bytes: usize = ranges.iter().map(|r| r.len()).sum();
+    println!("\n{} bytes differ in {} ranges, {} strings removed and {} added",
+        bytes, ranges.len(), removed.len(), added.len())
+}
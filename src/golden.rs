@@ -0,0 +1,118 @@
+/*
+    Copyright 2024 Bricky
+    https://github.com/bricky149
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+*/
+
+// Known-good images kept per radio, for putting back one that has been
+// corrupted.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::args::{self, Args};
+use crate::backup::timestamp;
+use crate::config::{self, Config};
+use crate::connect::{open_normal_mode_port, record_session};
+use crate::crypt::read_input;
+use crate::digest;
+use crate::dump::{manifest_path, radio_id, read_spi, write_manifest};
+use crate::output;
+use crate::spi::{CALIBRATION, SPI_FLASH_SIZES};
+
+fn golden_path(id: &str) -> PathBuf {
+    config::golden_dir().expect("Cannot find the config directory, set HOME").join(format!("{}.bin", id))
+}
+
+// Where the image came from is kept beside it, so a bench knows what it restores
+fn golden_metadata_path(image: &Path) -> PathBuf {
+    image.with_extension("toml")
+}
+
+pub fn set_golden(args: &Args) -> bool {
+    let filename = &args.files[0];
+    let spi = match read_input(filename) {
+        Ok(f) => f,
+        Err(e) => panic!("{}", e)
+    };
+    if !SPI_FLASH_SIZES.contains(&spi.len()) {
+        return false
+    }
+
+    // An image restored over and over must not be one that has already rotted
+    if let Ok(manifest) = fs::read_to_string(manifest_path(filename)) {
+        if digest::matches_manifest(&spi, &manifest) != Some(true) {
+            panic!("{} does not match {}, so it cannot be trusted as a golden image", filename, manifest_path(filename))
+        }
+    }
+
+    let calibration = &spi[CALIBRATION.offset..CALIBRATION.offset + CALIBRATION.size];
+    if calibration.iter().all(|&b| b == 0xFF) {
+        panic!("Calibration in {} is blank, so which radio it belongs to cannot be told", filename)
+    }
+    let id = radio_id(calibration);
+    let path = golden_path(&id);
+    if path.exists() && !args.force {
+        panic!("Radio {} already has a golden image. Specify --force to replace it.", id)
+    }
+
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).expect("Failed to create golden image directory")
+    }
+    fs::write(&path, &spi).expect("Failed to save golden image");
+    write_manifest(&path.to_string_lossy(), &spi);
+    let metadata = format!("source = \"{}\"\nset = \"{}\"\nsize = {}\n", filename, timestamp(), spi.len());
+    fs::write(golden_metadata_path(&path), metadata).expect("Failed to save golden image details");
+
+    output::success(&format!("Golden image for radio {} saved to {}", id, path.display()));
+    true
+}
+
+// Restores through -r, so the manifest, calibration and recovery checks all apply
+pub fn restore_golden(args: &Args, config: &Config, operation: fn(&Args) -> bool) -> bool {
+    let id = match args.files.first() {
+        Some(id) => id.clone(),
+        None => {
+            let serial = open_normal_mode_port(&args.port, args.baud_rate, args.timeout.unwrap_or(Duration::from_secs(2)));
+            let mut port = record_session(serial, &None);
+            let calibration = read_spi(port.as_mut(), CALIBRATION.offset, CALIBRATION.size);
+            if calibration.iter().all(|&b| b == 0xFF) {
+                panic!("The radio's calibration is blank, so its golden image cannot be found. \
+                    Give the ID printed by golden set instead.")
+            }
+            radio_id(&calibration)
+        }
+    };
+
+    let path = golden_path(&id);
+    if !path.exists() {
+        panic!("No golden image for radio {}. Save one with golden set first.", id)
+    }
+    output::info(&format!("Restoring the golden image for radio {}", id));
+    if let Ok(metadata) = fs::read_to_string(golden_metadata_path(&path)) {
+        for (key, value) in metadata.lines().filter_map(|l| l.split_once(" = ")) {
+            output::info(&format!("  {}: {}", key, value.trim_matches('"')))
+        }
+    }
+
+    let path = path.to_string_lossy();
+    let argv: Vec<String> = ["rt890-flash", "-p", &args.port, "-r", &path].iter().map(|a| a.to_string()).collect();
+    let mut restore = args::parse(&argv, config).expect("Invalid restore options");
+    // The image is restored whole, whatever size the config file expects
+    restore.flash_size = None;
+    restore.timeout = args.timeout;
+    restore.record = args.record.clone();
+    operation(&restore)
+}
@@ -0,0 +1,105 @@
+/*
+    Copyright 2024 Bricky
+    https://github.com/bricky149
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+*/
+
+/// Reads bytes written as hex pairs, e.g. "52 00 10" or "520010"
+pub fn parse(text: &str) -> Option<Vec<u8>> {
+    let digits: String = text.split_whitespace().collect();
+    if digits.is_empty() || !digits.len().is_multiple_of(2) {
+        return None
+    }
+
+    (0..digits.len()).step_by(2)
+        .map(|i| u8::from_str_radix(digits.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Formats bytes as hex pairs, the way parse reads them
+pub fn to_string(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect::<Vec<_>>().join(" ")
+}
+
+/// Formats data 16 bytes per line with addresses starting at base
+pub fn dump(data: &[u8], base: usize) -> Vec<String> {
+    data.chunks(16).enumerate().map(|(i, line)| {
+        let bytes: Vec<String> = line.iter().map(|b| format!("{:02x}", b)).collect();
+        let text: String = line.iter()
+            .map(|&b| if b.is_ascii_graphic() || b == b' ' { b as char } else { '.' })
+            .collect();
+        format!("{:08x}  {:<47}  {}", base + i * 16, bytes.join(" "), text)
+    }).collect()
+}
+
+/// Record that ends an Intel HEX file
+pub const INTEL_HEX_END: &str = ":00000001FF\n";
+
+fn intel_hex_record(kind: u8, address: u16, data: &[u8]) -> String {
+    let mut bytes = vec![data.len() as u8, (address >> 8) as u8, address as u8, kind];
+    bytes.extend_from_slice(data);
+    let sum = bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+    bytes.push(sum.wrapping_neg());
+    let digits: String = bytes.iter().map(|b| format!("{:02X}", b)).collect();
+    format!(":{}\n", digits)
+}
+
+/// Intel HEX data records for bytes starting at address, 16 to a line. An
+/// extended linear address record comes first whenever they start in a
+/// different 64 KiB segment to the last, which segment keeps track of.
+pub fn intel_hex(data: &[u8], address: usize, segment: &mut Option<u16>) -> String {
+    let mut text = String::new();
+    for (i, line) in data.chunks(16).enumerate() {
+        let at = address + i * 16;
+        let upper = (at >> 16) as u16;
+        if *segment != Some(upper) {
+            text.push_str(&intel_hex_record(4, 0, &upper.to_be_bytes()));
+            *segment = Some(upper)
+        }
+        text.push_str(&intel_hex_record(0, at as u16, line))
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_and_format() {
+        assert_eq!(parse("52 00 10"), Some(vec![0x52, 0x00, 0x10]));
+        assert_eq!(parse("520010"), Some(vec![0x52, 0x00, 0x10]));
+        assert_eq!(parse("5 2"), Some(vec![0x52]));
+        assert_eq!(parse("520"), None);
+        assert_eq!(parse("zz"), None);
+        assert_eq!(parse(""), None);
+        assert_eq!(to_string(&[0x52, 0x00, 0xff]), "52 00 ff")
+    }
+
+    #[test]
+    fn intel_hex_crosses_64k() {
+        let data: Vec<u8> = (0..32).collect();
+        let mut segment = None;
+        let text = intel_hex(&data, 0xFFF0, &mut segment);
+        assert_eq!(text, ":020000040000FA\n\
+            :10FFF000000102030405060708090A0B0C0D0E0F89\n\
+            :020000040001F9\n\
+            :10000000101112131415161718191A1B1C1D1E1F78\n");
+        assert_eq!(segment, Some(1));
+
+        // Carrying on in the same segment needs no new address record
+        let more = intel_hex(&[0xAA], 0x1_0010, &mut segment);
+        assert_eq!(more, ":01001000AA45\n")
+    }
+}
@@ -0,0 +1,64 @@
+/*
+    Copyright 2024 Bricky
+    https://github.com/bricky149
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+*/
+
+extern crate nix;
+use nix::sys::signal::{self, SaFlags, SigAction, SigHandler, SigSet, Signal};
+
+use std::panic::{self, AssertUnwindSafe};
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Exit code shells expect after SIGINT
+pub const EXIT_CODE: i32 = 130;
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_sigint(_: i32) {
+    INTERRUPTED.store(true, Ordering::SeqCst)
+}
+
+// Ctrl-C no longer kills the process outright, so every long-running loop
+// must check interrupted() and stop at a safe point itself
+pub fn install() {
+    let action = SigAction::new(SigHandler::Handler(on_sigint), SaFlags::SA_RESTART, SigSet::empty());
+    unsafe { signal::sigaction(Signal::SIGINT, &action) }
+        .expect("Failed to install Ctrl-C handler");
+}
+
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+// Carries the exit code out of an operation that stopped early and has
+// already said why, so that serve and jobs are not taken down with it
+struct Stop(i32);
+
+/// Unwinds out of the current operation without reporting an error
+pub fn stop(code: i32) -> ! {
+    panic::resume_unwind(Box::new(Stop(code)))
+}
+
+/// Runs operation, exiting with its code if it stopped early
+pub fn exit_on_stop<T>(operation: impl FnOnce() -> T) -> T {
+    match panic::catch_unwind(AssertUnwindSafe(operation)) {
+        Ok(result) => result,
+        Err(e) => match e.downcast::<Stop>() {
+            Ok(stop) => process::exit(stop.0),
+            Err(e) => panic::resume_unwind(e)
+        }
+    }
+}
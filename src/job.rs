@@ -0,0 +1,376 @@
+/*
+    Copyright 2024 Bricky
+    https://github.com/bricky149
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+*/
+
+// Job files use a small subset of YAML, for example:
+//
+//   port: /dev/ttyUSB0
+//   steps:
+//     - dump: spi_backup.bin
+//     - pause: Put the radio in bootloader mode
+//     - flash: firmware.bin
+//       inter-chunk-delay: 5
+//     - pause: Restart the radio in normal mode
+//     - restore: spi_backup.bin
+//       calibration: true
+//
+// A patch step changes bytes in a file between steps, which covers the
+// custom workflows a scripting language would otherwise be wanted for:
+//
+//     - dump: spi_backup.bin
+//     - patch: spi_backup.bin
+//       offset: 0x3BF010
+//       expect: 1f
+//       bytes: 20
+//     - restore: spi_backup.bin
+//       calibration: true
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::args::{self, Args};
+use crate::config::Config;
+use crate::crypt;
+use crate::dump::{manifest_path, write_manifest};
+use crate::hex;
+use crate::output;
+
+pub enum Step {
+    /// Arguments as they would be given on the command line
+    Run(Vec<String>),
+    /// Message to show before waiting for Enter
+    Pause(String),
+    /// Bytes to write into a file, refused if the old ones differ from expect
+    Patch {
+        file: String,
+        offset: usize,
+        bytes: Vec<u8>,
+        expect: Option<Vec<u8>>
+    }
+}
+
+struct RawStep {
+    op: String,
+    file: String,
+    options: Vec<(String, String)>,
+    indent: usize
+}
+
+struct Entry {
+    indent: usize,
+    list_item: bool,
+    key: String,
+    value: String
+}
+
+fn unquote(value: &str) -> String {
+    let value = value.trim();
+    for quote in ['"', '\''] {
+        if let Some(inner) = value.strip_prefix(quote).and_then(|v| v.strip_suffix(quote)) {
+            return inner.to_string()
+        }
+    }
+    value.to_string()
+}
+
+fn parse_line(line: &str) -> Option<Result<Entry, ()>> {
+    // Comments must start a line or follow whitespace so paths may contain #
+    let line = match line.find(" #") {
+        Some(i) => &line[..i],
+        None => line
+    };
+    if line.trim().is_empty() || line.trim_start().starts_with('#') {
+        return None
+    }
+
+    let indent = line.len() - line.trim_start().len();
+    let mut rest = line.trim();
+    let list_item = rest.starts_with("- ");
+    if list_item {
+        rest = rest[2..].trim_start()
+    }
+
+    Some(match rest.split_once(':') {
+        Some((key, value)) => Ok(Entry {
+            indent,
+            list_item,
+            key: key.trim().to_string(),
+            value: unquote(value)
+        }),
+        None => Err(())
+    })
+}
+
+/// Builds the command line for a dump, flash or restore with options given
+/// as keys and values, e.g. calibration: true
+pub fn step_args(port: &Option<String>, op: &str, file: &str, options: &[(String, String)]) -> Result<Vec<String>, String> {
+    let flag = match op {
+        "dump" => "-d",
+        "flash" => "-f",
+        "restore" => "-r",
+        _ => return Err(format!("unknown step '{}'", op))
+    };
+
+    let mut argv = vec![String::from("rt890-flash"), flag.to_string()];
+    let mut port = port.clone();
+    for (key, value) in options {
+        match (key.as_str(), value.as_str()) {
+            ("port", _) => port = Some(value.clone()),
+            ("calibration", "true") => argv.push(String::from("-c")),
+            (_, "true") => argv.push(format!("--{}", key)),
+            (_, "false") => (),
+            _ => {
+                argv.push(format!("--{}", key));
+                argv.push(value.clone())
+            }
+        }
+    }
+
+    match port {
+        Some(p) => {
+            argv.push(String::from("-p"));
+            argv.push(p)
+        }
+        None => return Err(format!("no port given for '{}' step", op))
+    }
+    argv.push(file.to_string());
+    Ok(argv)
+}
+
+fn patch_step(step: RawStep) -> Result<Step, String> {
+    let (mut offset, mut bytes, mut expect) = (None, None, None);
+    for (key, value) in &step.options {
+        match key.as_str() {
+            "offset" => offset = Some(args::parse_number(value).ok_or(format!("invalid offset '{}'", value))?),
+            "bytes" => bytes = Some(hex::parse(value).ok_or(format!("invalid bytes '{}'", value))?),
+            "expect" => expect = Some(hex::parse(value).ok_or(format!("invalid bytes '{}'", value))?),
+            _ => return Err(format!("unknown option '{}' for 'patch' step", key))
+        }
+    }
+
+    let offset = offset.ok_or("no offset given for 'patch' step")?;
+    let bytes = bytes.ok_or("no bytes given for 'patch' step")?;
+    if expect.as_ref().is_some_and(|e| e.len() != bytes.len()) {
+        return Err(String::from("expect and bytes differ in length"))
+    }
+    Ok(Step::Patch { file: step.file, offset, bytes, expect })
+}
+
+/// Turns a job file into steps, or describes the first problem found
+pub fn parse(text: &str) -> Result<Vec<Step>, String> {
+    let mut port = None;
+    let mut in_steps = false;
+    let mut current: Option<RawStep> = None;
+    let mut steps = Vec::new();
+
+    for (number, line) in text.lines().enumerate() {
+        let entry = match parse_line(line) {
+            None => continue,
+            Some(Ok(e)) => e,
+            Some(Err(())) => return Err(format!("line {}: expected 'key: value'", number + 1))
+        };
+
+        if entry.indent == 0 && !entry.list_item {
+            match entry.key.as_str() {
+                "port" => port = Some(entry.value),
+                "steps" => in_steps = true,
+                _ => return Err(format!("line {}: unknown setting '{}'", number + 1, entry.key))
+            }
+            continue
+        }
+
+        if !in_steps {
+            return Err(format!("line {}: steps must come under 'steps:'", number + 1))
+        }
+
+        if entry.list_item {
+            steps.extend(current.take());
+            current = Some(RawStep {
+                op: entry.key,
+                file: entry.value,
+                options: Vec::new(),
+                indent: entry.indent
+            });
+            continue
+        }
+
+        match current.as_mut() {
+            Some(step) if entry.indent > step.indent => step.options.push((entry.key, entry.value)),
+            _ => return Err(format!("line {}: option does not belong to a step", number + 1))
+        }
+    }
+
+    steps.extend(current);
+
+    steps.into_iter().enumerate().map(|(i, step)| {
+        if step.op == "pause" {
+            return Ok(Step::Pause(step.file))
+        }
+        if step.op == "patch" {
+            return patch_step(step).map_err(|e| format!("step {}: {}", i + 1, e))
+        }
+        step_args(&port, &step.op, &step.file, &step.options)
+            .map(Step::Run)
+            .map_err(|e| format!("step {}: {}", i + 1, e))
+    }).collect()
+}
+
+pub fn run(filename: &String, config: &Config, operation: fn(&Args) -> bool) {
+    let text = match fs::read_to_string(filename) {
+        Ok(t) => t,
+        Err(e) => panic!("{}", e)
+    };
+
+    // Check every step up front so a typo cannot stop a job halfway
+    let steps = match parse(&text) {
+        Ok(s) => s,
+        Err(e) => panic!("Invalid job file, {}", e)
+    };
+    let mut jobs = Vec::new();
+    for (i, step) in steps.iter().enumerate() {
+        match step {
+            Step::Run(argv) => match args::parse_step(argv, config) {
+                Ok(a) => jobs.push(Some(a)),
+                Err(e) => panic!("Invalid job file, step {}: {}", i + 1, e)
+            },
+            Step::Pause(_) | Step::Patch { .. } => jobs.push(None)
+        }
+    }
+
+    for (i, (step, args)) in steps.iter().zip(jobs).enumerate() {
+        output::info(&format!("Step {} of {}", i + 1, steps.len()));
+        match (step, args) {
+            (Step::Pause(message), _) => {
+                output::prompt(&format!("{}. Press Enter to continue.", message));
+                io::stdin().read_line(&mut String::new()).expect("Failed to read from stdin");
+            }
+            (Step::Patch { file, offset, bytes, expect }, _) => patch_file(file, *offset, bytes, expect.as_deref()),
+            (_, Some(args)) => {
+                if !operation(&args) {
+                    panic!("Job stopped at step {}", i + 1)
+                }
+            }
+            _ => unreachable!()
+        }
+    }
+}
+
+// Manifests are rewritten so a restore later in the job accepts the change
+fn patch_file(filename: &str, offset: usize, bytes: &[u8], expect: Option<&[u8]>) {
+    let mut data = fs::read(filename).expect("Failed to read file to patch");
+    if crypt::is_encrypted(&data) {
+        panic!("{} is encrypted, so its bytes cannot be patched", filename)
+    }
+    let old = match data.get(offset..offset + bytes.len()) {
+        Some(o) => o.to_vec(),
+        None => panic!("{:#x} is beyond the end of {}", offset + bytes.len(), filename)
+    };
+    if expect.is_some_and(|e| e != old) {
+        panic!("{} has {} at {:#x} instead of the expected bytes", filename, hex::to_string(&old), offset)
+    }
+
+    data[offset..offset + bytes.len()].copy_from_slice(bytes);
+    fs::write(filename, &data).expect("Failed to write patched file");
+    if Path::new(&manifest_path(filename)).exists() {
+        write_manifest(filename, &data)
+    }
+    output::info(&format!("Patched {} at {:#x}: {} -> {}", filename, offset, hex::to_string(&old), hex::to_string(bytes)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::args::Mode;
+    use std::time::Duration;
+
+    const JOB: &str = "\
+port: /dev/ttyUSB0   # the programming cable
+steps:
+  - dump: spi_backup.bin
+  - pause: 'Put the radio in bootloader mode'
+  - flash: firmware.bin
+    inter-chunk-delay: 5
+  - restore: \"spi_backup.bin\"
+    calibration: true
+    port: /dev/ttyUSB1
+";
+
+    fn argv(step: &Step) -> Vec<&str> {
+        match step {
+            Step::Run(argv) => argv.iter().map(String::as_str).collect(),
+            _ => panic!("not a run step")
+        }
+    }
+
+    #[test]
+    fn parses_every_kind_of_step() {
+        let steps = parse(JOB).unwrap();
+        assert_eq!(steps.len(), 4);
+        assert_eq!(argv(&steps[0]), ["rt890-flash", "-d", "-p", "/dev/ttyUSB0", "spi_backup.bin"]);
+        assert!(matches!(&steps[1], Step::Pause(m) if m == "Put the radio in bootloader mode"));
+        assert_eq!(argv(&steps[2]), ["rt890-flash", "-f", "--inter-chunk-delay", "5", "-p", "/dev/ttyUSB0", "firmware.bin"]);
+        assert_eq!(argv(&steps[3]), ["rt890-flash", "-r", "-c", "-p", "/dev/ttyUSB1", "spi_backup.bin"])
+    }
+
+    #[test]
+    fn steps_parse_as_command_lines() {
+        let steps = parse(JOB).unwrap();
+        let args = match &steps[2] {
+            Step::Run(argv) => args::parse(argv, &Config::default()).unwrap(),
+            _ => unreachable!()
+        };
+        assert!(args.mode == Mode::Flash && args.inter_chunk_delay == Some(Duration::from_millis(5)) && args.port == "/dev/ttyUSB0")
+    }
+
+    #[test]
+    fn describes_problems() {
+        let problem = |text: &str| parse(text).err().unwrap_or_default();
+        assert_eq!(problem("steps:\n  - dump: a.bin\n"), "step 1: no port given for 'dump' step");
+        assert_eq!(problem("port: p\nsteps:\n  - erase: a.bin\n"), "step 1: unknown step 'erase'");
+        assert_eq!(problem("colour: red\n"), "line 1: unknown setting 'colour'");
+        assert_eq!(problem("  - dump: a.bin\n"), "line 1: steps must come under 'steps:'");
+        assert_eq!(problem("steps:\n  calibration: true\n"), "line 2: option does not belong to a step");
+        assert_eq!(problem("steps\n"), "line 1: expected 'key: value'")
+    }
+
+    #[test]
+    fn steps_cannot_set_hooks() {
+        // Hooks only come from the command line and config file
+        let config = Config::default();
+        let steps = parse("port: p\nsteps:\n  - dump: a.bin\n    pre-hook: touch x\n").unwrap();
+        match &steps[0] {
+            Step::Run(argv) => {
+                assert!(args::parse_step(argv, &config).is_err());
+                assert!(args::parse(argv, &config).is_ok())
+            }
+            _ => unreachable!()
+        }
+    }
+
+    #[test]
+    fn parses_patch_steps() {
+        let steps = parse("steps:\n  - patch: spi_backup.bin\n    offset: 0x3BF010\n    expect: 1f\n    bytes: 20\n").unwrap();
+        match &steps[0] {
+            Step::Patch { file, offset, bytes, expect } => {
+                assert_eq!((file.as_str(), *offset), ("spi_backup.bin", 0x3BF010));
+                assert_eq!((bytes.as_slice(), expect.as_deref()), (&[0x20][..], Some(&[0x1f][..])))
+            }
+            _ => panic!("not a patch step")
+        }
+        assert!(parse("steps:\n  - patch: a.bin\n    offset: 0x10\n").err().unwrap_or_default().contains("step 1"))
+    }
+}
@@ -0,0 +1,243 @@
+/*
+    Copyright 2024 Bricky
+    https://github.com/bricky149
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+*/
+
+// Just enough JSON to read API responses. Numbers are kept as f64, which
+// holds every size and ID these APIs return exactly.
+
+pub enum Value {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Value>),
+    Object(Vec<(String, Value)>)
+}
+
+impl Value {
+    /// Looks up a key of an object, or None for anything else
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Object(fields) => fields.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(s) => Some(s),
+            _ => None
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Value::Number(n) => Some(*n),
+            _ => None
+        }
+    }
+
+    pub fn as_array(&self) -> &[Value] {
+        match self {
+            Value::Array(items) => items,
+            _ => &[]
+        }
+    }
+}
+
+struct Parser<'a> {
+    text: &'a [u8],
+    at: usize
+}
+
+impl Parser<'_> {
+    fn skip_whitespace(&mut self) {
+        while self.text.get(self.at).is_some_and(|b| b.is_ascii_whitespace()) {
+            self.at += 1
+        }
+    }
+
+    fn expect(&mut self, literal: &str) -> Result<(), String> {
+        if self.text[self.at..].starts_with(literal.as_bytes()) {
+            self.at += literal.len();
+            Ok(())
+        } else {
+            Err(format!("expected {} at byte {}", literal, self.at))
+        }
+    }
+
+    fn value(&mut self) -> Result<Value, String> {
+        self.skip_whitespace();
+        match self.text.get(self.at) {
+            Some(b'n') => self.expect("null").map(|_| Value::Null),
+            Some(b't') => self.expect("true").map(|_| Value::Bool(true)),
+            Some(b'f') => self.expect("false").map(|_| Value::Bool(false)),
+            Some(b'"') => self.string().map(Value::String),
+            Some(b'[') => self.array(),
+            Some(b'{') => self.object(),
+            Some(b'-' | b'0'..=b'9') => self.number(),
+            _ => Err(format!("unexpected input at byte {}", self.at))
+        }
+    }
+
+    fn number(&mut self) -> Result<Value, String> {
+        let start = self.at;
+        while self.text.get(self.at).is_some_and(|b| b.is_ascii_digit() || b"+-.eE".contains(b)) {
+            self.at += 1
+        }
+        let text = String::from_utf8_lossy(&self.text[start..self.at]);
+        text.parse().map(Value::Number).map_err(|_| format!("invalid number at byte {}", start))
+    }
+
+    fn hex4(&mut self) -> Result<u32, String> {
+        let digits = self.text.get(self.at..self.at + 4).ok_or("string ends early")?;
+        self.at += 4;
+        u32::from_str_radix(&String::from_utf8_lossy(digits), 16).map_err(|_| format!("invalid escape at byte {}", self.at))
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        self.at += 1;
+        let mut bytes = Vec::new();
+        loop {
+            let byte = *self.text.get(self.at).ok_or("string ends early")?;
+            self.at += 1;
+            match byte {
+                b'"' => return Ok(String::from_utf8_lossy(&bytes).into_owned()),
+                b'\\' => {
+                    let escape = *self.text.get(self.at).ok_or("string ends early")?;
+                    self.at += 1;
+                    let c = match escape {
+                        b'n' => '\n',
+                        b't' => '\t',
+                        b'r' => '\r',
+                        b'b' => '\u{8}',
+                        b'f' => '\u{c}',
+                        b'u' => {
+                            let mut code = self.hex4()?;
+                            // Characters beyond the BMP come as a surrogate pair
+                            if (0xD800..0xDC00).contains(&code) && self.text[self.at..].starts_with(b"\\u") {
+                                self.at += 2;
+                                let low = self.hex4()?;
+                                code = 0x10000 + ((code - 0xD800) << 10) + (low.wrapping_sub(0xDC00) & 0x3FF)
+                            }
+                            char::from_u32(code).unwrap_or('\u{FFFD}')
+                        }
+                        other => other as char
+                    };
+                    bytes.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes())
+                }
+                _ => bytes.push(byte)
+            }
+        }
+    }
+
+    fn array(&mut self) -> Result<Value, String> {
+        self.at += 1;
+        let mut items = Vec::new();
+        self.skip_whitespace();
+        if self.text.get(self.at) == Some(&b']') {
+            self.at += 1;
+            return Ok(Value::Array(items))
+        }
+        loop {
+            items.push(self.value()?);
+            self.skip_whitespace();
+            match self.text.get(self.at) {
+                Some(b',') => self.at += 1,
+                Some(b']') => {
+                    self.at += 1;
+                    return Ok(Value::Array(items))
+                }
+                _ => return Err(format!("expected , or ] at byte {}", self.at))
+            }
+        }
+    }
+
+    fn object(&mut self) -> Result<Value, String> {
+        self.at += 1;
+        let mut fields = Vec::new();
+        self.skip_whitespace();
+        if self.text.get(self.at) == Some(&b'}') {
+            self.at += 1;
+            return Ok(Value::Object(fields))
+        }
+        loop {
+            self.skip_whitespace();
+            if self.text.get(self.at) != Some(&b'"') {
+                return Err(format!("expected a key at byte {}", self.at))
+            }
+            let key = self.string()?;
+            self.skip_whitespace();
+            self.expect(":")?;
+            fields.push((key, self.value()?));
+            self.skip_whitespace();
+            match self.text.get(self.at) {
+                Some(b',') => self.at += 1,
+                Some(b'}') => {
+                    self.at += 1;
+                    return Ok(Value::Object(fields))
+                }
+                _ => return Err(format!("expected , or }} at byte {}", self.at))
+            }
+        }
+    }
+}
+
+pub fn parse(text: &str) -> Result<Value, String> {
+    let mut parser = Parser { text: text.as_bytes(), at: 0 };
+    let value = parser.value()?;
+    parser.skip_whitespace();
+    if parser.at != parser.text.len() {
+        return Err(format!("unexpected input at byte {}", parser.at))
+    }
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_a_release() {
+        let text = r#" {"tag_name": "v1.2", "draft": false, "body": null,
+            "assets": [{"name": "fw.bin", "size": 60416, "digest": "sha256:ab"}, {"name": "b", "size": -1.5e1}]} "#;
+        let release = parse(text).unwrap();
+        assert_eq!(release.get("tag_name").and_then(Value::as_str), Some("v1.2"));
+        assert!(matches!(release.get("draft"), Some(Value::Bool(false))));
+        assert!(matches!(release.get("body"), Some(Value::Null)));
+        assert!(release.get("missing").is_none());
+
+        let assets = release.get("assets").map_or(&[][..], Value::as_array);
+        assert_eq!(assets.len(), 2);
+        assert_eq!(assets[0].get("size").and_then(Value::as_f64), Some(60416.0));
+        assert_eq!(assets[1].get("size").and_then(Value::as_f64), Some(-15.0));
+        assert!(parse("[]").unwrap().as_array().is_empty());
+        assert!(parse("{}").unwrap().get("a").is_none())
+    }
+
+    #[test]
+    fn unescapes_strings() {
+        let text = r#""a\"b\\c\/d\né😀""#;
+        assert_eq!(parse(text).unwrap().as_str(), Some("a\"b\\c/d\né😀"))
+    }
+
+    #[test]
+    fn refuses_invalid_json() {
+        for text in ["", "{", "[1,]", "{\"a\" 1}", "{a: 1}", "\"open", "tru", "1 2", "[1] x", "--1"] {
+            assert!(parse(text).is_err(), "{} parsed", text)
+        }
+    }
+}
@@ -0,0 +1,101 @@
+/*
+    Copyright 2024 Bricky
+    https://github.com/bricky149
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+*/
+
+// Checks for channels the radio would take but that are likely mistakes,
+// worth running before a codeplug goes out to a whole club.
+
+use std::cmp::Reverse;
+use std::ops::Range;
+
+use crate::channels::{self, Channel, Tone};
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Warning,
+    Error
+}
+
+impl Severity {
+    pub fn label(self) -> &'static str {
+        match self {
+            Severity::Warning => "warning",
+            Severity::Error => "error"
+        }
+    }
+}
+
+pub struct Finding {
+    pub severity: Severity,
+    pub text: String
+}
+
+// Bands nobody using this radio may transmit in, in tens of hertz
+const RECEIVE_ONLY: [(Range<u32>, &str); 4] = [
+    (8_750_000..10_800_000, "broadcast FM"),
+    (10_800_000..13_700_000, "the air band"),
+    (16_240_000..16_255_001, "weather broadcasts"),
+    (40_600_000..40_610_000, "distress beacons")
+];
+
+// Usual repeater splits on 2 m, 70 cm and GMRS, in tens of hertz. There is
+// no directory of repeaters to go by, but nearly all of them want a tone.
+const REPEATER_SPLITS: [u32; 4] = [60_000, 160_000, 500_000, 760_000];
+
+/// Everything found in the channels given, errors first
+pub fn check(in_use: &[Channel]) -> Vec<Finding> {
+    let mut found = Vec::new();
+    let mut report = |severity, number: usize, text: String| {
+        found.push(Finding { severity, text: format!("channel {}: {}", number, text) })
+    };
+
+    for channel in in_use {
+        for problem in channels::problems(channel) {
+            report(Severity::Error, channel.number, problem)
+        }
+        if !channel.transmits() {
+            continue
+        }
+        if let Some((_, band)) = RECEIVE_ONLY.iter().find(|(r, _)| r.contains(&channel.tx_frequency)) {
+            report(Severity::Error, channel.number,
+                format!("transmits on {} MHz, which is in {}", channels::format_frequency(channel.tx_frequency), band))
+        }
+        let split = channel.tx_frequency.abs_diff(channel.rx_frequency);
+        if REPEATER_SPLITS.contains(&split) && channel.tx_tone == Tone::None {
+            report(Severity::Warning, channel.number,
+                format!("has a {} MHz repeater split but sends no tone", channels::format_frequency(split)))
+        }
+    }
+
+    let mut names: Vec<(String, Vec<usize>)> = Vec::new();
+    for channel in in_use {
+        let name = channel.name.trim().to_ascii_uppercase();
+        if name.is_empty() {
+            continue
+        }
+        match names.iter_mut().find(|(n, _)| *n == name) {
+            Some((_, numbers)) => numbers.push(channel.number),
+            None => names.push((name, vec![channel.number]))
+        }
+    }
+    for (_, numbers) in names.iter().filter(|(_, n)| n.len() > 1) {
+        let others: Vec<String> = numbers[1..].iter().map(|n| n.to_string()).collect();
+        report(Severity::Warning, numbers[0], format!("has the same name as channel{} {}", if others.len() > 1 { "s" } else { "" }, others.join(", ")))
+    }
+
+    found.sort_by_key(|f| Reverse(f.severity));
+    found
+}
@@ -0,0 +1,176 @@
+/*
+    Copyright 2024 Bricky
+    https://github.com/bricky149
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+*/
+
+// Status messages are translated as they are printed, so the code keeps
+// writing them in English. Catalogs are gettext PO files in po/, built in,
+// whose msgids are the English messages with {} wherever format! fills
+// something in. A message is matched against each msgid in turn and what
+// stood for each {} is put into the msgstr unchanged, in the same order.
+// Anything filled in that needs translating is translated before it is.
+// Listings, tables and the usage text stay in English, as scripts read them.
+
+use std::env;
+use std::sync::OnceLock;
+
+const CATALOGS: [(&str, &str); 1] = [
+    ("es", include_str!("../po/es.po"))
+];
+
+static CATALOG: OnceLock<Vec<(String, String)>> = OnceLock::new();
+
+// Reads a quoted PO string, undoing its escapes
+fn po_string(text: &str) -> Option<String> {
+    let inner = text.trim().strip_prefix('"')?.strip_suffix('"')?;
+    let mut out = String::new();
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next()? {
+                'n' => out.push('\n'),
+                't' => out.push('\t'),
+                other => out.push(other)
+            },
+            c => out.push(c)
+        }
+    }
+    Some(out)
+}
+
+/// Reads the msgid and msgstr pairs of a PO file, leaving out untranslated
+/// ones. Strings may continue over several quoted lines.
+fn parse(text: &str) -> Vec<(String, String)> {
+    let mut entries = Vec::new();
+    let (mut id, mut translated) = (String::new(), String::new());
+    let mut in_msgstr = false;
+    for line in text.lines().map(str::trim) {
+        if let Some(rest) = line.strip_prefix("msgid ") {
+            if in_msgstr && !id.is_empty() && !translated.is_empty() {
+                entries.push((id.clone(), translated.clone()))
+            }
+            id = po_string(rest).unwrap_or_default();
+            translated.clear();
+            in_msgstr = false
+        } else if let Some(rest) = line.strip_prefix("msgstr ") {
+            translated = po_string(rest).unwrap_or_default();
+            in_msgstr = true
+        } else if line.starts_with('"') {
+            let more = po_string(line).unwrap_or_default();
+            if in_msgstr { translated.push_str(&more) } else { id.push_str(&more) }
+        }
+    }
+    if in_msgstr && !id.is_empty() && !translated.is_empty() {
+        entries.push((id, translated))
+    }
+    entries
+}
+
+// The language is the first part of LC_ALL, LC_MESSAGES or LANG, e.g. es
+// from es_AR.UTF-8, as C does for messages
+fn language() -> Option<String> {
+    let locale = ["LC_ALL", "LC_MESSAGES", "LANG"].iter()
+        .filter_map(|name| env::var(name).ok())
+        .find(|value| !value.is_empty())?;
+    let language = locale.split(['_', '.', '@']).next()?;
+    Some(language.to_ascii_lowercase())
+}
+
+fn catalog() -> &'static [(String, String)] {
+    CATALOG.get_or_init(|| {
+        let language = language();
+        CATALOGS.iter()
+            .find(|(name, _)| language.as_deref() == Some(name))
+            .map_or_else(Vec::new, |(_, text)| parse(text))
+    })
+}
+
+// What each {} in a msgid stood for in a message, or None if it does not match
+fn fill_ins<'a>(id: &str, message: &'a str) -> Option<Vec<&'a str>> {
+    let mut parts = id.split("{}");
+    let mut rest = message.strip_prefix(parts.next()?)?;
+    let parts: Vec<&str> = parts.collect();
+    let mut found = Vec::new();
+    for (i, part) in parts.iter().enumerate() {
+        // The last part has to end the message, the others are found leftmost
+        let at = if i == parts.len() - 1 {
+            rest.strip_suffix(part).map(str::len)?
+        } else if part.is_empty() {
+            return None
+        } else {
+            rest.find(part)?
+        };
+        found.push(&rest[..at]);
+        rest = &rest[at + part.len()..]
+    }
+    rest.is_empty().then_some(found)
+}
+
+/// The message in the user's language, or as it is if there is no
+/// translation for it
+pub fn translate(message: &str) -> String {
+    for (id, translated) in catalog() {
+        if let Some(found) = fill_ins(id, message) {
+            let mut out = String::new();
+            let mut parts = translated.split("{}");
+            out.push_str(parts.next().unwrap_or_default());
+            for (i, part) in parts.enumerate() {
+                out.push_str(found.get(i).copied().unwrap_or_default());
+                out.push_str(part)
+            }
+            return out
+        }
+    }
+    message.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_po_entries() {
+        let text = "# comment\nmsgid \"\"\nmsgstr \"\"\n\"Language: es\\n\"\n\n\
+            msgid \"Failed to open {}: {}\"\nmsgstr \"No se pudo \"\n\"abrir {}: {}\"\n\n\
+            msgid \"Say \\\"hi\\\"\\tnow\"\nmsgstr \"Di \\\"hola\\\"\\tya\"\n\n\
+            msgid \"Untranslated\"\nmsgstr \"\"\n";
+        let entries = parse(text);
+        assert_eq!(entries, [
+            (String::from("Failed to open {}: {}"), String::from("No se pudo abrir {}: {}")),
+            (String::from("Say \"hi\"\tnow"), String::from("Di \"hola\"\tya"))
+        ])
+    }
+
+    #[test]
+    fn finds_fill_ins() {
+        assert_eq!(fill_ins("Failed to open {}: {}", "Failed to open a.bin: No such file"), Some(vec!["a.bin", "No such file"]));
+        assert_eq!(fill_ins("{} {}% done", "Dump 40% done"), Some(vec!["Dump", "40"]));
+        assert_eq!(fill_ins("Dumping to {}", "Dumping to x.bin"), Some(vec!["x.bin"]));
+        assert_eq!(fill_ins("MCU flash erased", "MCU flash erased"), Some(vec![]));
+        assert_eq!(fill_ins("Dumping to {}", "Restoring x.bin"), None);
+        assert_eq!(fill_ins("MCU flash erased", "MCU flash erased twice"), None)
+    }
+
+    #[test]
+    fn catalogs_keep_every_fill_in() {
+        for (language, text) in CATALOGS {
+            let entries = parse(text);
+            assert!(!entries.is_empty(), "{} has no entries", language);
+            for (id, translated) in entries {
+                assert_eq!(id.matches("{}").count(), translated.matches("{}").count(), "{}: {}", language, id)
+            }
+        }
+    }
+}
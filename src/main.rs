@@ -15,236 +15,530 @@
     limitations under the License.
 */
 
-extern crate nix;
-use nix::unistd::Uid;
-
 extern crate serialport5;
 use self::serialport5::*;
 
-use std::env::args;
-use std::fs::{self, File};
-use std::io::Write;
-use std::time::Duration;
+use std::env::{self, args};
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+use std::process::{exit, Command, Stdio};
+use std::time::{Duration, Instant};
 
-mod spi;
-use spi::SpiRange;
+mod args;
+use args::{Args, Mode};
 
-mod uart;
+mod backup;
+use backup::{backup, watch};
 
-const HEADER: &str = "rt890-flash - Copyright 2024 bricky149";
-const USAGE: &str = "Flashing and dumping tool for the Radtel RT-890.
+mod bench;
+use bench::run_benchmark;
+
+mod channels;
+
+mod chirp;
+
+mod codeplug;
+
+mod config;
+
+mod connect;
+use connect::{describe_port, open_port, record_session};
+
+mod crypt;
+
+mod digest;
+
+mod doctor;
+
+mod dump;
+use dump::{dump_incremental, dump_spi_flash};
+
+mod dumpfile;
+use dumpfile::{assemble_dump, inspect_file, inspect_radio, print_checksums, scrub_dump, split_dump,
+    transplant_calibration, validate_dump};
+
+mod firmware;
+use firmware::FIRMWARE_SIZE;
+
+mod flash;
+use flash::{fetch_firmware_release, flash_firmware, list_firmware_releases, show_firmware_diff, show_firmware_info,
+    trust_firmware};
+
+mod golden;
+use golden::{restore_golden, set_golden};
+
+mod hex;
+
+mod interrupt;
+
+mod job;
+
+mod json;
+
+mod lint;
+
+mod locale;
+
+mod memories;
+use memories::{edit_channels, export_channels, find_channels, lint_channels};
+
+mod output;
+
+mod remote;
+
+mod report;
+
+mod restore;
+use restore::{clone_radio, restore_spi_flash};
+
+mod serve;
+
+mod session;
 
-rt890-flash -l
-rt890-flash -p PORT -d FILE
-rt890-flash -p PORT -f FILE
-rt890-flash -p PORT -r [-c] FILE
+mod shell;
 
--l
-List available ports, e.g. /dev/ttyUSB0
+mod spi;
+use spi::CHANNELS;
+
+mod transport;
 
--p PORT
-Port to read from or write to.
+mod uart;
+mod update;
+mod zip;
+use uart::BAUD_RATE;
 
--d FILE
-Dump external SPI flash to file, e.g. spi_backup.bin
-Radio MUST be in normal mode.
+// Only reachable from this machine unless asked otherwise
+const DEFAULT_LISTEN: &str = "127.0.0.1:8989";
 
--f FILE
-Write firmware file to MCU flash, e.g. firmware.bin
-Radio MUST be in bootloader mode and will automatically restart.
+const HEADER: &str = "rt890-flash - Copyright 2024 bricky149";
+const USAGE: &str = "Flashing and dumping tool for the Radtel RT-890.
 
--r [-c] FILE
-Write flash dump to external SPI flash, e.g. spi_backup.bin
-If -c is specified, only calibration data will be written.
-Radio MUST be in normal mode and be manually restarted.
+rt890-flash -l [--radios-only | --known-cables] [--json]
+rt890-flash -p PORT -d [--channels | --flash-size MIB] [--incremental BASE] [--baud N]
+                       [--pipeline | --verify-dump] [--force] [--format bin | ihex] [--encrypt] (FILE | --out FILE)
+rt890-flash -p PORT -f [-y] [--installed VERSION [--allow-downgrade]] [--resume-from ADDR]
+                       (FILE | --url URL [--sha256 HASH])
+rt890-flash -p PORT -r [-c | --channels | --flash-size MIB] [--preserve-calibration | --force-foreign]
+                       [--paranoid | --no-paranoid] [--ignore-manifest] [--baud N] [--resume-from ADDR] FILE
+rt890-flash raw -p PORT --send BYTES
+rt890-flash shell -p PORT
+rt890-flash bench -p PORT [--baud N]
+rt890-flash clone --from PORT --to PORT [--channels] [--baud N] [--timeout MS]
+rt890-flash cal transplant --from DUMP --into DUMP --out OUTPUT
+rt890-flash scrub DUMP --out OUTPUT [--blank-channels] [--blank-calibration]
+rt890-flash split DUMP DIR
+rt890-flash assemble DIR --out OUTPUT
+rt890-flash inspect (FILE | -p PORT) [--offset ADDR] [--length N]
+rt890-flash checksum DUMP [OTHER]
+rt890-flash validate DUMP
+rt890-flash channels bulk-set (DUMP OUTPUT | -p PORT) [--where FIELD=VALUE] TONES
+rt890-flash channels import CSV (DUMP OUTPUT | -p PORT) [--format chirp]
+rt890-flash channels export (DUMP | -p PORT) CSV [--format chirp]
+rt890-flash channels tidy (DUMP OUTPUT | -p PORT) [--dedupe] [--sort frequency | name] [--compact] [-y]
+rt890-flash channels compact (DUMP OUTPUT | -p PORT) [-y]
+rt890-flash channels rename (DUMP OUTPUT | -p PORT) [--match PATTERN] --template TEMPLATE [-y]
+rt890-flash channels find (DUMP | -p PORT) [--freq MHZ[-MHZ]] [--tone TONE] [--name PATTERN]
+rt890-flash lint (DUMP | -p PORT)
+rt890-flash backup -p PORT [--rotate N]
+rt890-flash watch [--on-connect backup]
+rt890-flash serve [--listen ADDR]
+rt890-flash run JOB
+rt890-flash replay SESSION
+rt890-flash firmware info FILE
+rt890-flash firmware diff OLD NEW
+rt890-flash firmware trust FILE
+rt890-flash firmware releases [TAG]
+rt890-flash golden set [--force] DUMP
+rt890-flash golden restore -p PORT [ID]
+rt890-flash report [--session SESSION]
+rt890-flash doctor
+rt890-flash self-update [-y]
+
+Options accepted by most commands:
+-p PORT                  serial port, or tcp://HOST:PORT for one on the network
+-y, --yes                do not ask for confirmation where a command would
+--radio NAME             use the [radio.NAME] settings from the config file
+--baud N                 talk to the radio at N baud instead of 115200
+--timeout MS             wait up to MS milliseconds for each reply
+--inter-chunk-delay MS   pause after each chunk written, for unreliable cables
+--record SESSION         save all traffic with the radio, for replay
+--log-file FILE          write a timestamped log of the traffic and retries
+--pre-hook COMMAND       run a shell command before -d, -f, -r and clone
+--post-hook COMMAND      run a shell command after them
+--progress-json          print progress as JSON lines on standard error
+--accessible             print progress as plain lines every 10%, without colour
+--no-color               print status messages without colour
+-h, --help               show this text
+
+Radios MUST be in normal mode, except for -f, which needs bootloader mode.
+Defaults are read from ~/.config/rt890-flash/config.toml.
+
+README.md describes each command and the config file in detail, and lists
+what cannot be done yet and why, e.g. reading CPS codeplugs.
 ";
 
-const BAUD_RATE: u32 = 115_200;
-const CHUNK_LENGTH: usize = 128;
-const FIRMWARE_SIZE: usize = 60_416;
-const SPI_FLASH_SIZE: usize = 4_194_304;
-
-fn dump_spi_flash(port: &String, filename: &String) {
-    let port = SerialPort::builder()
-        .baud_rate(BAUD_RATE)
-        .read_timeout(Some(Duration::from_secs(2)))
-        .open(port)
-        .expect("Failed to open port");
-
-    let mut fw = match File::create(filename) {
-        Ok(f) => f,
-        Err(e) => panic!("{}", e)
-    };
+fn summary(started: Instant) -> String {
+    format!("{} chunks transferred in {:.1} s, {} retried, {} checksum errors",
+        uart::chunks(), started.elapsed().as_secs_f64(), uart::retries(), uart::checksum_errors())
+}
 
-    for offset in 0..32768 {
-        match uart::command_readspiflash(&port, offset) {
-            Ok(Some(data)) => {
-                print!("\rDumping SPI flash from address {:#06x}", offset);
-                fw.write_all(&data).expect("Failed to dump SPI flash")
-            }
-            Ok(None) => break,
-            Err(e) => panic!("{}. Is the radio in normal mode?", e)
-        }
+fn print_summary(started: Instant) {
+    output::info(&summary(started));
+    if uart::retries() > 0 {
+        output::warn("Retries usually mean a marginal cable or adapter")
     }
 }
 
-fn restore_spi_flash(port: &String, calib_only: bool, filename: &String) -> Result<bool> {
-    let port = SerialPort::builder()
-        .baud_rate(BAUD_RATE)
-        .read_timeout(Some(Duration::from_secs(3)))
-        .open(port)
-        .expect("Failed to open port");
-
-    let spi = match fs::read(filename) {
-        Ok(f) => {
-            if f.len() != SPI_FLASH_SIZE {
-                return Ok(false)
-            };
-            f
-        },
-        Err(e) => panic!("{}", e)
+// Hooks run through the shell, told what the operation is in RT890_*
+// variables. Their output goes to stderr, as a dump may be going to stdout.
+fn run_hook(command: &str, args: &Args, succeeded: Option<bool>) -> bool {
+    let operation = match args.mode {
+        Mode::Dump => "dump",
+        Mode::Flash => "flash",
+        Mode::Restore => "restore",
+        _ => "clone"
     };
-
-    // TODO: Document these magic command bytes
-    let spi_ranges;
-    if calib_only {
-        spi_ranges = vec![
-            SpiRange { cmd: 0x48, offset: 3928064, size: 4096 }     // 3BF000 Calibration data
-        ];
-    } else {
-        spi_ranges = vec![
-            SpiRange { cmd: 0x40, offset: 0, size: 2949120 },
-            SpiRange { cmd: 0x41, offset: 2949120, size: 163840 },
-            SpiRange { cmd: 0x42, offset: 3112960, size: 139264 },
-            SpiRange { cmd: 0x43, offset: 3252224, size: 8192 },
-            SpiRange { cmd: 0x47, offset: 3887104, size: 40960 },
-            SpiRange { cmd: 0x48, offset: 3928064, size: 4096 },    // 3BF000 Calibration data
-            SpiRange { cmd: 0x49, offset: 3936256, size: 40960 },
-            SpiRange { cmd: 0x4b, offset: 4030464, size: 40960 },
-            SpiRange { cmd: 0x4c, offset: 3260416, size: 626688 }
-        ]; 
+    let mut hook = Command::new("sh");
+    hook.args(["-c", command])
+        .env("RT890_OPERATION", operation)
+        .env("RT890_PORT", args.from.as_ref().unwrap_or(&args.port))
+        .env("RT890_FILE", args.files.first().or(args.to.as_ref()).map_or("", String::as_str))
+        .stdout(Stdio::from(io::stderr()));
+    if let Some(succeeded) = succeeded {
+        hook.env("RT890_STATUS", if succeeded { "succeeded" } else { "failed" });
     }
-
-    for spi_range in spi_ranges {
-        let mut offset = spi_range.offset;
-        let block_length = offset + spi_range.size;
-
-        while offset < block_length {
-            match uart::command_writespiflash(&port, &spi_range, offset, &spi) {
-                Ok(true) => print!("\rRestoring SPI flash to address {:#08x}", offset),
-                _ => panic!("Failed to restore SPI flash. Is the radio in normal mode?")
-            }
-            offset += CHUNK_LENGTH
+    match hook.status() {
+        Ok(status) if status.success() => true,
+        Ok(status) => {
+            output::warn(&format!("Hook \"{}\" exited with {}", command, status));
+            false
+        }
+        Err(e) => {
+            output::warn(&format!("Failed to run hook \"{}\": {}", command, e));
+            false
         }
     }
-
-    Ok(true)
 }
 
-fn flash_firmware(port: &String, filename: &String) -> Result<bool> {
-    let port = SerialPort::builder()
-        .baud_rate(BAUD_RATE)
-        .read_timeout(Some(Duration::from_secs(2)))
-        .open(port)
-        .expect("Failed to open port");
-
-    let fw = match fs::read(filename) {
-        Ok(f) => {
-            if f.len() != FIRMWARE_SIZE {
-                return Ok(false)
-            };
-            f
-        },
-        Err(e) => panic!("{}", e)
-    };
+fn run_port_operation(args: &Args) -> bool {
+    // Job steps each get their own summary
+    uart::reset_counters();
+    let started = Instant::now();
 
-    match uart::command_eraseflash(&port) {
-        Ok(true) => println!("MCU flash erased"),
-        _ => panic!("Failed to erase MCU flash. Is the radio in bootloader mode?")
+    // A failing pre-hook stops the operation, e.g. when a check it makes fails
+    if let Some(hook) = &args.pre_hook {
+        if !run_hook(hook, args, None) {
+            output::error("Pre-operation hook failed, so the radio has not been touched");
+            return false
+        }
     }
 
-    let mut offset = 0;
-
-    while offset < FIRMWARE_SIZE {
-        match uart::command_writeflash(&port, offset, &fw) {
-            Ok(true) => print!("\rFlashing firmware to address {:#06x}", offset),
-            _ => panic!("Failed to write firmware to MCU flash")
+    // Failures mostly panic, and the post-hook should hear of those too
+    let result = panic::catch_unwind(AssertUnwindSafe(|| match args.mode {
+        Mode::Bench => {
+            run_benchmark(args);
+            true
+        }
+        Mode::Shell => {
+            let serial = open_port(&args.port, BAUD_RATE, args.timeout.unwrap_or(Duration::from_secs(1)));
+            let mut port = record_session(serial, &args.record);
+            shell::run(port.as_mut());
+            true
+        }
+        Mode::Inspect => {
+            inspect_radio(args);
+            true
+        }
+        Mode::BulkSet | Mode::ChannelImport | Mode::ChannelTidy | Mode::ChannelRename => edit_channels(args),
+        Mode::ChannelFind => {
+            find_channels(args);
+            true
+        }
+        Mode::ChannelExport => {
+            export_channels(args);
+            true
+        }
+        Mode::Lint => lint_channels(args),
+        Mode::Raw => {
+            if !shell::send_raw(args) {
+                output::error("Bytes to send must be given as hex pairs, e.g. \"52 00 10\"");
+                return false
+            }
+            true
+        }
+        Mode::Clone => {
+            clone_radio(args);
+            output::success("Clone complete. Reboot the target radio now.");
+            true
+        }
+        Mode::Dump => {
+            match &args.incremental {
+                Some(base) => dump_incremental(args, base),
+                None => dump_spi_flash(args)
+            }
+            output::success("SPI flash dump complete");
+            true
+        }
+        Mode::Flash => {
+            match flash_firmware(args) {
+                Ok(true) => {
+                    output::success("Firmware flash complete. Radio should now reboot.");
+                    true
+                }
+                _ => {
+                    output::error(&format!("Specified file is not exactly {} bytes", FIRMWARE_SIZE));
+                    false
+                }
+            }
+        }
+        Mode::Restore => {
+            match restore_spi_flash(args) {
+                Ok(true) => {
+                    if args.calib_only {
+                        output::success("Calibration restore complete. Reboot the radio now.")
+                    } else if args.channels_only {
+                        output::success("Channel restore complete. Reboot the radio now.")
+                    } else {
+                        output::success("SPI flash restore complete. Reboot the radio now.")
+                    }
+                    true
+                }
+                _ => {
+                    match (args.channels_only, args.flash_size) {
+                        (true, _) => output::error(&format!("Specified file is not exactly {} bytes", CHANNELS.size)),
+                        (false, Some(size)) => output::error(&format!("Specified file is not exactly {} bytes", size)),
+                        (false, None) => output::error("Specified file is not a 4 or 8 MiB dump")
+                    }
+                    false
+                }
+            }
         }
-        offset += CHUNK_LENGTH
+        _ => unreachable!()
+    }));
+    if let Some(hook) = &args.post_hook {
+        run_hook(hook, args, Some(matches!(result, Ok(true))));
     }
+    let succeeded = result.unwrap_or_else(|e| panic::resume_unwind(e));
 
-    Ok(true)
+    if matches!(args.mode, Mode::Dump | Mode::Flash | Mode::Restore | Mode::Clone) {
+        print_summary(started);
+        let outcome = if succeeded { "succeeded" } else { "failed" };
+        let command: Vec<String> = env::args().collect();
+        report::save_last_operation(&format!("{}\n{} {}\n", command.join(" "), outcome, summary(started)))
+    }
+    succeeded
 }
 
 fn main() {
     // Always display header text
-    println!("{}", HEADER);
+    eprintln!("{}", HEADER);
+
+    let argv: Vec<String> = args().collect();
+    let radio = argv.iter().position(|a| a == "--radio").and_then(|i| argv.get(i + 1));
+    let config = match config::load(radio.map(String::as_str)) {
+        Ok(c) => c,
+        Err(e) => {
+            output::error(&e);
+            exit(1)
+        }
+    };
 
-    let args: Vec<String> = args().collect();
-    match args.len() {
-        2 => { // Executable name with one argument
-            if args[1] != "-l" {
-                println!("{}", USAGE);
-                return
-            }
+    // Usage is only printed when asked for, so that it does not bury what was wrong
+    if argv.len() == 1 || argv.iter().any(|a| a == "-h" || a == "--help") {
+        println!("{}", USAGE);
+        return
+    }
+    let args = match args::parse(&argv, &config) {
+        Ok(a) => a,
+        Err(e) => {
+            output::error(&e);
+            eprintln!("Run \"rt890-flash --help\" for usage.");
+            exit(2)
+        }
+    };
+    output::init(args.no_color, args.progress_json, args.accessible);
+    if let Some(filename) = &args.log_file {
+        if let Err(e) = output::log_to(filename) {
+            output::error(&format!("Failed to open {}: {}", filename, e));
+            exit(1)
+        }
+    }
 
-            println!("Ports available:");
-            for p in uart::get_available_ports() {
-                println!("\t{}", p.port_name)
+    match args.mode {
+        Mode::List => {
+            let ports: Vec<SerialPortInfo> = uart::get_available_ports().into_iter().filter(|p| {
+                let usb = match &p.port_type {
+                    SerialPortType::UsbPort(usb) => Some(usb),
+                    _ => None
+                };
+                (!args.radios_only || usb.is_some())
+                    && (!args.known_cables_only || usb.and_then(uart::cable_chip).is_some())
+            }).collect();
+            if args.json {
+                let ports: Vec<String> = ports.iter().map(uart::port_json).collect();
+                println!("[{}]", ports.join(", "))
+            } else {
+                println!("Ports available:");
+                for p in ports {
+                    println!("\t{}", describe_port(&p))
+                }
             }
+            return
+        }
+        Mode::Report => {
+            report::print(&args.session);
+            return
         }
-        5..=6 => { // Executable name with four or five arguments
-            if args[1] != "-p" {
-                println!("{}", USAGE);
-                return
+        Mode::Doctor => {
+            if !doctor::run() {
+                exit(1)
             }
-
-            if !Uid::effective().is_root() {
-                println!("You must run this executable with root permissions");
-                return
+            output::success("Nothing found that would stop a radio being reached");
+            return
+        }
+        Mode::FirmwareInfo => {
+            show_firmware_info(&args.files[0]);
+            return
+        }
+        Mode::FirmwareTrust => {
+            trust_firmware(&args.files[0]);
+            return
+        }
+        Mode::FirmwareReleases => {
+            match args.files.first() {
+                Some(tag) => fetch_firmware_release(tag),
+                None => list_firmware_releases()
             }
-
-            match args[3].as_str() {
-                "-d" => {
-                    if args[4] != "-c" {
-                        dump_spi_flash(&args[2], &args[4]);
-                        println!("\nSPI flash dump complete")
-                    } else {
-                        // Cannot specify -c here
-                        println!("{}", USAGE);
-                        return
-                    }
+            return
+        }
+        Mode::FirmwareDiff => {
+            show_firmware_diff(&args.files[0], &args.files[1]);
+            return
+        }
+        Mode::Replay => {
+            if !session::replay(&args.files[0]) {
+                output::error("Specified file is not a session recording");
+                exit(1)
+            }
+            return
+        }
+        Mode::CalTransplant => {
+            let (from, into, out) = (args.from.as_ref().unwrap(), args.into.as_ref().unwrap(), args.out.as_ref().unwrap());
+            match transplant_calibration(from, into, out) {
+                true => output::success(&format!("Calibration from {} written into a copy of {} at {}", from, into, out)),
+                false => {
+                    output::error("Specified dumps are not 4 or 8 MiB");
+                    exit(1)
                 }
-                "-f" => {
-                    if args[4] != "-c" {
-                        match flash_firmware(&args[2], &args[4]) {
-                            Ok(true) => println!("\nFirmware flash complete. Radio should now reboot."),
-                            _ => println!("Specified file is not exactly {} bytes", FIRMWARE_SIZE)
-                        }
-                    } else {
-                        // Cannot specify -c here
-                        println!("{}", USAGE);
-                        return
-                    }
+            }
+            return
+        }
+        Mode::Scrub => {
+            match scrub_dump(&args) {
+                true => {
+                    output::success(&format!("Scrubbed copy written to {}", args.out.as_ref().unwrap()));
+                    output::warn("The DTMF ID and welcome text are not scrubbed, as where they are kept is not known yet")
                 }
-                "-r" => {
-                    if args[4] != "-c" {
-                        match restore_spi_flash(&args[2], false, &args[4]) {
-                            Ok(true) => println!("\nSPI flash restore complete. Reboot the radio now."),
-                            _ => println!("Specified file is not exactly {} bytes", SPI_FLASH_SIZE)
-                        }
-                    } else {
-                        match restore_spi_flash(&args[2], true, &args[5]) {
-                            Ok(true) => println!("\nCalibration restore complete. Reboot the radio now."),
-                            _ => println!("Specified file is not exactly {} bytes", SPI_FLASH_SIZE)
-                        }
-                    }
+                false => {
+                    output::error("Specified dump is not 4 or 8 MiB");
+                    exit(1)
                 }
-                _ => {
-                    println!("{}", USAGE);
+            }
+            return
+        }
+        Mode::Split => {
+            match split_dump(&args.files[0], &args.files[1]) {
+                true => output::success(&format!("Regions written to {}", args.files[1])),
+                false => {
+                    output::error("Specified dump is not 4 or 8 MiB");
+                    exit(1)
                 }
             }
+            return
+        }
+        Mode::Assemble => {
+            if !assemble_dump(&args.files[0], args.out.as_ref().unwrap()) {
+                exit(1)
+            }
+            output::success(&format!("Full dump written to {}", args.out.as_ref().unwrap()));
+            return
+        }
+        Mode::BulkSet | Mode::ChannelImport | Mode::ChannelTidy | Mode::ChannelRename if args.port.is_empty() => {
+            if !edit_channels(&args) {
+                exit(1)
+            }
+            return
+        }
+        Mode::ChannelFind if args.port.is_empty() => {
+            find_channels(&args);
+            return
         }
-        _ => println!("{}", USAGE)
+        Mode::ChannelExport if args.port.is_empty() => {
+            export_channels(&args);
+            return
+        }
+        Mode::Lint if args.port.is_empty() => {
+            if !lint_channels(&args) {
+                exit(1)
+            }
+            return
+        }
+        Mode::Inspect if args.port.is_empty() => {
+            inspect_file(&args);
+            return
+        }
+        Mode::Validate => {
+            match validate_dump(&args.files[0]) {
+                true => output::success(&format!("{} looks usable", args.files[0])),
+                false => {
+                    output::error(&format!("{} has problems, see above", args.files[0]));
+                    exit(1)
+                }
+            }
+            return
+        }
+        Mode::Checksum => {
+            if !print_checksums(&args.files) {
+                output::error("Specified dumps are not 4 or 8 MiB");
+                exit(1)
+            }
+            return
+        }
+        Mode::SelfUpdate => {
+            update::run(args.yes);
+            return
+        }
+        Mode::GoldenSet => {
+            if !set_golden(&args) {
+                output::error("Specified dump is not 4 or 8 MiB");
+                exit(1)
+            }
+            return
+        }
+        _ => ()
+    }
+
+    // Ctrl-C should simply leave the shell or stop the server
+    if !matches!(args.mode, Mode::Shell | Mode::Serve) {
+        interrupt::install()
     }
+
+    interrupt::exit_on_stop(|| {
+        if args.mode == Mode::Run {
+            job::run(&args.files[0], &config, run_port_operation);
+            output::success("Job complete")
+        } else if args.mode == Mode::GoldenRestore {
+            if !restore_golden(&args, &config, run_port_operation) {
+                exit(1)
+            }
+        } else if args.mode == Mode::Backup {
+            exit(backup(&args, &config, run_port_operation))
+        } else if args.mode == Mode::Watch {
+            watch(&config, run_port_operation)
+        } else if args.mode == Mode::Serve {
+            serve::run(args.listen.as_deref().unwrap_or(DEFAULT_LISTEN), &config, run_port_operation)
+        } else if !run_port_operation(&args) {
+            exit(1)
+        }
+    })
 }
@@ -0,0 +1,348 @@
+/*
+    Copyright 2024 Bricky
+    https://github.com/bricky149
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+*/
+
+// Channel commands, which work on the channel region of a dump or, given a
+// port, of the radio itself.
+
+use std::fs;
+use std::io::{self, Write};
+use std::time::Duration;
+
+use crate::args::{Args, Mode};
+use crate::channels::{self, Channel};
+use crate::codeplug::{self, CodeplugFormat};
+use crate::connect::{open_radio, throttle};
+use crate::crypt::read_input;
+use crate::dump::{read_spi, region_name};
+use crate::lint::{self, Severity};
+use crate::output::{self, confirm};
+use crate::spi::{CHANNELS, SPI_FLASH_SIZE, SPI_FLASH_SIZES};
+use crate::uart::{self, CHUNK_LENGTH};
+
+// Returns how many channels changed, or None if --where is not understood
+fn bulk_set(args: &Args, spi: &mut [u8]) -> Option<usize> {
+    let mut changed = 0;
+    for mut channel in channels::all(spi) {
+        if let Some(condition) = &args.condition {
+            if !channels::matches(&channel, condition)? {
+                continue
+            }
+        }
+        let before = (channel.rx_tone, channel.tx_tone);
+        channel.rx_tone = args.rx_tone.unwrap_or(channel.rx_tone);
+        channel.tx_tone = args.tx_tone.unwrap_or(channel.tx_tone);
+        if (channel.rx_tone, channel.tx_tone) != before {
+            channels::set(spi, &channel);
+            print_channel(&channel);
+            changed += 1
+        }
+    }
+    Some(changed)
+}
+
+fn describe_channel(channel: &Channel) -> String {
+    let tx_frequency = match channel.transmits() {
+        true => channels::format_frequency(channel.tx_frequency),
+        false => String::from("off")
+    };
+    format!("{:>4}  {}  {:<9}  {:<10}  rx {}  tx {}", channel.number,
+        channels::format_frequency(channel.rx_frequency), tx_frequency, channel.name, channel.rx_tone, channel.tx_tone)
+}
+
+fn print_channel(channel: &Channel) {
+    output::info(&describe_channel(channel))
+}
+
+// Prints a diff of the channel table and returns how many memories changed
+fn show_channel_changes(before: &[u8], after: &[u8]) -> usize {
+    let mut changed = 0;
+    for number in 1..=channels::CHANNEL_COUNT {
+        let (old, new) = (channels::get(before, number), channels::get(after, number));
+        if old.as_ref().map(describe_channel) == new.as_ref().map(describe_channel) {
+            continue
+        }
+        if let Some(old) = old {
+            output::info(&format!("- {}", describe_channel(&old)))
+        }
+        if let Some(new) = new {
+            output::info(&format!("+ {}", describe_channel(&new)))
+        }
+        changed += 1
+    }
+    changed
+}
+
+// Shows the change as a diff of the channel table and asks before going ahead
+fn tidy_channels(args: &Args, spi: &mut [u8]) -> bool {
+    let before = channels::all(spi);
+    let mut kept: Vec<&Channel> = Vec::new();
+    for channel in &before {
+        let duplicate = kept.iter().any(|k| (k.rx_frequency, k.tx_frequency) == (channel.rx_frequency, channel.tx_frequency));
+        if !(args.dedupe && duplicate) {
+            kept.push(channel)
+        }
+    }
+    match args.sort.as_deref() {
+        Some("frequency") => kept.sort_by_key(|c| c.rx_frequency),
+        Some("name") => kept.sort_by_key(|c| c.name.to_lowercase()),
+        _ => ()
+    }
+
+    // Without --compact, channels stay in the memories that were already in use
+    let slots: Vec<usize> = if args.compact {
+        (1..=kept.len()).collect()
+    } else {
+        let mut numbers: Vec<usize> = before.iter().map(|c| c.number).filter(|n| kept.iter().any(|k| k.number == *n)).collect();
+        numbers.sort();
+        numbers
+    };
+    let moves: Vec<(usize, usize)> = slots.into_iter().zip(kept.iter().map(|c| c.number)).collect();
+
+    let mut after = spi.to_vec();
+    channels::rearrange(&mut after, &moves);
+    let changed = show_channel_changes(spi, &after);
+
+    if changed == 0 {
+        output::info("Channels are already tidy");
+        return false
+    }
+    if !args.yes && !confirm(&format!("Write these changes to {} memories?", changed)) {
+        output::info("Tidy cancelled, nothing has been written");
+        return false
+    }
+    spi.copy_from_slice(&after);
+    true
+}
+
+// Only channels whose name changes are written, so others keep their records
+// byte for byte
+fn rename_channels(args: &Args, spi: &mut [u8]) -> bool {
+    let template = args.template.as_ref().unwrap();
+    let matching: Vec<Channel> = channels::all(spi).into_iter()
+        .filter(|c| args.pattern.as_ref().is_none_or(|p| channels::glob_matches(p, &c.name)))
+        .collect();
+
+    let mut after = spi.to_vec();
+    let mut truncated = 0;
+    for (i, channel) in matching.iter().enumerate() {
+        let name = match channels::render_name(template, channel, i + 1) {
+            Ok(n) => n,
+            Err(e) => {
+                output::error(&e);
+                return false
+            }
+        };
+        if name.chars().count() > channels::NAME_LENGTH {
+            truncated += 1
+        }
+        let name: String = name.chars().take(channels::NAME_LENGTH).collect::<String>().trim_end().to_string();
+        if name != channel.name {
+            channels::set(&mut after, &Channel { name, ..channel.clone() })
+        }
+    }
+
+    let changed = show_channel_changes(spi, &after);
+    if truncated > 0 {
+        output::warn(&format!("{} names are longer than {} characters and were cut short", truncated, channels::NAME_LENGTH))
+    }
+    if changed == 0 {
+        output::info(&format!("No names change, {} channels matched", matching.len()));
+        return false
+    }
+    if !args.yes && !confirm(&format!("Rename {} channels?", changed)) {
+        output::info("Rename cancelled, nothing has been written");
+        return false
+    }
+    spi.copy_from_slice(&after);
+    true
+}
+
+// Channels are read from the dump named first or, given a port, the radio
+fn read_channels(args: &Args) -> Vec<u8> {
+    if args.port.is_empty() {
+        let spi = match read_input(&args.files[0]) {
+            Ok(f) => f,
+            Err(e) => panic!("{}", e)
+        };
+        if !SPI_FLASH_SIZES.contains(&spi.len()) {
+            panic!("Specified dump is not 4 or 8 MiB")
+        }
+        spi
+    } else {
+        let mut port = open_radio(args, Duration::from_secs(3));
+        let mut spi = vec![0xFF; SPI_FLASH_SIZE];
+        spi[CHANNELS.offset..CHANNELS.offset + CHANNELS.size].copy_from_slice(&read_spi(port.as_mut(), CHANNELS.offset, CHANNELS.size));
+        spi
+    }
+}
+
+// A channel matches when it passes every filter given, and on frequency and
+// tone either its receive or transmit side will do
+pub fn find_channels(args: &Args) {
+    let spi = read_channels(args);
+    let found: Vec<Channel> = channels::all(&spi).into_iter().filter(|c| {
+        args.frequencies.is_none_or(|(low, high)| [c.rx_frequency, c.tx_frequency].iter().any(|f| (low..=high).contains(f)))
+            && args.rx_tone.is_none_or(|t| c.rx_tone == t || c.tx_tone == t)
+            && args.pattern.as_ref().is_none_or(|p| channels::glob_matches(p, &c.name))
+    }).collect();
+
+    println!("{:>4}  {:<9}  {:<9}  {:<10}  tones", "#", "rx", "tx", "name");
+    for channel in &found {
+        println!("{}", describe_channel(channel))
+    }
+    output::info(&format!("{} channels found", found.len()))
+}
+
+// Checked when the arguments were parsed
+fn channel_format(args: &Args) -> &'static dyn CodeplugFormat {
+    args.format.as_deref().and_then(codeplug::find).unwrap_or_else(codeplug::default_format)
+}
+
+// Returns false if any errors were found, warnings are left to the user
+pub fn lint_channels(args: &Args) -> bool {
+    let spi = read_channels(args);
+    let findings = lint::check(&channels::all(&spi));
+    for finding in &findings {
+        println!("{:<8}{}", finding.severity.label(), finding.text)
+    }
+    println!("{:<8}scan lists are not checked, as where they are kept is not known yet", "skipped");
+
+    let errors = findings.iter().filter(|f| f.severity == Severity::Error).count();
+    println!("{} errors, {} warnings", errors, findings.len() - errors);
+    errors == 0
+}
+
+pub fn export_channels(args: &Args) {
+    let spi = read_channels(args);
+    let all = channels::all(&spi);
+    let filename = args.files.last().unwrap();
+    let format = channel_format(args);
+    let data = format.encode(&all);
+    if filename == "-" {
+        io::stdout().write_all(&data).expect("Failed to write to stdout")
+    } else {
+        fs::write(filename, data).unwrap_or_else(|e| panic!("Failed to write {}: {}", filename, e))
+    }
+    output::success(&format!("{} channels written to {} as {}", all.len(), filename, format.description()))
+}
+
+// Puts channels from a CSV file into free memories in order
+fn import_channels(args: &Args, spi: &mut [u8]) -> bool {
+    let data = match read_input(&args.files[0]) {
+        Ok(d) => d,
+        Err(e) => panic!("{}", e)
+    };
+    let imported = match channel_format(args).decode(&data) {
+        Ok(c) => c,
+        Err(e) => {
+            output::error(&e);
+            return false
+        }
+    };
+
+    let free = channels::free(spi);
+    if imported.len() > free.len() {
+        output::error(&format!("{} channels to import but only {} memories are free", imported.len(), free.len()));
+        return false
+    }
+    for (mut channel, number) in imported.into_iter().zip(free) {
+        channel.number = number;
+        channels::set(spi, &channel);
+        print_channel(&channel)
+    }
+    true
+}
+
+// Edits a copy of a dump, which is read from the first of the last two files
+fn edit_dump_channels(args: &Args, edit: impl FnOnce(&mut [u8]) -> bool) -> bool {
+    let (input, output) = (&args.files[args.files.len() - 2], &args.files[args.files.len() - 1]);
+    let mut spi = match read_input(input) {
+        Ok(f) => f,
+        Err(e) => panic!("{}", e)
+    };
+    if !SPI_FLASH_SIZES.contains(&spi.len()) {
+        output::error("Specified dump is not 4 or 8 MiB");
+        return false
+    }
+
+    if !edit(&mut spi) {
+        return false
+    }
+    fs::write(output, spi).expect("Failed to write SPI flash dump");
+    output::success(&format!("Channels written to {}", output));
+    true
+}
+
+// Edits the channel region on the radio, writing back only the chunks that changed
+fn edit_radio_channels(args: &Args, edit: impl FnOnce(&mut [u8]) -> bool) -> bool {
+    let mut port = open_radio(args, Duration::from_secs(3));
+
+    let mut spi = vec![0xFF; SPI_FLASH_SIZE];
+    let region = CHANNELS.offset..CHANNELS.offset + CHANNELS.size;
+    spi[region.clone()].copy_from_slice(&read_spi(port.as_mut(), CHANNELS.offset, CHANNELS.size));
+    let before = spi[region.clone()].to_vec();
+
+    if !edit(&mut spi) {
+        return false
+    }
+
+    let changed = before.chunks(CHUNK_LENGTH).zip(spi[region.clone()].chunks(CHUNK_LENGTH))
+        .filter(|(a, b)| a != b)
+        .count();
+    output::start("write", changed * CHUNK_LENGTH, region_name);
+
+    for (i, chunk) in before.chunks(CHUNK_LENGTH).enumerate() {
+        let offset = CHANNELS.offset + i * CHUNK_LENGTH;
+        if chunk == &spi[offset..offset + CHUNK_LENGTH] {
+            continue
+        }
+        match uart::command_writespiflash(port.as_mut(), &CHANNELS, offset, &spi) {
+            Ok(true) => {
+                output::progress("Writing channels to", offset, CHUNK_LENGTH);
+                throttle(args)
+            }
+            _ => panic!("Failed to write SPI flash. Is the radio in normal mode?")
+        }
+    }
+    output::success("Channels written. Reboot the radio now.");
+    true
+}
+
+pub fn edit_channels(args: &Args) -> bool {
+    let edit = |spi: &mut [u8]| match args.mode {
+        Mode::BulkSet => match bulk_set(args, spi) {
+            Some(changed) => {
+                output::info(&format!("{} channels changed", changed));
+                true
+            }
+            None => {
+                output::error("--where must be band=, name=, frequency= or channel= followed by a value");
+                false
+            }
+        },
+        Mode::ChannelImport => import_channels(args, spi),
+        Mode::ChannelTidy => tidy_channels(args, spi),
+        Mode::ChannelRename => rename_channels(args, spi),
+        _ => unreachable!()
+    };
+
+    if args.port.is_empty() {
+        edit_dump_channels(args, edit)
+    } else {
+        edit_radio_channels(args, edit)
+    }
+}
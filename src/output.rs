@@ -0,0 +1,255 @@
+/*
+    Copyright 2024 Bricky
+    https://github.com/bricky149
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+*/
+
+// All status output goes to stderr so stdout stays free for dumps and
+// listings. Progress overwrites its own line, so anything else printed
+// while one is showing must move to a fresh line first.
+
+extern crate nix;
+use nix::unistd::isatty;
+
+extern crate tracing;
+extern crate tracing_subscriber;
+use self::tracing::Level;
+use self::tracing_subscriber::fmt::format::FmtSpan;
+
+use std::env;
+use std::fs::File;
+use std::io;
+use std::panic;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::locale;
+use crate::uart;
+
+static COLOR: AtomicBool = AtomicBool::new(false);
+static MID_LINE: AtomicBool = AtomicBool::new(false);
+static LAST_ADDRESS: AtomicUsize = AtomicUsize::new(0);
+static PROGRESS_JSON: AtomicBool = AtomicBool::new(false);
+static ACCESSIBLE: AtomicBool = AtomicBool::new(false);
+static OPERATION: Mutex<Option<Operation>> = Mutex::new(None);
+
+// The current rate is measured over at least this long, so it does not
+// jump about with every chunk
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+
+// What progress is being shown for and how fast it is going
+struct Operation {
+    name: &'static str,
+    total: usize,
+    done: usize,
+    region: fn(usize) -> Option<&'static str>,
+    // Region of the last address shown, so moving into the next can be logged
+    current_region: Option<&'static str>,
+    started: Instant,
+    // Bytes done when the current rate was last measured, when that was and the rate
+    sample: (usize, Instant, f64),
+    // Percentage last printed with --accessible
+    reported: usize,
+    // Action last shown and its translation, so it is only looked up once
+    action: (String, String)
+}
+
+impl Operation {
+    fn rates(&mut self) -> (f64, f64) {
+        let now = Instant::now();
+        let kib_per_sec = |bytes: usize, since: Instant| bytes as f64 / 1024.0 / now.duration_since(since).as_secs_f64().max(0.001);
+        let average = kib_per_sec(self.done, self.started);
+        let (done, at, _) = self.sample;
+        if now.duration_since(at) >= RATE_WINDOW {
+            self.sample = (self.done, now, kib_per_sec(self.done - done, at))
+        } else if at == self.started {
+            // Until a whole window has passed, the average is the best guess
+            self.sample.2 = average
+        }
+        (self.sample.2, average)
+    }
+}
+
+const RED: &str = "31";
+const GREEN: &str = "32";
+const YELLOW: &str = "33";
+
+/// Decides whether to use colour and how to show progress, and makes panics
+/// print as errors
+pub fn init(no_color: bool, progress_json: bool, accessible: bool) {
+    PROGRESS_JSON.store(progress_json, Ordering::SeqCst);
+    ACCESSIBLE.store(accessible, Ordering::SeqCst);
+
+    // https://no-color.org asks for any non-empty value to disable colour
+    let no_color = no_color || accessible || env::var_os("NO_COLOR").is_some_and(|v| !v.is_empty());
+    COLOR.store(!no_color && isatty(2).unwrap_or(false), Ordering::SeqCst);
+
+    panic::set_hook(Box::new(|info| {
+        let message = match info.payload().downcast_ref::<String>() {
+            Some(s) => s.as_str(),
+            None => info.payload().downcast_ref::<&str>().copied().unwrap_or("Unknown error")
+        };
+        tracing::error!("{}", message);
+        error(message);
+        // A failure right after a bad frame is most likely down to it
+        for line in uart::describe_bad_frame().unwrap_or_default() {
+            tracing::error!("{}", line);
+            self::info(&line)
+        }
+    }));
+}
+
+/// Writes a timeline of ports opened, every command sent, retries and
+/// region changes to a file for --log-file
+pub fn log_to(filename: &str) -> io::Result<()> {
+    let file = File::create(filename)?;
+    tracing_subscriber::fmt()
+        .with_writer(Mutex::new(file))
+        .with_max_level(Level::TRACE)
+        .with_span_events(FmtSpan::NEW | FmtSpan::CLOSE)
+        .init();
+    Ok(())
+}
+
+fn paint(text: &str, color: &str) -> String {
+    if COLOR.load(Ordering::SeqCst) {
+        format!("\x1b[{}m{}\x1b[0m", color, text)
+    } else {
+        text.to_string()
+    }
+}
+
+/// Moves past a progress line, e.g. before printing to stdout
+pub fn end_line() {
+    if MID_LINE.swap(false, Ordering::SeqCst) {
+        eprintln!()
+    }
+}
+
+/// Names the operation that following progress belongs to, how many bytes
+/// it will cover and which region of flash an address is in
+pub fn start(name: &'static str, total: usize, region: fn(usize) -> Option<&'static str>) {
+    let now = Instant::now();
+    tracing::info!(operation = name, total, "started");
+    *OPERATION.lock().unwrap() = Some(Operation {
+        name, total, done: 0, region, current_region: None, started: now, sample: (0, now, 0.0), reported: 0,
+        action: (String::new(), String::new())
+    })
+}
+
+/// Overwrites the current line with how far an operation has got, or prints
+/// a JSON object per line with --progress-json, or a line every 10% with
+/// --accessible
+pub fn progress(action: &str, address: usize, length: usize) {
+    LAST_ADDRESS.store(address, Ordering::SeqCst);
+
+    let mut operation = OPERATION.lock().unwrap();
+    if let Some(op) = operation.as_mut() {
+        op.done += length;
+        let region = (op.region)(address);
+        if region != op.current_region {
+            tracing::info!(operation = op.name, region, offset = address, retries = uart::retries(), "entered region");
+            op.current_region = region
+        }
+    }
+    // Screen readers read out every rewrite of a line, so only whole steps are printed
+    if ACCESSIBLE.load(Ordering::SeqCst) {
+        if let Some(op) = operation.as_mut().filter(|op| op.total > 0) {
+            let percent = (op.done * 100 / op.total).min(100) / 10 * 10;
+            if percent > op.reported {
+                op.reported = percent;
+                let mut name = op.name.to_string();
+                name[..1].make_ascii_uppercase();
+                eprintln!("{}", locale::translate(&format!("{} {}% done", locale::translate(&name), percent)))
+            }
+        }
+        return
+    }
+    if !PROGRESS_JSON.load(Ordering::SeqCst) {
+        match operation.as_mut() {
+            Some(op) => {
+                if op.action.0 != action {
+                    op.action = (action.to_string(), locale::translate(action))
+                }
+                let (current, average) = op.rates();
+                eprint!("\r{:<28}{:#08x}  {:>6.1} KiB/s, {:>6.1} average", op.action.1, address, current, average)
+            }
+            None => eprint!("\r{:<28}{:#08x}", locale::translate(action), address)
+        }
+        MID_LINE.store(true, Ordering::SeqCst);
+        return
+    }
+
+    let (name, region, done, total) = match operation.as_ref() {
+        Some(op) => (op.name, (op.region)(address), op.done, op.total),
+        None => ("unknown", None, 0, 0)
+    };
+    eprintln!(r#"{{"operation": {}, "region": {}, "offset": {}, "bytes_done": {}, "total": {}, "retries": {}}}"#,
+        json_string(name), region.map_or(String::from("null"), json_string), address, done, total, uart::retries())
+}
+
+/// Address most recently shown by progress, for reporting it elsewhere
+pub fn last_address() -> usize {
+    LAST_ADDRESS.load(Ordering::SeqCst)
+}
+
+/// Quotes and escapes text for use in JSON
+pub fn json_string(text: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c)
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Asks something on the current line, leaving the cursor after it
+pub fn prompt(question: &str) {
+    end_line();
+    eprint!("{} ", locale::translate(question))
+}
+
+pub fn confirm(question: &str) -> bool {
+    prompt(&format!("{} [y/N]", question));
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer).expect("Failed to read from stdin");
+    matches!(answer.trim(), "y" | "Y" | "yes")
+}
+
+pub fn info(message: &str) {
+    end_line();
+    eprintln!("{}", locale::translate(message))
+}
+
+pub fn success(message: &str) {
+    end_line();
+    eprintln!("{}", paint(&locale::translate(message), GREEN))
+}
+
+pub fn warn(message: &str) {
+    end_line();
+    eprintln!("{} {}", paint(&locale::translate("Warning:"), YELLOW), locale::translate(message))
+}
+
+pub fn error(message: &str) {
+    end_line();
+    eprintln!("{} {}", paint(&locale::translate("Error:"), RED), locale::translate(message))
+}
@@ -0,0 +1,64 @@
+/*
+    Copyright 2024 Bricky
+    https://github.com/bricky149
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+*/
+
+// Backups are pushed off the machine with curl, as downloads are. A PUT to a
+// URL ending in / is all WebDAV needs, and S3 and compatible stores take the
+// same once curl signs it.
+
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+pub struct Target<'a> {
+    /// Directory to upload into, e.g. https://dav.example.org/rt890/
+    pub url: &'a str,
+    /// user:password, or access key:secret key for S3
+    pub user: Option<&'a str>,
+    /// Set for S3, which signs requests for a region
+    pub s3_region: Option<&'a str>
+}
+
+/// Uploads a file into the target directory under its own name
+pub fn upload(target: &Target, file: &Path) -> Result<(), String> {
+    let name = file.file_name().ok_or("Nothing to upload")?.to_string_lossy();
+    let url = format!("{}/{}", target.url.trim_end_matches('/'), name);
+
+    let mut curl = Command::new("curl");
+    curl.args(["-fsS", "-K", "-", "-T"]).arg(file).arg(&url)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped());
+    if let Some(region) = target.s3_region {
+        curl.arg("--aws-sigv4").arg(format!("aws:amz:{}:s3", region));
+    }
+    let mut child = curl.spawn().map_err(|e| format!("Failed to run curl: {}", e))?;
+
+    // Credentials go through stdin, as arguments can be seen by every user
+    let config = match target.user {
+        Some(user) => format!("user = \"{}\"\n", user.replace('\\', "\\\\").replace('"', "\\\"")),
+        None => String::new()
+    };
+    let mut stdin = child.stdin.take().unwrap();
+    stdin.write_all(config.as_bytes()).map_err(|e| format!("Failed to talk to curl: {}", e))?;
+    drop(stdin);
+
+    let output = child.wait_with_output().map_err(|e| format!("Failed to run curl: {}", e))?;
+    if !output.status.success() {
+        return Err(format!("Failed to upload {}: {}", name, String::from_utf8_lossy(&output.stderr).trim()))
+    }
+    Ok(())
+}
@@ -0,0 +1,99 @@
+/*
+    Copyright 2024 Bricky
+    https://github.com/bricky149
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+*/
+
+extern crate nix;
+use nix::sys::utsname;
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+use crate::connect::describe_port;
+use crate::session::{self, Exchange};
+use crate::uart;
+
+const LAST_OPERATION: &str = "last-operation.txt";
+// Whole dumps are tens of thousands of exchanges, so only the end is kept
+const SESSION_TAIL: usize = 50;
+
+fn cache_dir() -> Option<PathBuf> {
+    match env::var_os("XDG_CACHE_HOME") {
+        Some(dir) => Some(PathBuf::from(dir)),
+        None => env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache"))
+    }.map(|dir| dir.join("rt890-flash"))
+}
+
+/// Remembers how the latest operation went so report can include it
+pub fn save_last_operation(text: &str) {
+    // Reports are a convenience, so failing to save one is not an error
+    if let Some(dir) = cache_dir() {
+        let _ = fs::create_dir_all(&dir).and_then(|_| fs::write(dir.join(LAST_OPERATION), text));
+    }
+}
+
+fn last_operation() -> String {
+    cache_dir()
+        .and_then(|dir| fs::read_to_string(dir.join(LAST_OPERATION)).ok())
+        .unwrap_or(String::from("No operation recorded\n"))
+}
+
+fn session_summary(exchanges: &[Exchange]) -> String {
+    let mut text = format!("{} exchanges recorded\n", exchanges.len());
+    let start = exchanges.len().saturating_sub(SESSION_TAIL);
+    for (i, exchange) in exchanges.iter().enumerate().skip(start) {
+        text.push_str(&format!("{:6} {}\n", i + 1, session::describe(exchange)))
+    }
+    text
+}
+
+/// Builds a plain text report to attach to bug reports
+pub fn generate(ports: &[String], session: Option<&[Exchange]>) -> String {
+    let uts = utsname::uname();
+    let mut text = String::from("## rt890-flash report\n\n");
+    text.push_str(&format!("Version: {}\n", env!("CARGO_PKG_VERSION")));
+    text.push_str(&format!("OS: {} {} ({} {})\n\n", env::consts::OS, env::consts::ARCH, uts.sysname(), uts.release()));
+
+    text.push_str("## Ports\n\n");
+    if ports.is_empty() {
+        text.push_str("None found\n")
+    }
+    for port in ports {
+        text.push_str(port);
+        text.push('\n')
+    }
+
+    text.push_str("\n## Last operation\n\n");
+    text.push_str(&last_operation());
+
+    if let Some(exchanges) = session {
+        text.push_str("\n## Session\n\n");
+        text.push_str(&session_summary(exchanges))
+    }
+    text
+}
+
+pub fn print(session: &Option<String>) {
+    let exchanges = session.as_ref().map(|filename| {
+        let data = match fs::read(filename) {
+            Ok(d) => d,
+            Err(e) => panic!("{}", e)
+        };
+        session::load(&data).expect("Specified file is not a session recording")
+    });
+    let ports: Vec<String> = uart::get_available_ports().iter().map(describe_port).collect();
+    print!("{}", generate(&ports, exchanges.as_deref()))
+}
@@ -0,0 +1,239 @@
+/*
+    Copyright 2024 Bricky
+    https://github.com/bricky149
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+*/
+
+// Writing a dump back to a radio, or one radio's SPI flash onto another.
+
+extern crate serialport5;
+use self::serialport5::*;
+
+extern crate tracing;
+use self::tracing::{info_span, warn};
+
+use std::thread;
+use std::time::Duration;
+
+use crate::args::Args;
+use crate::channels;
+use crate::connect::{open_normal_mode_port, record_session, reopen_port, throttle};
+use crate::crypt::read_input;
+use crate::dump::{check_manifest, detect_flash_size, read_spi, region_name};
+use crate::dumpfile::layout_description;
+use crate::interrupt;
+use crate::output;
+use crate::spi::{SpiRange, CALIBRATION, CHANNELS, SPI_FLASH_SIZE, SPI_FLASH_SIZES, SPI_RANGES};
+use crate::uart::{self, CHUNK_LENGTH};
+
+// How often a restore reopens the port after the radio stops answering
+const RECOVERY_ATTEMPTS: usize = 3;
+const RECOVERY_DELAY: Duration = Duration::from_secs(2);
+
+pub fn restore_spi_flash(args: &Args) -> Result<bool> {
+    let _span = info_span!("restore", port = %args.port, file = %args.files[0], paranoid = args.paranoid).entered();
+    let timeout = args.timeout.unwrap_or(Duration::from_secs(3));
+    let serial = open_normal_mode_port(&args.port, args.baud_rate, timeout);
+    let mut port = record_session(serial, &args.record);
+
+    let spi = match read_input(&args.files[0]) {
+        Ok(f) => {
+            let valid = match (args.channels_only, args.flash_size) {
+                (true, _) => f.len() == CHANNELS.size,
+                (false, Some(size)) => f.len() == size,
+                (false, None) => SPI_FLASH_SIZES.contains(&f.len())
+            };
+            if !valid {
+                return Ok(false)
+            };
+            f
+        },
+        Err(e) => panic!("{}", e)
+    };
+
+    if args.files[0] != "-" && !args.ignore_manifest {
+        check_manifest(&args.files[0], &spi)
+    }
+
+    if !args.channels_only && args.flash_size.is_none() {
+        let detected = detect_flash_size(port.as_mut());
+        if detected != spi.len() {
+            output::warn(&format!("Dump is {} MiB but the radio has {} MiB of SPI flash",
+                spi.len() / 1024 / 1024, detected / 1024 / 1024))
+        }
+    }
+
+    // Channel dumps are placed where they would sit in a full dump so the
+    // same offsets work for both
+    let spi = if args.channels_only {
+        let mut full = vec![0xFF; SPI_FLASH_SIZE];
+        full[CHANNELS.offset..CHANNELS.offset + CHANNELS.size].copy_from_slice(&spi);
+        full
+    } else {
+        spi
+    };
+
+    // Stock firmware cannot report its version, so only the dump can be judged
+    if !args.calib_only && channels::known_layout(&spi) == Some(false) {
+        output::warn(&format!("In {}, {}. They may be garbled on this radio.", args.files[0], layout_description(false)))
+    }
+
+    let spi_ranges: Vec<&SpiRange> = if args.calib_only {
+        vec![&CALIBRATION]
+    } else if args.channels_only {
+        vec![&CHANNELS]
+    } else {
+        SPI_RANGES.iter()
+            .filter(|r| !args.preserve_calibration || r.cmd != CALIBRATION.cmd)
+            .collect()
+    };
+
+    // Calibration is unique to each radio, so a full dump from another one
+    // must not silently replace it. A resumed restore was checked when it
+    // started, and calibration it has already written would now look foreign.
+    let resuming = args.resume_from.is_some();
+    if !args.calib_only && !args.force_foreign && !resuming && spi_ranges.iter().any(|r| r.cmd == CALIBRATION.cmd) {
+        let current = read_spi(port.as_mut(), CALIBRATION.offset, CALIBRATION.size);
+        let restored = &spi[CALIBRATION.offset..CALIBRATION.offset + CALIBRATION.size];
+        let blank = current.iter().all(|&b| b == 0xFF);
+        if !blank && current != restored {
+            panic!("Calibration in {} belongs to another radio. Specify --preserve-calibration \
+                to keep this radio's or --force-foreign to overwrite it.", args.files[0])
+        }
+    }
+
+    if let Some(resume) = args.resume_from {
+        if !spi_ranges.iter().any(|r| (r.offset..r.offset + r.size).contains(&resume)) {
+            panic!("Address {:#08x} is not written by this restore", resume)
+        }
+    }
+
+    // Ranges are not written in address order, so skip chunks until the
+    // resume point comes up rather than comparing addresses
+    let mut resume_from = args.resume_from;
+    let mut recoveries = 0;
+    let mut refused = false;
+    output::start("restore", spi_ranges.iter().map(|r| r.size).sum(), region_name);
+
+    for spi_range in &spi_ranges {
+        let _region = info_span!("region", name = spi_range.name, cmd = spi_range.cmd, offset = spi_range.offset).entered();
+        let mut offset = spi_range.offset;
+        let block_length = offset + spi_range.size;
+
+        while offset < block_length {
+            if resume_from.is_some_and(|resume| resume != offset) {
+                offset += CHUNK_LENGTH;
+                continue
+            }
+            resume_from = None;
+
+            if interrupt::interrupted() {
+                output::warn(&format!("Restore interrupted before address {:#08x}. The radio is partially written.", offset));
+                output::info(&format!("Keep it in normal mode and run the same command with --resume-from {:#08x}", offset));
+                interrupt::stop(interrupt::EXIT_CODE)
+            }
+
+            match uart::command_writespiflash(port.as_mut(), spi_range, offset, &spi) {
+                Ok(true) => {
+                    output::progress("Restoring SPI flash to", offset, CHUNK_LENGTH);
+                    refused = false;
+                    throttle(args)
+                }
+                // A stray byte can look like a refusal, so ask once more
+                Ok(false) if !refused => {
+                    refused = true;
+                    port.clear_input()?;
+                    continue
+                }
+                // The radio is answering, so reopening the port would not help
+                Ok(false) => panic!("The radio refused to write {} at address {:#08x}. Its firmware may protect \
+                    this region, which cannot be checked or turned off over UART.",
+                    region_name(offset).unwrap_or("SPI flash"), offset),
+                _ if recoveries < RECOVERY_ATTEMPTS => {
+                    recoveries += 1;
+                    warn!(offset, attempt = recoveries, "radio stopped answering, reopening port");
+                    output::warn(&format!("Radio stopped answering at address {:#08x}, reopening the port (attempt {} of {})",
+                        offset, recoveries, RECOVERY_ATTEMPTS));
+                    // Release the port first, as USB adapters often come back under the same name
+                    drop(port);
+                    thread::sleep(RECOVERY_DELAY);
+                    port = match reopen_port(args, timeout) {
+                        Some(p) => p,
+                        None => panic!("Radio did not come back at address {:#08x}. Once it does, \
+                            run the same command with --resume-from {:#08x}", offset, offset)
+                    };
+                    // Try the same chunk again
+                    continue
+                }
+                _ => panic!("Failed to restore SPI flash at address {:#08x}. Is the radio in normal mode? \
+                    Run the same command with --resume-from {:#08x} to carry on", offset, offset)
+            }
+
+            if args.paranoid {
+                let expected = &spi[offset..offset + CHUNK_LENGTH];
+                match uart::command_readspiflash(port.as_mut(), (offset / CHUNK_LENGTH) as u16) {
+                    Ok(Some(data)) => {
+                        if let Some(i) = data.iter().zip(expected).position(|(a, b)| a != b) {
+                            panic!("Verification failed at address {:#08x}, wrote {:#04x} but read {:#04x}",
+                                offset + i, expected[i], data[i])
+                        }
+                    }
+                    _ => panic!("Failed to read back SPI flash at address {:#08x}", offset)
+                }
+            }
+            offset += CHUNK_LENGTH
+        }
+    }
+
+    Ok(true)
+}
+
+// Streams each range chunk by chunk, so nothing is kept on disk
+pub fn clone_radio(args: &Args) {
+    let (from, to) = (args.from.as_ref().unwrap(), args.to.as_ref().unwrap());
+    let _span = info_span!("clone", from = %from, to = %to).entered();
+    let mut source = open_normal_mode_port(from, args.baud_rate, args.timeout.unwrap_or(Duration::from_secs(2)));
+    let mut target = open_normal_mode_port(to, args.baud_rate, args.timeout.unwrap_or(Duration::from_secs(3)));
+
+    // Calibration belongs to the target radio and is never copied over
+    let spi_ranges: Vec<&SpiRange> = if args.channels_only {
+        vec![&CHANNELS]
+    } else {
+        SPI_RANGES.iter().filter(|r| r.cmd != CALIBRATION.cmd).collect()
+    };
+
+    let mut spi = vec![0xFF; SPI_FLASH_SIZE];
+    output::start("clone", spi_ranges.iter().map(|r| r.size).sum(), region_name);
+    for spi_range in spi_ranges {
+        for offset in (spi_range.offset..spi_range.offset + spi_range.size).step_by(CHUNK_LENGTH) {
+            if interrupt::interrupted() {
+                output::warn(&format!("Clone interrupted before address {:#08x}. {} is partially written.", offset, to));
+                interrupt::stop(interrupt::EXIT_CODE)
+            }
+
+            match uart::command_readspiflash(source.as_mut(), (offset / CHUNK_LENGTH) as u16) {
+                Ok(Some(data)) => spi[offset..offset + CHUNK_LENGTH].copy_from_slice(&data),
+                Ok(None) => panic!("Failed to read SPI flash at address {:#08x} from {}", offset, from),
+                Err(e) => panic!("{}. Is the radio on {} in normal mode?", e, from)
+            }
+            match uart::command_writespiflash(target.as_mut(), spi_range, offset, &spi) {
+                Ok(true) => {
+                    output::progress("Cloning SPI flash at", offset, CHUNK_LENGTH);
+                    throttle(args)
+                }
+                _ => panic!("Failed to write SPI flash. Is the radio on {} in normal mode?", to)
+            }
+        }
+    }
+}
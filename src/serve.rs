@@ -0,0 +1,292 @@
+/*
+    Copyright 2024 Bricky
+    https://github.com/bricky149
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+*/
+
+// A small HTTP/1.1 API for dashboards and provisioning stations:
+//
+//   GET  /ports            ports as JSON
+//   POST /dump?port=P&file=F&channels=true
+//   POST /flash?port=P&file=F
+//   POST /restore?port=P&file=F&calibration=true
+//   GET  /job              the current or last job as JSON
+//   GET  /events           server-sent events with job progress until it ends
+//
+// Only one job runs at a time, as every operation shares the same counters.
+// Starting one takes the token printed at startup, so that a web page the
+// user happens to visit cannot start one through their browser.
+
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::args::{self, Args};
+use crate::config::Config;
+use crate::job;
+use crate::output::{self, json_string};
+use crate::uart;
+
+#[derive(Default)]
+struct Job {
+    operation: String,
+    running: bool,
+    succeeded: Option<bool>
+}
+
+struct Server {
+    config: Config,
+    token: String,
+    operation: fn(&Args) -> bool,
+    job: Mutex<Job>
+}
+
+// The options each endpoint passes on, leaving out any that run commands,
+// record traffic, overwrite files or otherwise go beyond the job itself.
+// --encrypt is left out too, as age would ask on the server's terminal.
+const OPTIONS: [(&str, &[&str]); 3] = [
+    ("dump", &["channels", "resume-from", "pipeline", "verify-dump", "baud", "timeout"]),
+    ("flash", &["allow-downgrade", "sha256", "paranoid", "no-paranoid", "baud", "timeout", "inter-chunk-delay"]),
+    ("restore", &["calibration", "channels", "flash-size", "resume-from", "preserve-calibration", "baud",
+        "timeout", "inter-chunk-delay"])
+];
+
+fn new_token() -> String {
+    // RandomState is seeded from the OS, which is random enough for this
+    (0..2).map(|_| format!("{:016x}", RandomState::new().build_hasher().finish())).collect()
+}
+
+// Checks every byte rather than stopping at the first that differs, so how
+// long a refusal takes does not give the token away a byte at a time
+fn token_matches(given: &str, token: &str) -> bool {
+    given.len() == token.len() && given.bytes().zip(token.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+// Files named by requests must be inside dir, so a request cannot read or
+// overwrite anything else the server's user can
+fn confine(dir: &Path, file: &str) -> Option<PathBuf> {
+    let name = Path::new(file);
+    if file.is_empty() || !name.components().all(|c| matches!(c, Component::Normal(_))) {
+        return None
+    }
+    Some(dir.join(name))
+}
+
+fn percent_decode(text: &str) -> String {
+    let mut decoded = Vec::with_capacity(text.len());
+    let mut bytes = text.bytes();
+    while let Some(byte) = bytes.next() {
+        match byte {
+            b'+' => decoded.push(b' '),
+            b'%' => {
+                let hex: Vec<u8> = bytes.by_ref().take(2).collect();
+                match std::str::from_utf8(&hex).ok().and_then(|h| u8::from_str_radix(h, 16).ok()) {
+                    Some(b) => decoded.push(b),
+                    // Not an escape after all, so keep it as it was
+                    None => {
+                        decoded.push(b'%');
+                        decoded.extend(hex)
+                    }
+                }
+            }
+            b => decoded.push(b)
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+fn query(target: &str) -> Vec<(String, String)> {
+    let query = target.split_once('?').map_or("", |(_, q)| q);
+    query.split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, "true"));
+            (percent_decode(key), percent_decode(value))
+        })
+        .collect()
+}
+
+fn respond(stream: &mut TcpStream, status: &str, body: &str) {
+    let _ = write!(stream, "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status, body.len(), body);
+}
+
+fn job_json(job: &Job) -> String {
+    let succeeded = match job.succeeded {
+        Some(s) => s.to_string(),
+        None => String::from("null")
+    };
+    format!(r#"{{"operation": {}, "running": {}, "address": {}, "succeeded": {}}}"#,
+        json_string(&job.operation), job.running, output::last_address(), succeeded)
+}
+
+fn ports_json() -> String {
+    let ports: Vec<String> = uart::get_available_ports().iter().map(uart::port_json).collect();
+    format!("[{}]", ports.join(", "))
+}
+
+fn take(options: &mut Vec<(String, String)>, key: &str) -> Result<String, (&'static str, String)> {
+    match options.iter().position(|(k, _)| k == key) {
+        Some(i) => Ok(options.remove(i).1),
+        None => Err(("400 Bad Request", format!("no {} given", key)))
+    }
+}
+
+fn start_job(server: &Arc<Server>, op: &str, target: &str) -> Result<(), (&'static str, String)> {
+    let mut options = query(target);
+    let file = take(&mut options, "file")?;
+    let dir = server.config.backup_dir.clone().unwrap_or_else(|| PathBuf::from("."));
+    let file = match confine(&dir, &file) {
+        Some(path) => path.to_string_lossy().into_owned(),
+        None => return Err(("400 Bad Request", format!("{} is not a relative path without \"..\"", file)))
+    };
+    // Only serial ports, rather than any device or address a request names
+    let port = take(&mut options, "port")?;
+    if !uart::get_available_ports().iter().any(|p| p.port_name == port) {
+        return Err(("400 Bad Request", format!("{} is not a serial port", port)))
+    }
+    let allowed = OPTIONS.iter().find(|(name, _)| *name == op).map_or(&[][..], |(_, o)| o);
+    if let Some((key, _)) = options.iter().find(|(k, _)| !allowed.contains(&k.as_str())) {
+        return Err(("400 Bad Request", format!("option '{}' is not accepted here", key)))
+    }
+    // The request itself is the confirmation a terminal would ask for
+    if op == "flash" {
+        options.push((String::from("yes"), String::from("true")))
+    }
+    let argv = job::step_args(&Some(port), op, &file, &options).map_err(|e| ("400 Bad Request", e))?;
+    let args = args::parse_step(&argv, &server.config).map_err(|e| ("400 Bad Request", e))?;
+
+    let mut job = server.job.lock().unwrap();
+    if job.running {
+        return Err(("409 Conflict", String::from("another job is running")))
+    }
+    *job = Job { operation: op.to_string(), running: true, succeeded: None };
+    drop(job);
+
+    let server = Arc::clone(server);
+    thread::spawn(move || {
+        // Failures panic with a message the hook has already printed
+        let succeeded = panic::catch_unwind(AssertUnwindSafe(|| (server.operation)(&args))).unwrap_or(false);
+        let mut job = server.job.lock().unwrap();
+        job.running = false;
+        job.succeeded = Some(succeeded)
+    });
+    Ok(())
+}
+
+fn stream_events(server: &Server, stream: &mut TcpStream) {
+    let header = "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\n\r\n";
+    if stream.write_all(header.as_bytes()).is_err() {
+        return
+    }
+    loop {
+        let (event, running) = {
+            let job = server.job.lock().unwrap();
+            (job_json(&job), job.running)
+        };
+        if write!(stream, "data: {}\n\n", event).is_err() || !running {
+            return
+        }
+        thread::sleep(Duration::from_millis(500))
+    }
+}
+
+fn handle(server: &Arc<Server>, mut stream: TcpStream) {
+    let mut reader = BufReader::new(match stream.try_clone() {
+        Ok(s) => s,
+        Err(_) => return
+    });
+    let mut request = String::new();
+    if reader.read_line(&mut request).is_err() {
+        return
+    }
+    // Only the token is needed, but every header must be read before replying
+    let mut authorized = false;
+    let mut header = String::new();
+    while reader.read_line(&mut header).is_ok_and(|n| n > 2) {
+        if let Some((name, value)) = header.split_once(':') {
+            let given = value.trim().strip_prefix("Bearer ").unwrap_or("");
+            if name.eq_ignore_ascii_case("authorization") && token_matches(given, &server.token) {
+                authorized = true
+            }
+        }
+        header.clear()
+    }
+
+    let mut parts = request.split_whitespace();
+    let (method, target) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+    let path = target.split('?').next().unwrap_or("");
+
+    match (method, path) {
+        ("GET", "/ports") => respond(&mut stream, "200 OK", &ports_json()),
+        ("GET", "/job") => {
+            let body = job_json(&server.job.lock().unwrap());
+            respond(&mut stream, "200 OK", &body)
+        }
+        ("GET", "/events") => stream_events(server, &mut stream),
+        ("POST", "/dump" | "/flash" | "/restore") if !authorized =>
+            respond(&mut stream, "401 Unauthorized", r#"{"error": "missing or wrong token"}"#),
+        ("POST", "/dump" | "/flash" | "/restore") => match start_job(server, &path[1..], target) {
+            Ok(()) => respond(&mut stream, "202 Accepted", r#"{"started": true}"#),
+            Err((status, reason)) => respond(&mut stream, status, &format!(r#"{{"error": {}}}"#, json_string(&reason)))
+        },
+        _ => respond(&mut stream, "404 Not Found", r#"{"error": "unknown endpoint"}"#)
+    }
+}
+
+/// Serves the API until the process is stopped, running jobs with operation
+pub fn run(listen: &str, config: &Config, operation: fn(&Args) -> bool) {
+    let listener = match TcpListener::bind(listen) {
+        Ok(l) => l,
+        Err(e) => panic!("Failed to listen on {}: {}", listen, e)
+    };
+    let token = new_token();
+    output::info(&format!("Listening on http://{}", listen));
+    output::info(&format!("Start jobs with the header \"Authorization: Bearer {}\"", token));
+
+    let server = Arc::new(Server { config: config.clone(), token, operation, job: Mutex::new(Job::default()) });
+    for stream in listener.incoming().flatten() {
+        let server = Arc::clone(&server);
+        thread::spawn(move || handle(&server, stream));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn files_stay_inside_the_directory() {
+        let dir = Path::new("/srv/rt890");
+        assert_eq!(confine(dir, "radio.bin"), Some(PathBuf::from("/srv/rt890/radio.bin")));
+        assert_eq!(confine(dir, "club/radio.bin"), Some(PathBuf::from("/srv/rt890/club/radio.bin")));
+        for file in ["", "/etc/passwd", "../radio.bin", "club/../../radio.bin", "./radio.bin"] {
+            assert_eq!(confine(dir, file), None, "{}", file)
+        }
+    }
+
+    #[test]
+    fn tokens() {
+        assert!(token_matches("0123abcd", "0123abcd"));
+        assert!(!token_matches("0123abce", "0123abcd"));
+        assert!(!token_matches("0123abc", "0123abcd"));
+        assert!(!token_matches("", "0123abcd"))
+    }
+}
@@ -0,0 +1,191 @@
+/*
+    Copyright 2024 Bricky
+    https://github.com/bricky149
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+*/
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+
+use crate::transport::Transport;
+use crate::uart::{self, CHUNK_LENGTH};
+
+// A recording is this magic followed by records of a kind byte, a
+// little-endian u32 length and that many bytes
+const MAGIC: &[u8] = b"RT890REC";
+const TO_RADIO: u8 = b'>';
+const FROM_RADIO: u8 = b'<';
+const CLEARED: u8 = b'C';
+const FAILED: u8 = b'E';
+
+/// Saves everything sent and received through a transport to a file
+pub struct Recorder<T: Transport> {
+    inner: T,
+    log: File
+}
+
+impl<T: Transport> Recorder<T> {
+    pub fn new(inner: T, filename: &String) -> io::Result<Self> {
+        let mut log = File::create(filename)?;
+        log.write_all(MAGIC)?;
+        Ok(Recorder { inner, log })
+    }
+
+    /// Carries on an existing recording, e.g. after the port was reopened
+    pub fn append(inner: T, filename: &String) -> io::Result<Self> {
+        let log = OpenOptions::new().append(true).open(filename)?;
+        Ok(Recorder { inner, log })
+    }
+
+    fn record(&mut self, kind: u8, data: &[u8]) -> io::Result<()> {
+        self.log.write_all(&[kind])?;
+        self.log.write_all(&(data.len() as u32).to_le_bytes())?;
+        self.log.write_all(data)
+    }
+}
+
+impl<T: Transport> Read for Recorder<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.inner.read(buf) {
+            Ok(n) => {
+                self.record(FROM_RADIO, &buf[..n])?;
+                Ok(n)
+            }
+            Err(e) => {
+                self.record(FAILED, e.to_string().as_bytes())?;
+                Err(e)
+            }
+        }
+    }
+}
+
+impl<T: Transport> Write for Recorder<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.record(TO_RADIO, &buf[..n])?;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<T: Transport> Transport for Recorder<T> {
+    fn clear_input(&mut self) -> io::Result<()> {
+        self.record(CLEARED, &[])?;
+        self.inner.clear_input()
+    }
+
+    fn set_control_lines(&mut self, level: bool) -> io::Result<()> {
+        self.inner.set_control_lines(level)
+    }
+}
+
+/// One command and everything received before the next one was sent
+pub struct Exchange {
+    pub command: Vec<u8>,
+    pub response: Vec<u8>,
+    pub error: Option<String>
+}
+
+/// Splits a recording back into exchanges, or None if it is not a recording
+pub fn load(data: &[u8]) -> Option<Vec<Exchange>> {
+    let mut rest = data.strip_prefix(MAGIC)?;
+    let mut exchanges: Vec<Exchange> = Vec::new();
+    let mut last_kind = 0;
+
+    while !rest.is_empty() {
+        let kind = rest[0];
+        let length = u32::from_le_bytes(rest.get(1..5)?.try_into().ok()?) as usize;
+        let bytes = rest.get(5..5 + length)?;
+        rest = &rest[5 + length..];
+
+        // Bytes received before any command still get an exchange of their own
+        if exchanges.is_empty() || (kind == TO_RADIO && last_kind != TO_RADIO) {
+            exchanges.push(Exchange { command: Vec::new(), response: Vec::new(), error: None })
+        }
+        let exchange = exchanges.last_mut()?;
+
+        match kind {
+            TO_RADIO => exchange.command.extend_from_slice(bytes),
+            FROM_RADIO => exchange.response.extend_from_slice(bytes),
+            FAILED => exchange.error = Some(String::from_utf8_lossy(bytes).into_owned()),
+            CLEARED => (),
+            _ => return None
+        }
+        last_kind = kind
+    }
+
+    Some(exchanges)
+}
+
+fn describe_ack(response: &[u8]) -> String {
+    match response {
+        [] => String::from("no response"),
+        [0x06] => String::from("acknowledged"),
+        _ => format!("refused with {:02x?}", response)
+    }
+}
+
+/// Decodes an exchange the same way the protocol code would have seen it
+pub fn describe(exchange: &Exchange) -> String {
+    let command = &exchange.command;
+    let mut text = match command.first() {
+        None => String::from("Unsolicited data"),
+        Some(0x39) => format!("Erase MCU flash: {}", describe_ack(&exchange.response)),
+        Some(0x57) if command.len() > 4 => format!("Write {} bytes to MCU flash at {:#06x}: {}",
+            command.len() - 4, (command[1] as usize) << 8 | command[2] as usize, describe_ack(&exchange.response)),
+        Some(0x40..=0x4c) if command.len() > 4 => format!("Write SPI flash command {:#04x} block {:#06x}: {}",
+            command[0], (command[1] as usize) << 8 | command[2] as usize, describe_ack(&exchange.response)),
+        Some(0x52) if command.len() == 4 => {
+            let block = (command[1] as usize) << 8 | command[2] as usize;
+            let expected = CHUNK_LENGTH + 4;
+            let response = &exchange.response;
+            let status = if response.len() < expected {
+                format!("{} of {} bytes received", response.len(), expected)
+            } else if uart::verify(&response[response.len() - expected..]) {
+                format!("{} bytes, checksum ok", response.len())
+            } else {
+                format!("{} bytes, checksum mismatch", response.len())
+            };
+            format!("Read SPI flash block {:#06x}: {}", block, status)
+        }
+        Some(cmd) => format!("Unknown command {:#04x}, {} bytes sent, {} received", cmd, command.len(), exchange.response.len())
+    };
+
+    if !command.is_empty() && !uart::verify(command) {
+        text.push_str(" (command checksum mismatch)")
+    }
+    if let Some(e) = &exchange.error {
+        text.push_str(&format!(" ({})", e))
+    }
+    text
+}
+
+pub fn replay(filename: &String) -> bool {
+    let data = match fs::read(filename) {
+        Ok(d) => d,
+        Err(e) => panic!("{}", e)
+    };
+    let exchanges = match load(&data) {
+        Some(e) => e,
+        None => return false
+    };
+
+    for (i, exchange) in exchanges.iter().enumerate() {
+        println!("{:6} {}", i + 1, describe(exchange))
+    }
+    true
+}
@@ -0,0 +1,202 @@
+/*
+    Copyright 2024 Bricky
+    https://github.com/bricky149
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+*/
+
+use std::io::{self, Write};
+use std::time::Duration;
+
+use crate::args::{self, Args};
+use crate::connect::{open_port, record_session};
+use crate::hex;
+use crate::spi::SPI_RANGES;
+use crate::transport::Transport;
+use crate::uart::{self, BAUD_RATE, CHUNK_LENGTH};
+
+const HELP: &str = "read ADDR          Show the SPI flash chunk holding ADDR (normal mode)
+write ADDR BYTES   Change SPI flash bytes starting at ADDR (normal mode)
+erase              Erase MCU flash (bootloader mode)
+send BYTES         Send a raw command, checksum added, and show the response
+ident              Report which mode the radio appears to be in
+history            List commands entered so far
+help               Show this list
+quit               Leave the shell
+
+Addresses may be decimal or 0x-prefixed hex, bytes are hex pairs.";
+
+fn parse_address(arg: Option<&str>) -> Option<usize> {
+    args::parse_number(arg?)
+}
+
+fn print_dump(data: &[u8], base: usize) {
+    for line in hex::dump(data, base) {
+        println!("{}", line)
+    }
+}
+
+fn read(port: &mut dyn Transport, address: usize) -> Option<Vec<u8>> {
+    match uart::command_readspiflash(port, (address / CHUNK_LENGTH) as u16) {
+        Ok(Some(data)) => Some(data),
+        Ok(None) => {
+            println!("Response failed checksum verification");
+            None
+        }
+        Err(e) => {
+            println!("{}", e);
+            None
+        }
+    }
+}
+
+// Chunks can only be written whole, so read the chunk, patch it and write it back
+fn write(port: &mut dyn Transport, address: usize, bytes: &[u8]) {
+    let start = address - address % CHUNK_LENGTH;
+    if address + bytes.len() > start + CHUNK_LENGTH {
+        println!("Bytes must all fall within one {}-byte chunk", CHUNK_LENGTH);
+        return
+    }
+    let range = match SPI_RANGES.iter().find(|r| (r.offset..r.offset + r.size).contains(&start)) {
+        Some(r) => r,
+        None => {
+            println!("Address {:#08x} is not in a writable region", address);
+            return
+        }
+    };
+
+    let mut chunk = match read(port, start) {
+        Some(c) => c,
+        None => return
+    };
+    chunk[address - start..address - start + bytes.len()].copy_from_slice(bytes);
+
+    // Writes index into a full image, so place the chunk where it belongs
+    let mut image = vec![0xFF; start + CHUNK_LENGTH];
+    image[start..].copy_from_slice(&chunk);
+    match uart::command_writespiflash(port, range, start, &image) {
+        Ok(true) => print_dump(&chunk, start),
+        Ok(false) => println!("Radio refused the write"),
+        Err(e) => println!("{}", e)
+    }
+}
+
+fn send(port: &mut dyn Transport, payload: &[u8]) {
+    match uart::command_raw(port, payload) {
+        Ok(r) if r.is_empty() => println!("No response"),
+        Ok(r) => {
+            print_dump(&r, 0);
+            if r.len() > 1 {
+                println!("Checksum {}", if uart::verify(&r) { "valid" } else { "invalid" })
+            }
+        }
+        Err(e) => println!("{}", e)
+    }
+}
+
+fn ident(port: &mut dyn Transport) {
+    // Only normal mode firmware answers SPI flash reads
+    match uart::command_readspiflash(port, 0) {
+        Ok(Some(_)) => println!("Radio answers SPI flash reads, so it is in normal mode"),
+        _ => println!("No answer to SPI flash reads. The radio is off, in bootloader mode or on another port.")
+    }
+}
+
+pub fn run(port: &mut dyn Transport) {
+    println!("Type help for a list of commands");
+    let mut history: Vec<String> = Vec::new();
+
+    loop {
+        print!("rt890> ");
+        io::stdout().flush().expect("Failed to write to stdout");
+
+        let mut line = String::new();
+        match io::stdin().read_line(&mut line) {
+            Ok(0) => break,
+            Ok(_) => (),
+            Err(e) => panic!("{}", e)
+        }
+
+        let line = line.trim();
+        if line.is_empty() {
+            continue
+        }
+        history.push(line.to_string());
+
+        let mut words = line.split_whitespace();
+        let command = words.next().unwrap_or_default();
+        let rest: Vec<&str> = words.collect();
+
+        match command {
+            "read" => match parse_address(rest.first().copied()) {
+                Some(address) => {
+                    let start = address - address % CHUNK_LENGTH;
+                    if let Some(data) = read(port, start) {
+                        print_dump(&data, start)
+                    }
+                }
+                None => println!("Usage: read ADDR")
+            },
+            "write" => match (parse_address(rest.first().copied()), hex::parse(&rest.iter().skip(1).copied().collect::<String>())) {
+                (Some(address), Some(bytes)) => write(port, address, &bytes),
+                _ => println!("Usage: write ADDR BYTES")
+            },
+            "erase" => match uart::command_eraseflash(port) {
+                Ok(true) => println!("MCU flash erased"),
+                Ok(false) => println!("Radio refused to erase"),
+                Err(e) => println!("{}", e)
+            },
+            "send" => match hex::parse(&rest.concat()) {
+                Some(payload) => send(port, &payload),
+                None => println!("Usage: send BYTES")
+            },
+            "ident" => ident(port),
+            "history" => {
+                for (i, entry) in history.iter().enumerate() {
+                    println!("{:4}  {}", i + 1, entry)
+                }
+            }
+            "help" => println!("{}", HELP),
+            "quit" | "exit" => break,
+            _ => println!("Unknown command, type help for a list")
+        }
+    }
+}
+
+pub fn send_raw(args: &Args) -> bool {
+    let payload = match args.send.as_deref().and_then(hex::parse) {
+        Some(p) => p,
+        None => return false
+    };
+
+    let serial = open_port(&args.port, BAUD_RATE, args.timeout.unwrap_or(Duration::from_secs(1)));
+    let mut port = record_session(serial, &args.record);
+    let response = match uart::command_raw(port.as_mut(), &payload) {
+        Ok(r) => r,
+        Err(e) => panic!("{}", e)
+    };
+
+    match response.as_slice() {
+        [] => println!("No response"),
+        [0x06] => println!("Acknowledged (06)"),
+        [b] => println!("Single byte {:02x}", b),
+        _ => {
+            for line in hex::dump(&response, 0) {
+                println!("{}", line)
+            }
+            let status = if uart::verify(&response) { "valid" } else { "invalid" };
+            println!("{} bytes, checksum {}", response.len(), status)
+        }
+    }
+    true
+}
@@ -1,5 +1,34 @@
 pub struct SpiRange {
+    pub name: &'static str,
     pub cmd: u8,
     pub offset: usize,
     pub size: usize
 }
+
+pub const SPI_FLASH_SIZE: usize = 4_194_304;
+// Reads address 128-byte blocks with a 16-bit number, so 8 MiB is the most
+// any radio can have dumped. Only the ranges below can be written back.
+pub const SPI_FLASH_SIZES: [usize; 2] = [SPI_FLASH_SIZE, 8_388_608];
+
+// TODO: Document these magic command bytes
+pub const CALIBRATION: SpiRange = SpiRange { name: "calibration", cmd: 0x48, offset: 3928064, size: 4096 };   // 3BF000 Calibration data
+pub const CHANNELS: SpiRange = SpiRange { name: "channels", cmd: 0x49, offset: 3936256, size: 40960 };     // 3C1000 Channel memories
+
+// Ranges whose contents are not known yet are named after their command byte
+pub const SPI_RANGES: [SpiRange; 9] = [
+    SpiRange { name: "range-40", cmd: 0x40, offset: 0, size: 2949120 },
+    SpiRange { name: "range-41", cmd: 0x41, offset: 2949120, size: 163840 },
+    SpiRange { name: "range-42", cmd: 0x42, offset: 3112960, size: 139264 },
+    SpiRange { name: "range-43", cmd: 0x43, offset: 3252224, size: 8192 },
+    SpiRange { name: "range-47", cmd: 0x47, offset: 3887104, size: 40960 },
+    CALIBRATION,
+    CHANNELS,
+    SpiRange { name: "range-4b", cmd: 0x4b, offset: 4030464, size: 40960 },
+    SpiRange { name: "range-4c", cmd: 0x4c, offset: 3260416, size: 626688 }
+];
+
+/// Finds the range that restores a given byte of a full dump, if any
+pub fn range_at(offset: usize) -> Option<&'static SpiRange> {
+    let ranges: &'static [SpiRange] = &SPI_RANGES;
+    ranges.iter().find(|r| (r.offset..r.offset + r.size).contains(&offset))
+}
@@ -0,0 +1,180 @@
+/*
+    Copyright 2024 Bricky
+    https://github.com/bricky149
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+*/
+
+extern crate nix;
+use nix::fcntl::{flock, FlockArg};
+
+extern crate serialport5;
+use self::serialport5::*;
+
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::process::{self, Command};
+use std::time::Duration;
+
+/// Anything protocol commands can be sent over
+pub trait Transport: Read + Write {
+    /// Throws away anything received that has not been read yet
+    fn clear_input(&mut self) -> io::Result<()>;
+
+    /// Raises or lowers DTR and RTS, where there are such lines to set
+    fn set_control_lines(&mut self, _level: bool) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Transport for SerialPort {
+    fn clear_input(&mut self) -> io::Result<()> {
+        self.clear(ClearBuffer::Input)?;
+        Ok(())
+    }
+
+    fn set_control_lines(&mut self, level: bool) -> io::Result<()> {
+        self.write_data_terminal_ready(level)?;
+        self.write_request_to_send(level)?;
+        Ok(())
+    }
+}
+
+impl Transport for Box<dyn Transport> {
+    fn clear_input(&mut self) -> io::Result<()> {
+        (**self).clear_input()
+    }
+
+    fn set_control_lines(&mut self, level: bool) -> io::Result<()> {
+        (**self).set_control_lines(level)
+    }
+}
+
+/// Takes an advisory lock on an open serial port, held until it is closed,
+/// so two programs cannot talk to the radio at once
+pub fn lock(port: &SerialPort) -> io::Result<()> {
+    flock(port.as_raw_fd(), FlockArg::LockExclusiveNonblock)?;
+    Ok(())
+}
+
+// lsof lists each process as a p line with its ID, then a c line with its name
+fn lsof_holders(port: &str) -> Vec<String> {
+    let output = match Command::new("lsof").args(["-F", "pc", port]).output() {
+        Ok(o) => o,
+        Err(_) => return Vec::new()
+    };
+    let own = process::id().to_string();
+    let mut found = Vec::new();
+    let mut pid = None;
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        if let Some(id) = line.strip_prefix('p') {
+            pid = Some(id.to_string())
+        } else if let (Some(name), Some(id)) = (line.strip_prefix('c'), pid.take()) {
+            if id != own {
+                found.push(format!("{} ({})", id, name))
+            }
+        }
+    }
+    found
+}
+
+/// Other processes with a port open, e.g. "1234 (minicom)". Linux has /proc
+/// to look in; elsewhere lsof is asked, if it is installed.
+pub fn holders(port: &str) -> Vec<String> {
+    let device = match fs::canonicalize(port) {
+        Ok(d) => d,
+        Err(_) => return Vec::new()
+    };
+    let own = process::id().to_string();
+    let processes = match fs::read_dir("/proc") {
+        Ok(p) => p,
+        Err(_) => return lsof_holders(port)
+    };
+
+    let mut found = Vec::new();
+    for pid in processes.filter_map(|e| e.ok()).map(|e| e.file_name().to_string_lossy().into_owned()) {
+        if pid == own || !pid.bytes().all(|b| b.is_ascii_digit()) {
+            continue
+        }
+        // Processes of other users cannot be looked into without root
+        let fds = match fs::read_dir(Path::new("/proc").join(&pid).join("fd")) {
+            Ok(f) => f,
+            Err(_) => continue
+        };
+        if fds.filter_map(|e| e.ok()).any(|fd| fs::read_link(fd.path()).is_ok_and(|target| target == device)) {
+            let name = fs::read_to_string(Path::new("/proc").join(&pid).join("comm")).unwrap_or_default();
+            found.push(format!("{} ({})", pid, name.trim()))
+        }
+    }
+    found
+}
+
+/// A serial port shared over the network as a raw TCP socket, e.g. by
+/// ser2net in raw mode. RFC 2217 control is not negotiated, so the remote
+/// end must already be set to 115200 baud.
+pub struct TcpTransport {
+    stream: TcpStream
+}
+
+pub fn connect(address: &str, timeout: Duration) -> io::Result<TcpTransport> {
+    let address = address.to_socket_addrs()?
+        .next()
+        .ok_or(io::Error::new(io::ErrorKind::NotFound, "address did not resolve"))?;
+    let stream = TcpStream::connect_timeout(&address, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    // Frames are small and every one waits for a reply
+    stream.set_nodelay(true)?;
+    Ok(TcpTransport { stream })
+}
+
+impl Read for TcpTransport {
+    // Sockets report a read timeout as WouldBlock on some systems, but the
+    // protocol code expects TimedOut as serial ports give
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.stream.read(buf) {
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Err(io::Error::new(io::ErrorKind::TimedOut, e)),
+            result => result
+        }
+    }
+}
+
+impl Write for TcpTransport {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+impl Transport for TcpTransport {
+    // Drains whatever has already arrived without waiting for more
+    fn clear_input(&mut self) -> io::Result<()> {
+        self.stream.set_nonblocking(true)?;
+        let mut buffer = [0u8; 256];
+        let result = loop {
+            match self.stream.read(&mut buffer) {
+                Ok(0) => break Ok(()),
+                Ok(_) => continue,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break Ok(()),
+                Err(e) => break Err(e)
+            }
+        };
+        self.stream.set_nonblocking(false)?;
+        result
+    }
+}
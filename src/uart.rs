@@ -18,32 +18,130 @@
 extern crate serialport5;
 use self::serialport5::*;
 
-use std::io::{Read, Write};
+extern crate tracing;
+use self::tracing::{debug, trace, warn};
+
+use std::io;
+use std::path::Path;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use crate::hex;
+use crate::output::json_string;
 use crate::spi::SpiRange;
+use crate::transport::Transport;
+
+/// Rate both the bootloader and stock firmware listen at
+pub const BAUD_RATE: u32 = 115_200;
+/// Data bytes carried by every SPI flash and MCU flash frame
+pub const CHUNK_LENGTH: usize = 128;
+
+const READ_RETRIES: usize = 5;
+
+static CHUNKS: AtomicUsize = AtomicUsize::new(0);
+static RETRIES: AtomicUsize = AtomicUsize::new(0);
+static CHECKSUM_ERRORS: AtomicUsize = AtomicUsize::new(0);
+static BAD_FRAME: Mutex<Option<BadFrame>> = Mutex::new(None);
+
+// The last exchange that went wrong, kept until one goes right so a failure
+// can say what the radio actually sent
+enum BadFrame {
+    /// A reply whose echoed header or checksum did not match
+    Corrupt { address: usize, header: [u8; 3], frame: Vec<u8> },
+    /// Anything but an acknowledgement to a write
+    Refused { command: [u8; 3], response: u8 }
+}
+
+const BAD_FRAME_HINT: &str = "Corrupt or refused frames usually come from a loose or overlong cable, \
+    a USB adapter dropping bytes when its buffer fills, or another program reading the port. \
+    Try another cable or port, or --inter-chunk-delay for writes.";
+
+/// Number of chunks read or written successfully so far
+pub fn chunks() -> usize {
+    CHUNKS.load(Ordering::SeqCst)
+}
+
+/// Number of frames that had to be realigned or requested again so far
+pub fn retries() -> usize {
+    RETRIES.load(Ordering::SeqCst)
+}
+
+/// Number of frames received with a bad checksum so far
+pub fn checksum_errors() -> usize {
+    CHECKSUM_ERRORS.load(Ordering::SeqCst)
+}
+
+fn record(frame: Option<BadFrame>) {
+    *BAD_FRAME.lock().unwrap() = frame
+}
+
+/// Lines explaining the last frame if the last exchange with the radio went
+/// wrong: what was expected, what came back and the frame itself
+pub fn describe_bad_frame() -> Option<Vec<String>> {
+    let mut lines = match BAD_FRAME.lock().unwrap().as_ref()? {
+        BadFrame::Corrupt { address, header, frame } => {
+            let received = *frame.last()?;
+            let mut lines = vec![
+                format!("The reply for address {:#08x} was corrupt:", address),
+                format!("  header    expected {}, received {}", hex::to_string(header), hex::to_string(&frame[..3])),
+                format!("  checksum  expected {:#04x}, received {:#04x}", sum(&frame[..frame.len() - 1]), received),
+                String::from("  frame as received:")
+            ];
+            lines.extend(hex::dump(frame, 0).into_iter().map(|line| format!("    {}", line)));
+            lines
+        }
+        BadFrame::Refused { command, response } => vec![format!(
+            "The radio answered {:#04x} instead of 0x06 (acknowledged) to the write starting {}",
+            response, hex::to_string(command))]
+    };
+    lines.push(String::from(BAD_FRAME_HINT));
+    Some(lines)
+}
+
+pub fn reset_counters() {
+    CHUNKS.store(0, Ordering::SeqCst);
+    RETRIES.store(0, Ordering::SeqCst);
+    CHECKSUM_ERRORS.store(0, Ordering::SeqCst)
+}
 
-const CHUNK_LENGTH: usize = 128;
+fn acknowledged(command: &[u8], response: [u8; 1]) -> Result<bool> {
+    match response {
+        [0x06] => {
+            CHUNKS.fetch_add(1, Ordering::SeqCst);
+            record(None);
+            Ok(true)
+        }
+        [other] => {
+            record(Some(BadFrame::Refused { command: [command[0], command[1], command[2]], response: other }));
+            Ok(false)
+        }
+    }
+}
+
+fn sum(bytes: &[u8]) -> u8 {
+    // An 8-bit sum that wraps, as the radio's does
+    bytes.iter().fold(0, |sum, byte| sum.wrapping_add(*byte))
+}
 
 fn checksum(command: &mut [u8]) {
     let last_idx = command.len() - 1;
-    let mut sum = 0;
-    // Relies on arithmetic overflows
-    for byte in command.iter().take(last_idx) {
-        sum += byte
-    }
-    command[last_idx] = sum
+    command[last_idx] = sum(&command[..last_idx])
 }
 
-fn verify(command: &[u8]) -> bool {
+pub fn verify(command: &[u8]) -> bool {
     let last_idx = command.len() - 1;
-    let mut calculated_sum = 0;
-    // Relies on arithmetic overflows
-    for byte in command.iter().take(last_idx) {
-        calculated_sum += byte
-    }
-    command[last_idx] == calculated_sum
+    command[last_idx] == sum(&command[..last_idx])
 }
 
-pub fn command_eraseflash(mut port: &SerialPort) -> Result<bool> {
+fn corrupt(offset: u16, command: &[u8], block: &[u8]) {
+    CHECKSUM_ERRORS.fetch_add(1, Ordering::SeqCst);
+    record(Some(BadFrame::Corrupt {
+        address: offset as usize * CHUNK_LENGTH,
+        header: [command[0], command[1], command[2]],
+        frame: block.to_vec()
+    }))
+}
+
+pub fn command_eraseflash(port: &mut dyn Transport) -> Result<bool> {
     let mut command = [0u8; 5];
     command[0] = 0x39;
     command[3] = 0x55;
@@ -53,74 +151,261 @@ pub fn command_eraseflash(mut port: &SerialPort) -> Result<bool> {
 
     let mut response = [0u8];
     port.read_exact(&mut response)?;
+    debug!(response = response[0], "eraseflash");
     match response {
         [0x06] => Ok(true),
         _ => Ok(false)
     }
 }
 
-pub fn command_writeflash(mut port: &SerialPort, offset: usize, fw: &[u8]) -> Result<bool> {
-    let mut command = [0u8; 132];
+pub fn command_writeflash(port: &mut dyn Transport, offset: usize, fw: &[u8]) -> Result<bool> {
+    let mut command = [0u8; CHUNK_LENGTH + 4];
     command[0] = 0x57;
     command[1] = ((offset >> 8) & 0xFF) as u8;
     command[2] = ((offset) & 0xFF) as u8;
-    command[3..131].copy_from_slice(&fw[offset..offset+CHUNK_LENGTH]);
+    command[3..CHUNK_LENGTH+3].copy_from_slice(&fw[offset..offset+CHUNK_LENGTH]);
 
     checksum(&mut command);
     port.write_all(&command)?;
 
     let mut response = [0u8];
     port.read_exact(&mut response)?;
-    match response {
-        [0x06] => Ok(true),
-        _ => Ok(false)
-    }
+    trace!(offset, response = response[0], "writeflash");
+    acknowledged(&command, response)
 }
 
-pub fn command_readspiflash(mut port: &SerialPort, offset: u16) -> Result<Option<Vec<u8>>> {
+fn readspiflash(offset: u16) -> [u8; 4] {
     let mut command = [0u8; 4];
     command[0] = 0x52;
     command[1] = ((offset >> 8) & 0xFF) as u8;
     command[2] = ((offset) & 0xFF) as u8;
 
     checksum(&mut command);
+    command
+}
+
+/// Asks for a block without waiting for it, so the next can be asked for
+/// while this one is still arriving
+pub fn request_readspiflash(port: &mut dyn Transport, offset: u16) -> Result<()> {
+    trace!(block = offset, "request readspiflash");
+    port.write_all(&readspiflash(offset))?;
+    Ok(())
+}
+
+/// Reads the reply to request_readspiflash, or None if it did not arrive
+/// intact. Nothing is retried, as the next reply may already be on its way.
+pub fn receive_readspiflash(port: &mut dyn Transport, offset: u16) -> Result<Option<Vec<u8>>> {
+    let command = readspiflash(offset);
+    let mut block = [0u8; CHUNK_LENGTH + 4];
+    match port.read_exact(&mut block) {
+        Ok(()) => (),
+        Err(e) if e.kind() == io::ErrorKind::TimedOut => {
+            warn!(block = offset, "readspiflash reply timed out");
+            return Ok(None)
+        }
+        Err(e) => return Err(e.into())
+    }
+
+    if block.starts_with(&command[..3]) && verify(&block) {
+        trace!(block = offset, "receive readspiflash");
+        CHUNKS.fetch_add(1, Ordering::SeqCst);
+        record(None);
+        return Ok(Some(block[3..CHUNK_LENGTH+3].to_vec()))
+    }
+    // The whole frame is logged so --log-file keeps it without asking
+    warn!(block = offset, header = ?&block[..3], frame = %hex::to_string(&block), "readspiflash reply corrupt");
+    corrupt(offset, &command, &block);
+    Ok(None)
+}
+
+pub fn command_readspiflash(port: &mut dyn Transport, offset: u16) -> Result<Option<Vec<u8>>> {
+    let command = readspiflash(offset);
     port.write_all(&command)?;
 
-    let mut block = [0u8; 132];
-    port.read_exact(&mut block)?;
-    if !verify(&block) {
-        // Sometimes returns no data on first run
-        port.read_exact(&mut block)?;
+    let mut block = [0u8; CHUNK_LENGTH + 4];
+    let mut received = read_frame(port, &mut block)?;
+
+    let mut retries = 0;
+    loop {
+        if received && block.starts_with(&command[..3]) && verify(&block) {
+            trace!(block = offset, retries, "readspiflash");
+            let data = block[3..CHUNK_LENGTH+3].to_vec();
+            CHUNKS.fetch_add(1, Ordering::SeqCst);
+            record(None);
+            return Ok(Some(data))
+        }
+        if received {
+            warn!(block = offset, attempt = retries + 1, header = ?&block[..3], frame = %hex::to_string(&block),
+                "readspiflash reply corrupt");
+            corrupt(offset, &command, &block)
+        } else {
+            warn!(block = offset, attempt = retries + 1, "readspiflash reply timed out")
+        }
+        if retries == READ_RETRIES {
+            warn!(block = offset, "readspiflash gave up");
+            return Ok(None)
+        }
+        retries += 1;
+        RETRIES.fetch_add(1, Ordering::SeqCst);
+
+        // Sometimes returns no data on first run or drops a byte mid-frame.
+        // What arrived of a frame that timed out cannot be trusted to realign.
+        received = (received && realign(port, &command[..3], &mut block)?) || resync(port, &command, &mut block)?
     }
+}
 
-    if verify(&block) {
-        let data = block[3..131].to_vec();
-        return Ok(Some(data))
+// Reads a whole frame, or returns false if the radio fell silent part way
+fn read_frame(port: &mut dyn Transport, block: &mut [u8]) -> Result<bool> {
+    match port.read_exact(block) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::TimedOut => Ok(false),
+        Err(e) => Err(e.into())
     }
+}
 
-    Ok(None)
+// Shifts a misaligned frame so it starts at the echoed command header,
+// then reads whatever bytes are still missing from the end of it
+fn realign(port: &mut dyn Transport, header: &[u8], block: &mut [u8]) -> Result<bool> {
+    let start = (1..=block.len() - header.len())
+        .find(|&i| block[i..].starts_with(header));
+
+    let start = match start {
+        Some(i) => i,
+        None => return Ok(false)
+    };
+    debug!(shift = start, "realigning frame");
+
+    block.copy_within(start.., 0);
+    let filled = block.len() - start;
+    read_frame(port, &mut block[filled..])
+}
+
+// Throws away anything left in the input buffer and asks for the frame again
+fn resync(port: &mut dyn Transport, command: &[u8], block: &mut [u8]) -> Result<bool> {
+    debug!("clearing input and asking again");
+    port.clear_input()?;
+    port.write_all(command)?;
+    read_frame(port, block)
 }
 
-pub fn command_writespiflash(mut port: &SerialPort, spi_range: &SpiRange, offset: usize, spi: &[u8]) -> Result<bool> {
-    let block_offset = (offset - spi_range.offset) / 128;
+pub fn command_writespiflash(port: &mut dyn Transport, spi_range: &SpiRange, offset: usize, spi: &[u8]) -> Result<bool> {
+    let block_offset = (offset - spi_range.offset) / CHUNK_LENGTH;
 
-    let mut command = [0u8; 132];
+    let mut command = [0u8; CHUNK_LENGTH + 4];
     command[0] = spi_range.cmd;
     command[1] = ((block_offset >> 8) & 0xFF) as u8;
     command[2] = ((block_offset) & 0xFF) as u8;
-    command[3..131].copy_from_slice(&spi[offset..offset+CHUNK_LENGTH]);
+    command[3..CHUNK_LENGTH+3].copy_from_slice(&spi[offset..offset+CHUNK_LENGTH]);
 
     checksum(&mut command);
     port.write_all(&command)?;
 
     let mut response = [0u8];
     port.read_exact(&mut response)?;
-    match response {
-        [0x06] => Ok(true),
-        _ => Ok(false)
+    trace!(cmd = spi_range.cmd, block = block_offset, response = response[0], "writespiflash");
+    acknowledged(&command, response)
+}
+
+// Stock firmware only answers the SPI flash commands above, and no build is
+// known to give access to the BK4819's registers or report RSSI over UART.
+// Register and RSSI commands belong here once a firmware defines them, and a
+// monitor command could then poll the latter; until then raw can send them.
+
+/// Sends any command with its checksum appended and returns everything
+/// received until the read timeout expires
+pub fn command_raw(port: &mut dyn Transport, payload: &[u8]) -> Result<Vec<u8>> {
+    let mut command = payload.to_vec();
+    command.push(0);
+
+    checksum(&mut command);
+    port.write_all(&command)?;
+
+    let mut response = Vec::new();
+    let mut buffer = [0u8; 256];
+    loop {
+        match port.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(n) => response.extend_from_slice(&buffer[..n]),
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => break,
+            Err(e) => return Err(e.into())
+        }
     }
+    debug!(command = ?payload, response = ?response, "raw");
+    Ok(response)
+}
+
+// USB serial chips found in RT-890 programming cables
+const KNOWN_CABLES: [(u16, u16, &str); 4] = [
+    (0x1a86, 0x7523, "CH340"),
+    (0x067b, 0x2303, "PL2303"),
+    (0x10c4, 0xea60, "CP210x"),
+    (0x0403, 0x6001, "FT232")
+];
+
+/// Names the chip of a USB device that looks like a programming cable
+pub fn known_cable(vid: u16, pid: u16) -> Option<&'static str> {
+    KNOWN_CABLES.iter()
+        .find(|(v, p, _)| *v == vid && *p == pid)
+        .map(|(_, _, chip)| *chip)
+}
+
+/// Names the chip of a port that looks like a programming cable
+pub fn cable_chip(info: &UsbPortInfo) -> Option<&'static str> {
+    known_cable(info.vid, info.pid)
+}
+
+/// Whether a port is a Bluetooth serial link, e.g. /dev/rfcomm0
+pub fn is_bluetooth(port: &str) -> bool {
+    port.starts_with("/dev/rfcomm") || serialport5::available_ports().is_ok_and(|ports| {
+        ports.iter().any(|p| p.port_name == port && matches!(p.port_type, SerialPortType::BluetoothPort))
+    })
+}
+
+fn json_field(value: &Option<String>) -> String {
+    value.as_deref().map_or(String::from("null"), json_string)
+}
+
+/// Describes a port as a JSON object, with null for anything not known
+pub fn port_json(info: &SerialPortInfo) -> String {
+    let kind = match info.port_type {
+        SerialPortType::UsbPort(_) => "usb",
+        SerialPortType::PciPort => "pci",
+        SerialPortType::BluetoothPort => "bluetooth",
+        SerialPortType::Unknown => "unknown"
+    };
+    let usb = match &info.port_type {
+        SerialPortType::UsbPort(usb) => Some(usb),
+        _ => None
+    };
+    let chip = usb.and_then(cable_chip);
+    format!(r#"{{"name": {}, "type": "{}", "vid": {}, "pid": {}, "manufacturer": {}, "product": {}, "serial_number": {}, "chip": {}, "likely_radio": {}}}"#,
+        json_string(&info.port_name), kind,
+        usb.map_or(String::from("null"), |u| u.vid.to_string()),
+        usb.map_or(String::from("null"), |u| u.pid.to_string()),
+        json_field(&usb.and_then(|u| u.manufacturer.clone())),
+        json_field(&usb.and_then(|u| u.product.clone())),
+        json_field(&usb.and_then(|u| u.serial_number.clone())),
+        chip.map_or(String::from("null"), json_string),
+        chip.is_some())
+}
+
+// macOS lists every serial device twice. Opening the /dev/tty.* node waits
+// for carrier detect, which programming cables never raise, so it hangs,
+// while the /dev/cu.* node opens straight away.
+fn callout_name(port: &str) -> Option<String> {
+    port.strip_prefix("/dev/tty.").map(|name| format!("/dev/cu.{}", name))
+}
+
+/// The /dev/cu.* node to use instead of a macOS /dev/tty.* one, if it exists
+pub fn callout_port(port: &str) -> Option<String> {
+    callout_name(port).filter(|cu| Path::new(cu).exists())
 }
 
+/// Lists ports, leaving out macOS /dev/tty.* nodes that have a /dev/cu.* twin
 pub fn get_available_ports() -> Vec<SerialPortInfo> {
-    serialport5::available_ports().expect("No ports found")
+    let ports = serialport5::available_ports().expect("No ports found");
+    let names: Vec<String> = ports.iter().map(|p| p.port_name.clone()).collect();
+    ports.into_iter()
+        .filter(|p| !callout_name(&p.port_name).is_some_and(|cu| names.contains(&cu)))
+        .collect()
 }
@@ -0,0 +1,176 @@
+/*
+    Copyright 2024 Bricky
+    https://github.com/bricky149
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+*/
+
+// Downloads go through curl, which ships with every system this runs on,
+// rather than pulling an HTTP and TLS stack into the binary
+
+use std::env;
+use std::fs;
+use std::process::Command;
+
+use crate::digest;
+use crate::firmware;
+use crate::json;
+use crate::output::{self, confirm};
+
+const RELEASES: &str = "https://api.github.com/repos/bricky149/rt890-flash-rs/releases/latest";
+// Open Edition Firmware, the community firmware most people flash
+const FIRMWARE_RELEASES: &str = "https://api.github.com/repos/DualTachyon/radtel-rt-890-oefw/releases";
+
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// A newer release and where to download its binary for this system
+pub struct Release {
+    pub version: String,
+    binary_url: String,
+    checksum_url: String
+}
+
+/// A file attached to a firmware release
+pub struct Asset {
+    pub name: String,
+    pub url: String,
+    pub size: usize,
+    /// Only given by GitHub for files uploaded since it started keeping them
+    pub sha256: Option<String>
+}
+
+pub struct FirmwareRelease {
+    pub tag: String,
+    pub name: String,
+    pub date: String,
+    pub notes: String,
+    pub assets: Vec<Asset>
+}
+
+/// Downloads a URL into memory
+pub fn fetch(url: &str) -> Result<Vec<u8>, String> {
+    let output = Command::new("curl")
+        .args(["-fsSL", "-H", "User-Agent: rt890-flash", url])
+        .output()
+        .map_err(|e| format!("Failed to run curl: {}", e))?;
+    if !output.status.success() {
+        return Err(format!("Failed to download {}: {}", url, String::from_utf8_lossy(&output.stderr).trim()))
+    }
+    Ok(output.stdout)
+}
+
+// Release binaries are named after the target they were built for, e.g.
+// rt890-flash-linux-x86_64, each with a SHA-256 manifest alongside
+fn asset_name() -> String {
+    format!("rt890-flash-{}-{}{}", env::consts::OS, env::consts::ARCH, env::consts::EXE_SUFFIX)
+}
+
+/// Finds the latest release, if it is newer than this binary
+pub fn check() -> Result<Option<Release>, String> {
+    let json = String::from_utf8_lossy(&fetch(RELEASES)?).into_owned();
+    let release = json::parse(&json).map_err(|e| format!("Release is not valid JSON: {}", e))?;
+    let version = match release.get("tag_name").and_then(json::Value::as_str) {
+        Some(tag) => tag.to_string(),
+        None => return Err(String::from("No release found"))
+    };
+    if firmware::version_numbers(&version) <= firmware::version_numbers(VERSION) {
+        return Ok(None)
+    }
+
+    let asset = asset_name();
+    let assets = release.get("assets").map_or(&[][..], json::Value::as_array);
+    let find = |name: &str| assets.iter()
+        .find(|a| a.get("name").and_then(json::Value::as_str) == Some(name))
+        .and_then(|a| a.get("browser_download_url").and_then(json::Value::as_str))
+        .map(str::to_string);
+    match (find(&asset), find(&format!("{}.sha256", asset))) {
+        (Some(binary_url), Some(checksum_url)) => Ok(Some(Release { version, binary_url, checksum_url })),
+        (None, _) => Err(format!("Release {} has no binary for this system ({})", version, asset)),
+        (_, None) => Err(format!("Release {} has no checksum for {}, refusing to install it", version, asset))
+    }
+}
+
+/// Downloads a release, checks it against its manifest and puts it in place
+/// of the running binary
+pub fn install(release: &Release) -> Result<(), String> {
+    let binary = fetch(&release.binary_url)?;
+    let manifest = String::from_utf8_lossy(&fetch(&release.checksum_url)?).into_owned();
+    match digest::matches_manifest(&binary, &manifest) {
+        Some(true) => (),
+        Some(false) => return Err(String::from("Downloaded binary does not match its checksum, nothing was replaced")),
+        None => return Err(String::from("Release checksum is not a SHA-256 manifest"))
+    }
+
+    let current = env::current_exe().map_err(|e| e.to_string())?;
+    let new = current.with_extension("new");
+    let old = current.with_extension("old");
+    fs::write(&new, &binary).map_err(|e| format!("Failed to write {}: {}", new.display(), e))?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(&new, fs::Permissions::from_mode(0o755)).map_err(|e| e.to_string())?;
+    }
+
+    // A running binary can be renamed but not always overwritten
+    fs::rename(&current, &old).map_err(|e| format!("Failed to move {} aside: {}", current.display(), e))?;
+    if let Err(e) = fs::rename(&new, &current) {
+        let _ = fs::rename(&old, &current);
+        return Err(format!("Failed to replace {}: {}", current.display(), e))
+    }
+    let _ = fs::remove_file(&old);
+    Ok(())
+}
+
+/// Lists firmware releases, newest first
+pub fn firmware_releases() -> Result<Vec<FirmwareRelease>, String> {
+    let json = String::from_utf8_lossy(&fetch(FIRMWARE_RELEASES)?).into_owned();
+    let releases = json::parse(&json).map_err(|e| format!("Release list is not valid JSON: {}", e))?;
+    let text = |value: &json::Value, key: &str| value.get(key).and_then(json::Value::as_str).unwrap_or_default().to_string();
+
+    Ok(releases.as_array().iter().filter(|r| !matches!(r.get("draft"), Some(json::Value::Bool(true)))).map(|release| {
+        let assets = release.get("assets").map_or(&[][..], json::Value::as_array).iter().map(|asset| Asset {
+            name: text(asset, "name"),
+            url: text(asset, "browser_download_url"),
+            size: asset.get("size").and_then(json::Value::as_f64).unwrap_or_default() as usize,
+            sha256: asset.get("digest").and_then(json::Value::as_str)
+                .and_then(|d| d.strip_prefix("sha256:")).map(str::to_string)
+        }).collect();
+        FirmwareRelease {
+            tag: text(release, "tag_name"),
+            name: text(release, "name"),
+            // Only the day is worth showing
+            date: text(release, "published_at").chars().take(10).collect(),
+            notes: text(release, "body"),
+            assets
+        }
+    }).collect())
+}
+
+pub fn run(yes: bool) {
+    let release = match check() {
+        Ok(Some(release)) => release,
+        Ok(None) => {
+            output::info(&format!("Version {} is the latest", VERSION));
+            return
+        }
+        Err(e) => panic!("{}", e)
+    };
+    if !yes && !confirm(&format!("Replace version {} with {}?", VERSION, release.version)) {
+        output::info("Update cancelled");
+        return
+    }
+    match install(&release) {
+        Ok(()) => output::success(&format!("Updated to {}", release.version)),
+        Err(e) => panic!("{}", e)
+    }
+}
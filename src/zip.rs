@@ -0,0 +1,396 @@
+/*
+    Copyright 2024 Bricky
+    https://github.com/bricky149
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+*/
+
+// Firmware releases come as small zips, so reading the central directory
+// and inflating stored or deflated entries is all that is needed. Inflate
+// follows zlib's puff.c, which favours simplicity over speed.
+
+use crate::digest;
+
+const LOCAL_HEADER: u32 = 0x0403_4b50;
+const CENTRAL_HEADER: u32 = 0x0201_4b50;
+const END_OF_DIRECTORY: u32 = 0x0605_4b50;
+
+/// Whether data starts like a zip archive
+pub fn is_zip(data: &[u8]) -> bool {
+    data.len() >= 4 && read_u32(data, 0) == LOCAL_HEADER
+}
+
+pub struct Entry {
+    pub name: String,
+    pub size: usize,
+    method: u16,
+    crc: u32,
+    compressed_size: usize,
+    local_offset: usize
+}
+
+fn read_u16(data: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([data[at], data[at + 1]])
+}
+
+fn read_u32(data: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([data[at], data[at + 1], data[at + 2], data[at + 3]])
+}
+
+/// Lists the files in an archive, leaving out directories
+pub fn entries(data: &[u8]) -> Result<Vec<Entry>, String> {
+    // The end record is last, followed only by a comment of up to 64 KiB
+    let end = (0..data.len().saturating_sub(21)).rev()
+        .take(65_536 + 22)
+        .find(|&i| read_u32(data, i) == END_OF_DIRECTORY)
+        .ok_or("Archive has no central directory")?;
+    let count = read_u16(data, end + 10) as usize;
+    let mut at = read_u32(data, end + 16) as usize;
+
+    let mut entries = Vec::with_capacity(count);
+    for _ in 0..count {
+        if at + 46 > data.len() || read_u32(data, at) != CENTRAL_HEADER {
+            return Err(String::from("Archive directory is corrupt"))
+        }
+        let name_length = read_u16(data, at + 28) as usize;
+        let extra_length = read_u16(data, at + 30) as usize;
+        let comment_length = read_u16(data, at + 32) as usize;
+        let name = data.get(at + 46..at + 46 + name_length).ok_or("Archive directory is corrupt")?;
+        let name = String::from_utf8_lossy(name).into_owned();
+        if !name.ends_with('/') {
+            entries.push(Entry {
+                name,
+                size: read_u32(data, at + 24) as usize,
+                method: read_u16(data, at + 10),
+                crc: read_u32(data, at + 16),
+                compressed_size: read_u32(data, at + 20) as usize,
+                local_offset: read_u32(data, at + 42) as usize
+            })
+        }
+        at += 46 + name_length + extra_length + comment_length
+    }
+    Ok(entries)
+}
+
+/// Unpacks one file and checks it against its CRC
+pub fn extract(data: &[u8], entry: &Entry) -> Result<Vec<u8>, String> {
+    let at = entry.local_offset;
+    if at + 30 > data.len() || read_u32(data, at) != LOCAL_HEADER {
+        return Err(format!("{} is corrupt in the archive", entry.name))
+    }
+    let start = at + 30 + read_u16(data, at + 26) as usize + read_u16(data, at + 28) as usize;
+    let packed = data.get(start..start + entry.compressed_size)
+        .ok_or(format!("{} is truncated in the archive", entry.name))?;
+
+    let unpacked = match entry.method {
+        0 => packed.to_vec(),
+        8 => inflate(packed, entry.size)?,
+        method => return Err(format!("{} is packed with method {}, which is not supported", entry.name, method))
+    };
+    if unpacked.len() != entry.size {
+        return Err(format!("{} is not the size the archive gives for it", entry.name))
+    }
+    if digest::crc32(&unpacked) != entry.crc {
+        return Err(format!("{} does not match its CRC in the archive", entry.name))
+    }
+    Ok(unpacked)
+}
+
+struct Bits<'a> {
+    data: &'a [u8],
+    at: usize,
+    buffer: u32,
+    count: u32
+}
+
+impl Bits<'_> {
+    fn take(&mut self, n: u32) -> Result<u32, String> {
+        while self.count < n {
+            let byte = *self.data.get(self.at).ok_or("Deflate stream ends early")?;
+            self.buffer |= (byte as u32) << self.count;
+            self.at += 1;
+            self.count += 8
+        }
+        let value = self.buffer & ((1u32 << n) - 1);
+        self.buffer = if n == 32 { 0 } else { self.buffer >> n };
+        self.count -= n;
+        Ok(value)
+    }
+}
+
+// Canonical Huffman code given as how many codes there are of each length
+// and the symbols in code order
+struct Huffman {
+    counts: [u16; 16],
+    symbols: Vec<u16>
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Huffman {
+        let mut counts = [0u16; 16];
+        for &length in lengths {
+            counts[length as usize] += 1
+        }
+        counts[0] = 0;
+        let mut offsets = [0u16; 16];
+        for length in 1..15 {
+            offsets[length + 1] = offsets[length] + counts[length]
+        }
+        let mut symbols = vec![0u16; lengths.len()];
+        for (symbol, &length) in lengths.iter().enumerate() {
+            if length != 0 {
+                symbols[offsets[length as usize] as usize] = symbol as u16;
+                offsets[length as usize] += 1
+            }
+        }
+        Huffman { counts, symbols }
+    }
+
+    fn decode(&self, bits: &mut Bits) -> Result<u16, String> {
+        let (mut code, mut first, mut index) = (0i32, 0i32, 0i32);
+        for length in 1..16 {
+            code |= bits.take(1)? as i32;
+            let count = self.counts[length] as i32;
+            if code - count < first {
+                return Ok(self.symbols[(index + code - first) as usize])
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1
+        }
+        Err(String::from("Deflate stream has an invalid code"))
+    }
+}
+
+const LENGTH_BASE: [u16; 29] = [3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31,
+    35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258];
+const LENGTH_EXTRA: [u8; 29] = [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0];
+const DISTANCE_BASE: [u16; 30] = [1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193,
+    257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577];
+const DISTANCE_EXTRA: [u8; 30] = [0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13];
+// Order code length code lengths are sent in
+const CODE_LENGTH_ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+// Most a deflate stream can expand by, so a size given in a corrupt header
+// cannot reserve more than the stream could ever fill
+const MAX_RATIO: usize = 1032;
+
+// Output stops at size, the size the archive gives for the entry, so a
+// corrupt or malicious stream cannot fill memory
+fn inflate(packed: &[u8], size: usize) -> Result<Vec<u8>, String> {
+    let mut bits = Bits { data: packed, at: 0, buffer: 0, count: 0 };
+    let mut out = Vec::with_capacity(size.min(packed.len().saturating_mul(MAX_RATIO)));
+    loop {
+        let last = bits.take(1)? == 1;
+        match bits.take(2)? {
+            0 => stored(&mut bits, &mut out, size)?,
+            1 => {
+                let mut lengths = [8u8; 288];
+                lengths[144..256].fill(9);
+                lengths[256..280].fill(7);
+                codes(&mut bits, &mut out, size, &Huffman::new(&lengths), &Huffman::new(&[5u8; 30]))?
+            }
+            2 => {
+                let (lengths, distances) = dynamic_tables(&mut bits)?;
+                codes(&mut bits, &mut out, size, &lengths, &distances)?
+            }
+            _ => return Err(String::from("Deflate stream has an invalid block type"))
+        }
+        if last {
+            return Ok(out)
+        }
+    }
+}
+
+fn too_long() -> String {
+    String::from("Deflate stream unpacks to more than its entry's size")
+}
+
+fn stored(bits: &mut Bits, out: &mut Vec<u8>, size: usize) -> Result<(), String> {
+    // Stored blocks start on a byte boundary
+    bits.buffer = 0;
+    bits.count = 0;
+    let header = bits.data.get(bits.at..bits.at + 4).ok_or("Deflate stream ends early")?;
+    let length = read_u16(header, 0) as usize;
+    if length != !read_u16(header, 2) as usize {
+        return Err(String::from("Deflate stored block is corrupt"))
+    }
+    bits.at += 4;
+    if out.len() + length > size {
+        return Err(too_long())
+    }
+    out.extend_from_slice(bits.data.get(bits.at..bits.at + length).ok_or("Deflate stream ends early")?);
+    bits.at += length;
+    Ok(())
+}
+
+fn dynamic_tables(bits: &mut Bits) -> Result<(Huffman, Huffman), String> {
+    let length_count = bits.take(5)? as usize + 257;
+    let distance_count = bits.take(5)? as usize + 1;
+    let code_count = bits.take(4)? as usize + 4;
+
+    let mut code_lengths = [0u8; 19];
+    for &i in CODE_LENGTH_ORDER.iter().take(code_count) {
+        code_lengths[i] = bits.take(3)? as u8
+    }
+    let code = Huffman::new(&code_lengths);
+
+    let mut lengths = Vec::with_capacity(length_count + distance_count);
+    while lengths.len() < length_count + distance_count {
+        let (value, repeat) = match code.decode(bits)? {
+            symbol @ 0..=15 => (symbol as u8, 1),
+            16 => (*lengths.last().ok_or("Deflate stream repeats nothing")?, 3 + bits.take(2)?),
+            17 => (0, 3 + bits.take(3)?),
+            _ => (0, 11 + bits.take(7)?)
+        };
+        lengths.extend(std::iter::repeat_n(value, repeat as usize))
+    }
+    if lengths.len() > length_count + distance_count {
+        return Err(String::from("Deflate code lengths overrun"))
+    }
+    Ok((Huffman::new(&lengths[..length_count]), Huffman::new(&lengths[length_count..])))
+}
+
+fn codes(bits: &mut Bits, out: &mut Vec<u8>, size: usize, lengths: &Huffman, distances: &Huffman) -> Result<(), String> {
+    loop {
+        let symbol = lengths.decode(bits)? as usize;
+        match symbol {
+            0..=255 if out.len() >= size => return Err(too_long()),
+            0..=255 => out.push(symbol as u8),
+            256 => return Ok(()),
+            _ => {
+                let i = symbol - 257;
+                if i >= LENGTH_BASE.len() {
+                    return Err(String::from("Deflate stream has an invalid length"))
+                }
+                let length = LENGTH_BASE[i] as usize + bits.take(LENGTH_EXTRA[i] as u32)? as usize;
+                let d = distances.decode(bits)? as usize;
+                if d >= DISTANCE_BASE.len() {
+                    return Err(String::from("Deflate stream has an invalid distance"))
+                }
+                let distance = DISTANCE_BASE[d] as usize + bits.take(DISTANCE_EXTRA[d] as u32)? as usize;
+                if distance > out.len() {
+                    return Err(String::from("Deflate stream refers back too far"))
+                }
+                if out.len() + length > size {
+                    return Err(too_long())
+                }
+                // Copies may overlap what they produce, so go a byte at a time
+                let start = out.len() - distance;
+                for j in 0..length {
+                    out.push(out[start + j])
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEXT: &[u8] = b"The quick brown fox jumps over the lazy dog. The quick brown fox jumps over the lazy dog. \
+        The quick brown fox jumps over the lazy dog. Pack my box with five dozen liquor jugs.";
+
+    // Raw deflate streams from zlib, one of each block type that uses codes
+    const FIXED: [u8; 10] = [0xcb, 0x48, 0xcd, 0xc9, 0xc9, 0x57, 0xc8, 0x40, 0x27, 0x01];
+    const DYNAMIC: [u8; 79] = [
+        0xb5, 0xcb, 0xc7, 0x01, 0x80, 0x20, 0x10, 0x05, 0xd1, 0x56, 0x7e, 0x05, 0xd4, 0xe2, 0xc1, 0x06, 0x40, 0x49,
+        0x06, 0x56, 0xb2, 0x50, 0xbd, 0xdb, 0x84, 0xe7, 0x79, 0xb3, 0x3a, 0x8d, 0x58, 0xfd, 0x76, 0x42, 0x25, 0xea,
+        0x01, 0x86, 0x5e, 0x1c, 0xf5, 0x7e, 0x32, 0xa8, 0xe9, 0x84, 0xc2, 0xf9, 0x92, 0x73, 0x60, 0x27, 0x2b, 0xb0,
+        0xfe, 0x86, 0x17, 0xc9, 0xee, 0x1e, 0x50, 0x8c, 0xba, 0x2f, 0x0e, 0xc6, 0x37, 0xcd, 0x69, 0xea, 0x80, 0xcb,
+        0xc7, 0x4a, 0x89, 0x5f, 0x9b, 0xc5, 0x07
+    ];
+
+    // A one-file archive with a local header, central directory and end record
+    fn archive(name: &str, method: u16, packed: &[u8], size: usize, crc: u32) -> Vec<u8> {
+        let fields = |data: &mut Vec<u8>| {
+            for half in [20, 0, method, 0, 0] {
+                data.extend(u16::to_le_bytes(half))
+            }
+            for word in [crc, packed.len() as u32, size as u32] {
+                data.extend(u32::to_le_bytes(word))
+            }
+            data.extend(u16::to_le_bytes(name.len() as u16));
+            data.extend(u16::to_le_bytes(0))
+        };
+
+        let mut data = LOCAL_HEADER.to_le_bytes().to_vec();
+        fields(&mut data);
+        data.extend(name.as_bytes());
+        data.extend(packed);
+
+        let directory = data.len() as u32;
+        data.extend(CENTRAL_HEADER.to_le_bytes());
+        data.extend(u16::to_le_bytes(20));
+        fields(&mut data);
+        // Comment length, disk, attributes and the local header at offset 0
+        data.extend([0; 14]);
+        data.extend(name.as_bytes());
+
+        let directory_size = data.len() as u32 - directory;
+        data.extend(END_OF_DIRECTORY.to_le_bytes());
+        data.extend([0, 0, 0, 0, 1, 0, 1, 0]);
+        data.extend(directory_size.to_le_bytes());
+        data.extend(directory.to_le_bytes());
+        data.extend([0, 0]);
+        data
+    }
+
+    fn unpack(data: &[u8]) -> Result<Vec<u8>, String> {
+        assert!(is_zip(data));
+        let entries = entries(data)?;
+        assert_eq!(entries.len(), 1);
+        extract(data, &entries[0])
+    }
+
+    #[test]
+    fn stored_entry() {
+        let data = archive("fw.bin", 0, TEXT, TEXT.len(), digest::crc32(TEXT));
+        assert_eq!(entries(&data).unwrap()[0].name, "fw.bin");
+        assert_eq!(unpack(&data).unwrap(), TEXT)
+    }
+
+    #[test]
+    fn stored_block() {
+        // A final stored block: header bits, then length and its complement
+        let mut packed = vec![0x01, 5, 0, !5, !0];
+        packed.extend(b"hello");
+        let data = archive("a", 8, &packed, 5, digest::crc32(b"hello"));
+        assert_eq!(unpack(&data).unwrap(), b"hello")
+    }
+
+    #[test]
+    fn fixed_huffman() {
+        let text = b"hello hello hello hello";
+        let data = archive("a", 8, &FIXED, text.len(), digest::crc32(text));
+        assert_eq!(unpack(&data).unwrap(), text)
+    }
+
+    #[test]
+    fn dynamic_huffman() {
+        let data = archive("a", 8, &DYNAMIC, TEXT.len(), digest::crc32(TEXT));
+        assert_eq!(unpack(&data).unwrap(), TEXT)
+    }
+
+    #[test]
+    fn refuses_bad_entries() {
+        let crc = digest::crc32(TEXT);
+        // Claiming less than the stream holds stops inflating early
+        assert!(unpack(&archive("a", 8, &DYNAMIC, 10, crc)).is_err());
+        assert!(unpack(&archive("a", 8, &DYNAMIC, TEXT.len(), crc ^ 1)).is_err());
+        assert!(unpack(&archive("a", 8, &DYNAMIC[..40], TEXT.len(), crc)).is_err());
+        assert!(unpack(&archive("a", 12, &DYNAMIC, TEXT.len(), crc)).is_err());
+        assert!(entries(b"not an archive at all").is_err())
+    }
+}